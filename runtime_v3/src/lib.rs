@@ -77,4 +77,44 @@ mod tests {
 
         assert_eq!(runtime.frame_counter(), 0);
     }
+
+    #[test]
+    fn runtime_pending_task_is_parked_until_woken() {
+        use futures::channel::oneshot;
+        use futures::FutureExt;
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let mut runtime = Runtime::new();
+        let (sender, receiver) = oneshot::channel();
+        let polled = Rc::new(Cell::new(0));
+
+        let count = Rc::clone(&polled);
+        let mut receiver = receiver;
+        runtime.spawn(async move {
+            let value = futures::future::poll_fn(|cx| {
+                count.set(count.get() + 1);
+                receiver.poll_unpin(cx)
+            })
+            .await;
+            assert_eq!(value, Ok(7));
+        });
+        runtime.spawn(async move {
+            for _ in 0..10 {
+                next_frame().await;
+            }
+            sender.send(7).unwrap();
+        });
+
+        'update_loop: loop {
+            match runtime.update() {
+                RuntimeIsDone::Done => break 'update_loop,
+                RuntimeIsDone::NotDone => (),
+            }
+        }
+
+        // 最初のpollとwakeされた後のpollの2回だけ
+        assert_eq!(polled.get(), 2);
+        assert_eq!(runtime.frame_counter(), 10);
+    }
 }
@@ -1,63 +1,84 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::pin::Pin;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::task::Context;
-use std::task::Waker;
 use std::{future::Future, task::Poll};
 
 use futures::task::ArcWake;
 
-struct Task {
-    future: Pin<Box<dyn Future<Output = ()> + 'static>>,
+thread_local! {
+    // タスクをpollしている間だけSomeになる。
+    // 中身はポーリング中のタスクがnext_frame()で次フレームの実行を予約したかどうか。
+    static NEXT_FRAME_REQUESTED: Cell<Option<bool>> = const { Cell::new(None) };
 }
-impl Task {
-    fn new(f: impl Future<Output = ()> + 'static) -> Self {
-        Self {
-            future: Box::pin(f),
+
+/// ポーリング中のタスクを次のフレームに実行するよう予約する。
+/// ランタイムの外でpollされている場合はfalseを返す。
+pub(crate) fn request_next_frame() -> bool {
+    NEXT_FRAME_REQUESTED.with(|requested| match requested.get() {
+        Some(_) => {
+            requested.set(Some(true));
+            true
         }
-    }
+        None => false,
+    })
+}
 
-    fn poll(&mut self, mut ctx: Context) -> Poll<()> {
-        match Future::poll(self.future.as_mut(), &mut ctx) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(()) => Poll::Ready(()),
+type TaskId = u64;
+
+// wakeされたタスクのIDを積んでおくキュー。
+// task_queueが空になったときにランタイムが取り出して、待機中のタスクを実行可能に戻す。
+type WakeQueue = Arc<Mutex<Vec<TaskId>>>;
+
+struct TaskWaker {
+    id: TaskId,
+    woken: AtomicBool,
+    wake_queue: WakeQueue,
+}
+impl ArcWake for TaskWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        // 既にwakeされている場合はキューに積まない
+        if !arc_self.woken.swap(true, Ordering::AcqRel) {
+            arc_self.wake_queue.lock().unwrap().push(arc_self.id);
         }
     }
 }
 
-#[derive(Clone)]
-struct WakeFlag {
-    waked: Arc<Mutex<bool>>,
+struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()> + 'static>>,
+    waker: Arc<TaskWaker>,
 }
-impl WakeFlag {
-    fn new() -> Self {
+impl Task {
+    fn new(id: TaskId, f: impl Future<Output = ()> + 'static, wake_queue: WakeQueue) -> Self {
         Self {
-            waked: Arc::new(Mutex::new(false)),
+            id,
+            future: Box::pin(f),
+            waker: Arc::new(TaskWaker {
+                id,
+                woken: AtomicBool::new(false),
+                wake_queue,
+            }),
         }
     }
 
-    fn wake(&self) {
-        *self.waked.lock().unwrap() = true;
-    }
+    // pollしたタスクがnext_frame()で次フレームを予約したかどうかも返す。
+    fn poll(&mut self) -> (Poll<()>, bool) {
+        let waker = futures::task::waker_ref(&self.waker);
+        let mut ctx = Context::from_waker(&waker);
 
-    fn is_waked(&self) -> bool {
-        *self.waked.lock().unwrap()
+        NEXT_FRAME_REQUESTED.with(|requested| requested.set(Some(false)));
+        let poll = Future::poll(self.future.as_mut(), &mut ctx);
+        let next_frame_requested =
+            NEXT_FRAME_REQUESTED.with(|requested| requested.take()) == Some(true);
+        (poll, next_frame_requested)
     }
-}
 
-#[derive(Clone)]
-struct WakeFlagWaker {
-    flag: WakeFlag,
-}
-impl WakeFlagWaker {
-    fn waker(flag: WakeFlag) -> Waker {
-        futures::task::waker(Arc::new(Self { flag }))
-    }
-}
-impl ArcWake for WakeFlagWaker {
-    fn wake_by_ref(arc_self: &Arc<Self>) {
-        arc_self.flag.wake();
+    fn take_woken(&self) -> bool {
+        self.waker.woken.swap(false, Ordering::AcqRel)
     }
 }
 
@@ -69,42 +90,76 @@ pub enum RuntimeIsDone {
 #[derive(Clone)]
 pub struct Runtime {
     frame_counter: u64,
+    next_task_id: Rc<Cell<TaskId>>,
     tasks_queue: Rc<RefCell<Vec<Task>>>,
     wait_tasks: Rc<RefCell<Vec<Task>>>,
+    // wakeされるまでpollしないタスク
+    parked: Rc<RefCell<HashMap<TaskId, Task>>>,
+    wake_queue: WakeQueue,
 }
 impl Runtime {
     pub fn new() -> Self {
         Self {
             frame_counter: 0,
+            next_task_id: Rc::new(Cell::new(0)),
             tasks_queue: Rc::new(RefCell::new(vec![])),
             wait_tasks: Rc::new(RefCell::new(vec![])),
+            parked: Rc::new(RefCell::new(HashMap::new())),
+            wake_queue: Arc::new(Mutex::new(vec![])),
         }
     }
 
     pub fn spawn(&self, f: impl Future<Output = ()> + 'static) {
-        self.tasks_queue.borrow_mut().push(Task::new(f));
+        let id = self.next_task_id.get();
+        self.next_task_id.set(id + 1);
+        let task = Task::new(id, f, Arc::clone(&self.wake_queue));
+        self.tasks_queue.borrow_mut().push(task);
     }
 
+    // wakeされたタスクをparkedからtasks_queueに移す。
+    fn wake_parked_tasks(&self) {
+        let woken = std::mem::take(&mut *self.wake_queue.lock().unwrap());
+        let mut parked = self.parked.borrow_mut();
+        let mut tasks_queue = self.tasks_queue.borrow_mut();
+        for id in woken {
+            // poll中にwakeされてそのまま再pollされたタスクなど、
+            // parkedに見つからないIDは無視してよい
+            if let Some(task) = parked.remove(&id) {
+                tasks_queue.push(task);
+            }
+        }
+    }
+
+    // pollされるのは、next_frame()で次フレームを予約したタスクとwakeされたタスクだけである。
     pub fn update(&mut self) -> RuntimeIsDone {
         'current_frame: loop {
             let task = self.tasks_queue.borrow_mut().pop();
 
             match task {
-                // task_queueが空だった場合はループを抜ける
-                None => break 'current_frame,
+                None => {
+                    // このフレームの中でwakeされたタスクがあればもう一度回す
+                    // task_queueが空のままだった場合はループを抜ける
+                    self.wake_parked_tasks();
+                    if self.tasks_queue.borrow().is_empty() {
+                        break 'current_frame;
+                    }
+                }
                 Some(mut task) => {
-                    let flag = WakeFlag::new();
-                    let waker = WakeFlagWaker::waker(flag.clone());
-
-                    match task.poll(Context::from_waker(&waker)) {
-                        Poll::Ready(()) => (),
-                        Poll::Pending => {
-                            // タスクがwake済みだったらtask_queueにpush
-                            // そうでなかったらwait_tasksにpushする
-                            if flag.is_waked() {
+                    // poll前にwakeフラグを下ろしておき、poll中や後のwakeを検出する
+                    task.take_woken();
+
+                    match task.poll() {
+                        (Poll::Ready(()), _) => (),
+                        (Poll::Pending, next_frame_requested) => {
+                            // 次フレームを予約したタスクはwait_tasksにpush
+                            // wake済みだったらtask_queueにpushしてこのフレームの中でもう一度poll
+                            // そうでなかったらwakeされるまでparkedに入れる
+                            if next_frame_requested {
+                                self.wait_tasks.borrow_mut().push(task);
+                            } else if task.take_woken() {
                                 self.tasks_queue.borrow_mut().push(task);
                             } else {
-                                self.wait_tasks.borrow_mut().push(task);
+                                self.parked.borrow_mut().insert(task.id, task);
                             }
                         }
                     }
@@ -112,8 +167,8 @@ impl Runtime {
             }
         }
 
-        // wait_tasksが空の場合、全てのタスクの実行が終わっている。
-        if self.wait_tasks.borrow().is_empty() {
+        // wait_tasksとparkedが空の場合、全てのタスクの実行が終わっている。
+        if self.wait_tasks.borrow().is_empty() && self.parked.borrow().is_empty() {
             return RuntimeIsDone::Done;
         }
 
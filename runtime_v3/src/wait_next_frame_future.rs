@@ -2,6 +2,8 @@ use std::pin::Pin;
 use std::task::Context;
 use std::{future::Future, task::Poll};
 
+use crate::runtime::request_next_frame;
+
 pub struct WaitNextFrameFuture {
     polled: bool,
 }
@@ -13,11 +15,16 @@ impl WaitNextFrameFuture {
 impl Future for WaitNextFrameFuture {
     type Output = ();
 
-    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         if self.polled {
             Poll::Ready(())
         } else {
             self.polled = true;
+            // ランタイムのフレームスケジューラに次フレームの実行を予約する。
+            // ランタイムの外でpollされた場合はすぐにwakeしておく。
+            if !request_next_frame() {
+                cx.waker().wake_by_ref();
+            }
             Poll::Pending
         }
     }
@@ -0,0 +1,142 @@
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use futures::future::FutureExt;
+
+/// タスクが正常に終了しなかった理由を表すエラー。
+pub enum JoinError {
+    /// タスクがpanicした。panicのペイロードを保持する。
+    Panicked(Box<dyn Any + Send + 'static>),
+    /// タスクが完了する前に破棄された。
+    Cancelled,
+}
+impl JoinError {
+    /// タスクがpanicしたかどうかを返す関数。
+    pub fn is_panic(&self) -> bool {
+        matches!(self, JoinError::Panicked(_))
+    }
+
+    /// タスクがキャンセルされたかどうかを返す関数。
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, JoinError::Cancelled)
+    }
+
+    /// panicのペイロードを取り出す関数。
+    ///
+    /// ## panic
+    /// タスクがpanicしていない場合、panicする。
+    pub fn into_panic(self) -> Box<dyn Any + Send + 'static> {
+        match self {
+            JoinError::Panicked(payload) => payload,
+            JoinError::Cancelled => panic!("`into_panic` called on a cancelled task's JoinError"),
+        }
+    }
+}
+impl fmt::Debug for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Panicked(_) => write!(f, "JoinError::Panicked(..)"),
+            JoinError::Cancelled => write!(f, "JoinError::Cancelled"),
+        }
+    }
+}
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Panicked(_) => write!(f, "task panicked"),
+            JoinError::Cancelled => write!(f, "task was cancelled"),
+        }
+    }
+}
+impl std::error::Error for JoinError {}
+
+struct JoinState<T> {
+    output: Option<Result<T, JoinError>>,
+    completed: bool,
+    waker: Option<Waker>,
+}
+
+fn complete<T>(state: &Mutex<JoinState<T>>, output: Result<T, JoinError>) {
+    let waker = {
+        let mut state = state.lock().unwrap();
+        if state.completed {
+            return;
+        }
+        state.completed = true;
+        state.output = Some(output);
+        state.waker.take()
+    };
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+// タスクのFutureが完了前にdropされたときにCancelledを書き込むためのガード。
+struct CancelOnDrop<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+impl<T> Drop for CancelOnDrop<T> {
+    fn drop(&mut self) {
+        complete(&self.state, Err(JoinError::Cancelled));
+    }
+}
+
+/// spawnしたタスクの結果を待つためのFuture。
+///
+/// どのPhaseのタスクからでも`.await`できる。
+/// JoinHandleをdropしてもタスクは実行され続ける。
+pub struct JoinHandle<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+impl<T> JoinHandle<T> {
+    /// タスクが終了しているかどうかを返す関数。
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().unwrap().completed
+    }
+}
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        match state.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                if state.completed {
+                    panic!("JoinHandle polled after completion");
+                }
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Futureをランタイムに積めるタスクと、その結果を受け取るJoinHandleに分ける関数。
+/// タスク内のpanicは捕捉されJoinHandleに報告される。
+pub(crate) fn joinable<F>(f: F) -> (impl Future<Output = ()>, JoinHandle<F::Output>)
+where
+    F: Future,
+{
+    let state = Arc::new(Mutex::new(JoinState {
+        output: None,
+        completed: false,
+        waker: None,
+    }));
+    let guard = CancelOnDrop {
+        state: Arc::clone(&state),
+    };
+
+    let task = async move {
+        let guard = guard;
+        let output = AssertUnwindSafe(f).catch_unwind().await;
+        complete(&guard.state, output.map_err(JoinError::Panicked));
+    };
+
+    (task, JoinHandle { state })
+}
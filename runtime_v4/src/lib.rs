@@ -1,7 +1,11 @@
+mod join_handle;
 mod runtime;
+mod task_snapshot;
 mod wait_next_frame_future;
 
+pub use join_handle::{JoinError, JoinHandle};
 pub use runtime::{Runtime, RuntimeIsDone};
+pub use task_snapshot::{TaskSnapshot, TaskState};
 pub use wait_next_frame_future::next_frame;
 
 #[cfg(test)]
@@ -10,6 +14,7 @@ mod tests {
     use futures::join;
     use std::cell::RefCell;
     use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
 
     #[derive(Eq, PartialEq, Clone, Hash, Debug)]
     enum Phase {
@@ -134,6 +139,66 @@ mod tests {
         }
     }
 
+    #[test]
+    fn join_handle_returns_task_output_to_another_phase() {
+        let mut runtime = Runtime::new();
+        runtime.activate_phase(Phase::Phase1, 0);
+        runtime.activate_phase(Phase::Phase2, 1);
+
+        let handle = runtime.spawn(Phase::Phase1, async {
+            for _ in 0..3 {
+                next_frame().await;
+            }
+            42
+        });
+
+        let result = runtime.spawn(Phase::Phase2, async move { handle.await.unwrap() });
+
+        'update_loop: loop {
+            match runtime.update() {
+                RuntimeIsDone::Done => break 'update_loop,
+                RuntimeIsDone::NotDone => (),
+            }
+        }
+
+        assert!(result.is_finished());
+        assert_eq!(futures::executor::block_on(result).unwrap(), 42);
+    }
+
+    #[test]
+    fn join_handle_reports_panicked_task() {
+        let mut runtime = Runtime::new();
+        runtime.activate_phase(Phase::Phase1, 0);
+
+        let handle = runtime.spawn(Phase::Phase1, async {
+            next_frame().await;
+            panic!("task panic");
+        });
+
+        'update_loop: loop {
+            match runtime.update() {
+                RuntimeIsDone::Done => break 'update_loop,
+                RuntimeIsDone::NotDone => (),
+            }
+        }
+
+        let err = futures::executor::block_on(handle).unwrap_err();
+        assert!(err.is_panic());
+        assert_eq!(*err.into_panic().downcast::<&str>().unwrap(), "task panic");
+    }
+
+    #[test]
+    fn join_handle_reports_cancelled_task() {
+        let runtime = Runtime::new();
+        let handle = runtime.spawn(Phase::Phase2, async {});
+
+        // 実行されずにランタイムごと破棄されたタスクはキャンセル扱いになる
+        drop(runtime);
+
+        let err = futures::executor::block_on(handle).unwrap_err();
+        assert!(err.is_cancelled());
+    }
+
     #[test]
     #[should_panic(expected = "Another PHASE has already been registered in this order: Phase1")]
     fn phase_order_num_should_different_from_other_phases() {
@@ -143,4 +208,209 @@ mod tests {
         // this line should panic
         runtime.activate_phase(Phase::Phase2, 0);
     }
+
+    #[test]
+    fn paused_phase_keeps_tasks_until_resumed() {
+        let mut runtime = Runtime::new();
+        runtime.activate_phase(Phase::Phase1, 0);
+
+        let count = Arc::new(Mutex::new(0));
+        let task_count = Arc::clone(&count);
+        runtime.spawn(Phase::Phase1, async move {
+            for _ in 0..3 {
+                *task_count.lock().unwrap() += 1;
+                next_frame().await;
+            }
+        });
+
+        runtime.update();
+        assert_eq!(*count.lock().unwrap(), 1);
+
+        runtime.pause_phase(Phase::Phase1);
+        for _ in 0..3 {
+            assert!(matches!(runtime.update(), RuntimeIsDone::NotDone));
+        }
+        assert_eq!(*count.lock().unwrap(), 1);
+
+        runtime.resume_phase(&Phase::Phase1);
+        'update_loop: loop {
+            match runtime.update() {
+                RuntimeIsDone::Done => break 'update_loop,
+                RuntimeIsDone::NotDone => (),
+            }
+        }
+        assert_eq!(*count.lock().unwrap(), 3);
+    }
+
+    #[test]
+    fn reorder_and_deactivate_phases() {
+        let mut runtime = Runtime::new();
+        runtime.activate_phase(Phase::Phase1, 0);
+        runtime.activate_phase(Phase::Phase2, 1);
+
+        let log = Arc::new(Mutex::new(vec![]));
+        let log1 = Arc::clone(&log);
+        runtime.spawn(Phase::Phase1, async move {
+            for _ in 0..3 {
+                log1.lock().unwrap().push(1);
+                next_frame().await;
+            }
+        });
+        let log2 = Arc::clone(&log);
+        let handle = runtime.spawn(Phase::Phase2, async move {
+            for _ in 0..3 {
+                log2.lock().unwrap().push(2);
+                next_frame().await;
+            }
+        });
+
+        runtime.update();
+        runtime.set_phase_order(Phase::Phase1, 2);
+        runtime.update();
+        runtime.deactivate_phase(Phase::Phase2);
+        'update_loop: loop {
+            match runtime.update() {
+                RuntimeIsDone::Done => break 'update_loop,
+                RuntimeIsDone::NotDone => (),
+            }
+        }
+
+        assert_eq!(*log.lock().unwrap(), vec![1, 2, 2, 1, 1]);
+        assert!(futures::executor::block_on(handle)
+            .unwrap_err()
+            .is_cancelled());
+    }
+
+    #[test]
+    fn tasks_snapshot_lists_live_tasks() {
+        let mut runtime = Runtime::new();
+        runtime.activate_phase(Phase::Phase1, 0);
+        runtime.activate_phase(Phase::Phase2, 1);
+        runtime.pause_phase(Phase::Phase2);
+
+        runtime.spawn_named(Phase::Phase1, "looping", async {
+            loop {
+                next_frame().await;
+            }
+        });
+        runtime.update();
+        runtime.spawn(Phase::Phase2, async {});
+        runtime.update();
+
+        let mut snapshot = runtime.tasks_snapshot();
+        snapshot.sort_by_key(|task| task.spawn_frame());
+        assert_eq!(snapshot.len(), 2);
+
+        assert_eq!(snapshot[0].name(), Some("looping"));
+        assert_eq!(snapshot[0].phase(), &Phase::Phase1);
+        assert_eq!(snapshot[0].state(), TaskState::Runnable);
+        assert_eq!(snapshot[0].poll_count(), 2);
+        assert_eq!(snapshot[0].last_polled_frame(), Some(1));
+        assert_eq!(
+            snapshot[0].to_string(),
+            "task looping in PHASE Phase1: Runnable, spawned at frame 0, polled 2 times, last at frame 1"
+        );
+
+        // 一時停止中のPhaseのタスクはpollされずに残る
+        assert_eq!(snapshot[1].name(), None);
+        assert_eq!(snapshot[1].state(), TaskState::Parked);
+        assert_eq!(snapshot[1].spawn_frame(), 1);
+        assert_eq!(snapshot[1].poll_count(), 0);
+        assert_eq!(snapshot[1].last_polled_frame(), None);
+
+        // 再開すると完了して一覧から消える
+        runtime.resume_phase(&Phase::Phase2);
+        runtime.update();
+        assert_eq!(runtime.tasks_snapshot().len(), 1);
+    }
+
+    #[test]
+    fn pending_task_is_parked_until_woken() {
+        let mut runtime = Runtime::new();
+        runtime.activate_phase(Phase::Phase1, 0);
+        runtime.activate_phase(Phase::Phase2, 1);
+
+        let handle = runtime.spawn(Phase::Phase2, async {
+            for _ in 0..10 {
+                next_frame().await;
+            }
+            7
+        });
+        let result = runtime.spawn_named(
+            Phase::Phase1,
+            "waiting",
+            async move { handle.await.unwrap() },
+        );
+
+        for _ in 0..5 {
+            runtime.update();
+        }
+
+        // JoinHandleを待っている間はpollされない
+        let snapshot = runtime.tasks_snapshot();
+        let waiting = snapshot
+            .iter()
+            .find(|task| task.name() == Some("waiting"))
+            .unwrap();
+        assert_eq!(waiting.state(), TaskState::Parked);
+        assert_eq!(waiting.poll_count(), 1);
+
+        'update_loop: loop {
+            match runtime.update() {
+                RuntimeIsDone::Done => break 'update_loop,
+                RuntimeIsDone::NotDone => (),
+            }
+        }
+
+        // Phase2でwakeされたタスクは次のフレームのPhase1で実行される
+        assert_eq!(futures::executor::block_on(result).unwrap(), 7);
+        assert_eq!(runtime.frame_counter(), 11);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn task_events_are_attributed_to_task_and_frame() {
+        #[derive(Clone)]
+        struct Buffer(Arc<Mutex<Vec<u8>>>);
+        impl std::io::Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buffer = Buffer(Arc::new(Mutex::new(vec![])));
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::NEW)
+            .finish();
+
+        // 呼び出し元のスレッドでタスクを実行するので、スレッドのデフォルトのsubscriberで受け取れる
+        tracing::subscriber::with_default(subscriber, || {
+            let mut runtime = Runtime::new();
+            runtime.activate_phase(Phase::Phase1, 0);
+            runtime.spawn_named(Phase::Phase1, "logger", async {
+                next_frame().await;
+                tracing::info!("hello from task");
+            });
+            'update_loop: loop {
+                match runtime.update() {
+                    RuntimeIsDone::Done => break 'update_loop,
+                    RuntimeIsDone::NotDone => (),
+                }
+            }
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line = output
+            .lines()
+            .find(|line| line.contains("hello from task"))
+            .unwrap();
+        assert!(line.contains("task{spawn_frame=0 name=\"logger\"}:poll{frame=1}: "));
+        assert!(output.contains("update{frame=1}:phase{phase=Phase1}: "));
+    }
 }
@@ -1,66 +1,140 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::future::Future;
 use std::hash::Hash;
 use std::pin::Pin;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::task::{Context, Poll, Waker};
+use std::task::{Context, Poll};
 
 use futures::task::ArcWake;
 
-struct Task {
-    future: Pin<Box<dyn Future<Output = ()> + 'static>>,
+use crate::join_handle::{joinable, JoinHandle};
+use crate::task_snapshot::{TaskSnapshot, TaskState};
+
+thread_local! {
+    // タスクをpollしている間だけSomeになる。
+    // 中身はポーリング中のタスクがnext_frame()で次フレームの実行を予約したかどうか。
+    static NEXT_FRAME_REQUESTED: Cell<Option<bool>> = const { Cell::new(None) };
 }
-impl Task {
-    fn new(f: impl Future<Output = ()> + 'static) -> Self {
-        Self {
-            future: Box::pin(f),
+
+/// ポーリング中のタスクを次のフレームに実行するよう予約する。
+/// ランタイムの外でpollされている場合はfalseを返す。
+pub(crate) fn request_next_frame() -> bool {
+    NEXT_FRAME_REQUESTED.with(|requested| match requested.get() {
+        Some(_) => {
+            requested.set(Some(true));
+            true
         }
-    }
+        None => false,
+    })
+}
 
-    fn poll(&mut self, mut ctx: Context) -> Poll<()> {
-        match Future::poll(self.future.as_mut(), &mut ctx) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(()) => Poll::Ready(()),
+type TaskId = u64;
+
+// wakeされたタスクのIDを積んでおくキュー。
+// Phaseの境界でランタイムが取り出して、待機中のタスクを実行可能に戻す。
+type WakeQueue = Arc<Mutex<Vec<TaskId>>>;
+
+struct TaskWaker {
+    id: TaskId,
+    woken: AtomicBool,
+    wake_queue: WakeQueue,
+}
+impl ArcWake for TaskWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        // 既にwakeされている場合はキューに積まない
+        if !arc_self.woken.swap(true, Ordering::AcqRel) {
+            arc_self.wake_queue.lock().unwrap().push(arc_self.id);
         }
     }
 }
 
-#[derive(Clone)]
-struct WakeFlag {
-    waked: Arc<Mutex<bool>>,
+struct Task {
+    id: TaskId,
+    name: Option<String>,
+    spawn_frame: u64,
+    poll_count: u64,
+    last_polled_frame: Option<u64>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    future: Pin<Box<dyn Future<Output = ()> + 'static>>,
+    waker: Arc<TaskWaker>,
 }
-impl WakeFlag {
-    fn new() -> Self {
+impl Task {
+    fn new(
+        id: TaskId,
+        name: Option<String>,
+        spawn_frame: u64,
+        f: impl Future<Output = ()> + 'static,
+        wake_queue: WakeQueue,
+    ) -> Self {
         Self {
-            waked: Arc::new(Mutex::new(false)),
+            id,
+            #[cfg(feature = "tracing")]
+            span: task_span(name.as_deref(), spawn_frame),
+            name,
+            spawn_frame,
+            poll_count: 0,
+            last_polled_frame: None,
+            future: Box::pin(f),
+            waker: Arc::new(TaskWaker {
+                id,
+                woken: AtomicBool::new(false),
+                wake_queue,
+            }),
         }
     }
 
-    fn wake(&self) {
-        *self.waked.lock().unwrap() = true;
+    // pollしたタスクがnext_frame()で次フレームを予約したかどうかも返す。
+    fn poll(&mut self, frame: u64) -> (Poll<()>, bool) {
+        // タスク内のイベントがどのタスクのどのフレームのものか分かるようにする
+        #[cfg(feature = "tracing")]
+        let _entered = tracing::info_span!(parent: &self.span, "poll", frame).entered();
+        self.poll_count += 1;
+        self.last_polled_frame = Some(frame);
+
+        let waker = futures::task::waker_ref(&self.waker);
+        let mut ctx = Context::from_waker(&waker);
+        NEXT_FRAME_REQUESTED.with(|requested| requested.set(Some(false)));
+        let poll = Future::poll(self.future.as_mut(), &mut ctx);
+        let next_frame_requested =
+            NEXT_FRAME_REQUESTED.with(|requested| requested.take()) == Some(true);
+        (poll, next_frame_requested)
     }
 
-    fn is_waked(&self) -> bool {
-        *self.waked.lock().unwrap()
+    fn take_woken(&self) -> bool {
+        self.waker.woken.swap(false, Ordering::AcqRel)
     }
-}
 
-#[derive(Clone)]
-struct WakeFlagWaker {
-    flag: WakeFlag,
-}
-impl WakeFlagWaker {
-    fn waker(flag: WakeFlag) -> Waker {
-        futures::task::waker(Arc::new(Self { flag }))
+    fn snapshot<T>(&self, phase: T, state: TaskState) -> TaskSnapshot<T> {
+        TaskSnapshot::new(
+            self.name.clone(),
+            phase,
+            self.spawn_frame,
+            self.poll_count,
+            self.last_polled_frame,
+            state,
+        )
     }
 }
-impl ArcWake for WakeFlagWaker {
-    fn wake_by_ref(arc_self: &Arc<Self>) {
-        arc_self.flag.wake();
+
+// タスクのpollごとのspanの親になるspanを作る。
+// タスクは起動したタスクより長く生きることがあるので、親のないspanにする。
+#[cfg(feature = "tracing")]
+fn task_span(name: Option<&str>, spawn_frame: u64) -> tracing::Span {
+    let span = tracing::info_span!(
+        parent: None,
+        "task",
+        name = tracing::field::Empty,
+        spawn_frame
+    );
+    if let Some(name) = name {
+        span.record("name", name);
     }
+    span
 }
 
 /// 非同期タスクがすべて終了したかどうかのenum。
@@ -73,39 +147,150 @@ pub enum RuntimeIsDone {
 #[derive(Clone)]
 pub struct Runtime<T: Eq + Hash + Clone + Debug> {
     frame_counter: u64,
+    next_task_id: Rc<Cell<TaskId>>,
     tasks: Rc<RefCell<HashMap<T, Vec<Task>>>>,
     wait_tasks: Rc<RefCell<HashMap<T, Vec<Task>>>>,
+    // wakeされるまでpollしないタスク
+    parked: Rc<RefCell<HashMap<T, HashMap<TaskId, Task>>>>,
+    wake_queue: WakeQueue,
     activated_phase: Rc<RefCell<HashMap<u16, T>>>,
+    paused_phases: Rc<RefCell<HashSet<T>>>,
+    deactivated_phases: Rc<RefCell<Vec<T>>>,
 }
 impl<T: Eq + Hash + Clone + Debug> Runtime<T> {
     /// 新しくRuntimeを作成して返す。
     pub fn new() -> Self {
         Self {
             frame_counter: 0,
+            next_task_id: Rc::new(Cell::new(0)),
             tasks: Rc::new(RefCell::new(HashMap::new())),
             wait_tasks: Rc::new(RefCell::new(HashMap::new())),
+            parked: Rc::new(RefCell::new(HashMap::new())),
+            wake_queue: Arc::new(Mutex::new(vec![])),
             activated_phase: Rc::new(RefCell::new(HashMap::new())),
+            paused_phases: Rc::new(RefCell::new(HashSet::new())),
+            deactivated_phases: Rc::new(RefCell::new(vec![])),
         }
     }
 
     /// タスクを起動する関数。
     /// 同一Phaseのタスクの実行順序は不定。
-    pub fn spawn(&self, phase: T, f: impl Future<Output = ()> + 'static) {
+    ///
+    /// 返り値の[`JoinHandle`]を`.await`するとタスクの結果を受け取れる。
+    pub fn spawn<Fut>(&self, phase: T, f: Fut) -> JoinHandle<Fut::Output>
+    where
+        Fut: Future + 'static,
+    {
+        self.spawn_task(phase, None, f)
+    }
+
+    /// 名前を付けてタスクを起動する関数。
+    /// 名前は[`Runtime::tasks_snapshot`]で参照できる。
+    ///
+    /// 返り値の[`JoinHandle`]を`.await`するとタスクの結果を受け取れる。
+    pub fn spawn_named<Fut>(
+        &self,
+        phase: T,
+        name: impl Into<String>,
+        f: Fut,
+    ) -> JoinHandle<Fut::Output>
+    where
+        Fut: Future + 'static,
+    {
+        self.spawn_task(phase, Some(name.into()), f)
+    }
+
+    fn spawn_task<Fut>(&self, phase: T, name: Option<String>, f: Fut) -> JoinHandle<Fut::Output>
+    where
+        Fut: Future + 'static,
+    {
+        let (task, handle) = joinable(f);
+        let id = self.next_task_id.get();
+        self.next_task_id.set(id + 1);
+        let task = Task::new(
+            id,
+            name,
+            self.frame_counter,
+            task,
+            Arc::clone(&self.wake_queue),
+        );
         let mut tasks = self.tasks.borrow_mut();
         let ts = tasks.entry(phase).or_insert(vec![]);
-        ts.push(Task::new(f));
+        ts.push(task);
+        handle
+    }
+
+    /// 生きているタスクの一覧を返す関数。順序は不定。
+    ///
+    /// wakeを待っているタスクと一時停止中のPhaseのタスクが[`TaskState::Parked`]になる。
+    /// Phaseの実行中に呼び出した場合、実行中のタスクは含まれない。
+    pub fn tasks_snapshot(&self) -> Vec<TaskSnapshot<T>> {
+        let paused_phases = self.paused_phases.borrow();
+        let tasks = self.tasks.borrow();
+        let wait_tasks = self.wait_tasks.borrow();
+        let parked = self.parked.borrow();
+        let runnable = tasks
+            .iter()
+            .chain(wait_tasks.iter())
+            .flat_map(|(phase, ts)| {
+                let state = if paused_phases.contains(phase) {
+                    TaskState::Parked
+                } else {
+                    TaskState::Runnable
+                };
+                ts.iter()
+                    .map(move |task| task.snapshot(phase.clone(), state))
+            });
+        let parked = parked.iter().flat_map(|(phase, ts)| {
+            ts.values()
+                .map(move |task| task.snapshot(phase.clone(), TaskState::Parked))
+        });
+        runnable.chain(parked).collect()
     }
 
     /// 毎フレーム呼び出すべき関数。
     /// 各Phaseのタスクを順に実行していく。
+    ///
+    /// 各Phaseでpollされるのは、next_frame()で次フレームを予約したタスクと
+    /// wakeされたタスクだけである。
     pub fn update(&mut self) -> RuntimeIsDone {
+        #[cfg(feature = "tracing")]
+        let _entered = tracing::info_span!("update", frame = self.frame_counter).entered();
+
+        // 前のフレームでDeactivateされたPhaseのタスクを破棄する
+        self.drop_deactivated_tasks();
+
         // ActivateされているPhaseをソートする
-        let activated_phase = self.activated_phase.borrow();
-        let mut phases = activated_phase.iter().collect::<Vec<_>>();
-        phases.sort_by_key(|(&order, _phase)| order);
-        let phases = phases.into_iter().map(|(_order, phase)| phase);
+        // フレームの途中でPhaseが変更されても次のフレームまで反映されないように複製しておく
+        let phases = {
+            let activated_phase = self.activated_phase.borrow();
+            let mut phases = activated_phase.iter().collect::<Vec<_>>();
+            phases.sort_by_key(|(&order, _phase)| order);
+            phases
+                .into_iter()
+                .map(|(_order, phase)| phase.clone())
+                .collect::<Vec<_>>()
+        };
+        let paused_phases = self.paused_phases.borrow().clone();
+
+        for (i, phase) in phases.iter().enumerate() {
+            #[cfg(feature = "tracing")]
+            let _entered = tracing::info_span!("phase", phase = ?phase).entered();
+
+            // Phaseの境界でwakeされたタスクを実行可能に戻す
+            self.wake_parked_tasks(&phases[..i]);
+
+            // 一時停止中のPhaseのタスクはpollせずにwait_tasksに残す
+            if paused_phases.contains(phase) {
+                let paused = self.tasks.borrow_mut().remove(phase).unwrap_or_default();
+                let mut wait_tasks = self.wait_tasks.borrow_mut();
+                wait_tasks
+                    .entry(phase.clone())
+                    .or_insert(vec![])
+                    .extend(paused);
+                continue;
+            }
 
-        for phase in phases {
             'current_frame: loop {
                 let task = self
                     .tasks
@@ -115,25 +300,36 @@ impl<T: Eq + Hash + Clone + Debug> Runtime<T> {
                     .pop();
 
                 match task {
-                    // task_queueが空だった場合は次のphaseへ
-                    None => break 'current_frame,
+                    None => {
+                        // このPhaseの中でwakeされたタスクがあればもう一度回す
+                        // task_queueが空のままだった場合は次のphaseへ
+                        self.wake_parked_tasks(&phases[..i]);
+                        if self.tasks.borrow().get(phase).is_none_or(Vec::is_empty) {
+                            break 'current_frame;
+                        }
+                    }
                     Some(mut task) => {
-                        let flag = WakeFlag::new();
-                        let waker = WakeFlagWaker::waker(flag.clone());
-
-                        match task.poll(Context::from_waker(&waker)) {
-                            Poll::Ready(()) => (),
-                            Poll::Pending => {
-                                // タスクがwake済みだったらtask_queueにpush
-                                // そうでなかったらwait_tasksにpushする
-                                if flag.is_waked() {
+                        // poll前にwakeフラグを下ろしておき、poll中や後のwakeを検出する
+                        task.take_woken();
+
+                        match task.poll(self.frame_counter) {
+                            (Poll::Ready(()), _) => (),
+                            (Poll::Pending, next_frame_requested) => {
+                                // 次フレームを予約したタスクはwait_tasksにpush
+                                // wake済みだったらtask_queueにpushしてこのPhaseの中でもう一度poll
+                                // そうでなかったらwakeされるまでparkedに入れる
+                                if next_frame_requested {
+                                    let mut wait_tasks = self.wait_tasks.borrow_mut();
+                                    let wts = wait_tasks.entry(phase.clone()).or_insert(vec![]);
+                                    wts.push(task);
+                                } else if task.take_woken() {
                                     let mut tasks = self.tasks.borrow_mut();
                                     let ts = tasks.entry(phase.clone()).or_insert(vec![]);
                                     ts.push(task);
                                 } else {
-                                    let mut wait_tasks = self.wait_tasks.borrow_mut();
-                                    let wts = wait_tasks.entry(phase.clone()).or_insert(vec![]);
-                                    wts.push(task);
+                                    let mut parked = self.parked.borrow_mut();
+                                    let ps = parked.entry(phase.clone()).or_default();
+                                    ps.insert(task.id, task);
                                 }
                             }
                         }
@@ -142,8 +338,11 @@ impl<T: Eq + Hash + Clone + Debug> Runtime<T> {
             }
         }
 
+        // このフレームの中でwakeされたタスクは次のフレームで実行する
+        self.wake_parked_tasks(&phases);
+
         {
-            // すべてのPhaseのwait_tasksが空の場合、全てのタスクの実行が終わっている。
+            // すべてのPhaseのwait_tasksとparkedが空の場合、全てのタスクの実行が終わっている。
             let mut done_flag = true;
             let wait_tasks = self.wait_tasks.borrow();
             for (_p, tasks) in wait_tasks.iter() {
@@ -151,6 +350,12 @@ impl<T: Eq + Hash + Clone + Debug> Runtime<T> {
                     done_flag = false;
                 }
             }
+            let parked = self.parked.borrow();
+            for (_p, tasks) in parked.iter() {
+                if !tasks.is_empty() {
+                    done_flag = false;
+                }
+            }
             if done_flag {
                 return RuntimeIsDone::Done;
             }
@@ -190,4 +395,116 @@ impl<T: Eq + Hash + Clone + Debug> Runtime<T> {
 
         activated_phase.insert(order, phase);
     }
+
+    /// Phaseの登録を解除する関数。
+    ///
+    /// DeactivateされたPhaseは次のフレームから実行されなくなり、
+    /// 次のフレームの開始時までに再びActivateされなければPhaseのタスクは破棄される。
+    /// タスクを残したまま実行を止める場合は[`Runtime::pause_phase`]を使う。
+    pub fn deactivate_phase(&self, phase: T) {
+        self.activated_phase
+            .borrow_mut()
+            .retain(|_order, p| *p != phase);
+        self.paused_phases.borrow_mut().remove(&phase);
+        self.deactivated_phases.borrow_mut().push(phase);
+    }
+
+    /// Phaseを一時停止する関数。
+    ///
+    /// 一時停止したPhaseは次のフレームから[`Runtime::resume_phase`]を呼ぶまで実行されない。
+    /// Phaseのタスクはpollされずにwait_tasksに残る。
+    pub fn pause_phase(&self, phase: T) {
+        self.paused_phases.borrow_mut().insert(phase);
+    }
+
+    /// 一時停止したPhaseを再開する関数。
+    /// Phaseは次のフレームから実行される。
+    pub fn resume_phase(&self, phase: &T) {
+        self.paused_phases.borrow_mut().remove(phase);
+    }
+
+    /// ActivateされているPhaseの実行順序を変更する関数。
+    /// 変更は次のフレームから反映される。
+    ///
+    /// ## panic
+    /// PhaseがActivateされていない場合や、
+    /// orderに他のPhaseと重複した値を指定した場合、panicする。
+    pub fn set_phase_order(&self, phase: T, order: u16) {
+        let mut activated_phase = self.activated_phase.borrow_mut();
+
+        if let Some(p) = activated_phase.get(&order) {
+            if *p != phase {
+                panic!(
+                    "Another PHASE has already been registered in this order: {:?}",
+                    p
+                );
+            }
+        }
+
+        let current = activated_phase
+            .iter()
+            .find(|(_order, p)| **p == phase)
+            .map(|(&order, _p)| order);
+        match current {
+            Some(current) => {
+                activated_phase.remove(&current);
+                activated_phase.insert(order, phase);
+            }
+            None => panic!("PHASE is not activated: {:?}", phase),
+        }
+    }
+
+    // wakeされたタスクをparkedから戻す。
+    // このフレームで実行済みのPhaseのタスクはwait_tasksに、それ以外はtasksに戻す。
+    fn wake_parked_tasks(&self, done_phases: &[T]) {
+        let woken = std::mem::take(&mut *self.wake_queue.lock().unwrap());
+        if woken.is_empty() {
+            return;
+        }
+
+        let mut parked = self.parked.borrow_mut();
+        let mut tasks = self.tasks.borrow_mut();
+        let mut wait_tasks = self.wait_tasks.borrow_mut();
+        for id in woken {
+            // poll中にwakeされてそのまま再pollされたタスクなど、
+            // parkedに見つからないIDは無視してよい
+            for (phase, ps) in parked.iter_mut() {
+                if let Some(task) = ps.remove(&id) {
+                    let queue = if done_phases.contains(phase) {
+                        &mut wait_tasks
+                    } else {
+                        &mut tasks
+                    };
+                    queue.entry(phase.clone()).or_insert(vec![]).push(task);
+                    break;
+                }
+            }
+        }
+    }
+
+    // Deactivateされたまま次のフレームを迎えたPhaseのタスクを破棄する。
+    fn drop_deactivated_tasks(&self) {
+        let deactivated = std::mem::take(&mut *self.deactivated_phases.borrow_mut());
+        let mut dropped = vec![];
+        {
+            let activated_phase = self.activated_phase.borrow();
+            for phase in deactivated.iter() {
+                // 再びActivateされたPhaseのタスクは残す
+                if activated_phase.values().any(|p| p == phase) {
+                    continue;
+                }
+                dropped.extend(self.tasks.borrow_mut().remove(phase));
+                dropped.extend(self.wait_tasks.borrow_mut().remove(phase));
+                dropped.extend(
+                    self.parked
+                        .borrow_mut()
+                        .remove(phase)
+                        .map(|ps| ps.into_values().collect()),
+                );
+            }
+        }
+
+        // borrowを解放してからdropする
+        drop(dropped);
+    }
 }
@@ -1,79 +1,207 @@
-use std::collections::HashMap;
+use std::any::{type_name, Any};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::future::Future;
 use std::hash::Hash;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::pin::Pin;
 use std::rc::Rc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
-use std::task::{Context, Poll, Waker};
-use std::thread::{self, JoinHandle};
+use std::task::{Context, Poll};
+use std::thread;
 use std::{cell::RefCell, time::Duration};
 
 use futures::task::ArcWake;
 
+use crate::join_handle::{joinable, JoinHandle, ReportPanic};
+use crate::task_panic::{panic_message, TaskPanicPolicy, TaskPanicked};
+use crate::task_snapshot::{TaskSnapshot, TaskState};
+
+type PanicPayload = Box<dyn Any + Send + 'static>;
+type BoxedTask = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+type Restart = Arc<dyn Fn() -> BoxedTask + Send + Sync>;
+
+thread_local! {
+    // ワーカースレッドでタスクをpollしている間だけSomeになる。
+    // 中身はポーリング中のタスクがnext_frame()で次フレームの実行を予約したかどうか。
+    static NEXT_FRAME_REQUESTED: Cell<Option<bool>> = const { Cell::new(None) };
+}
+
+/// ポーリング中のタスクを次のフレームに実行するよう予約する。
+/// ランタイムの外でpollされている場合はfalseを返す。
+pub(crate) fn request_next_frame() -> bool {
+    NEXT_FRAME_REQUESTED.with(|requested| match requested.get() {
+        Some(_) => {
+            requested.set(Some(true));
+            true
+        }
+        None => false,
+    })
+}
+
+type TaskId = u64;
+
+// wakeされたタスクのIDを積んでおくキュー。
+// Phaseの境界でランタイムが取り出して、待機中のタスクを実行可能に戻す。
+type WakeQueue = Arc<Mutex<Vec<TaskId>>>;
+
+struct TaskWaker {
+    id: TaskId,
+    woken: AtomicBool,
+    wake_queue: WakeQueue,
+}
+impl ArcWake for TaskWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        // 既にwakeされている場合はキューに積まない
+        if !arc_self.woken.swap(true, Ordering::AcqRel) {
+            arc_self.wake_queue.lock().unwrap().push(arc_self.id);
+        }
+    }
+}
+
+fn task_waker(id: TaskId, wake_queue: WakeQueue) -> Arc<TaskWaker> {
+    Arc::new(TaskWaker {
+        id,
+        woken: AtomicBool::new(false),
+        wake_queue,
+    })
+}
+
 struct Task {
-    future: Pin<Box<dyn Future<Output = ()> + Send + 'static>>,
+    id: TaskId,
+    name: Option<String>,
+    spawn_frame: u64,
+    poll_count: u64,
+    last_polled_frame: Option<u64>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    future: BoxedTask,
+    report_panic: Option<ReportPanic>,
+    restart: Option<Restart>,
+    waker: Arc<TaskWaker>,
 }
 impl Task {
-    fn new(f: impl Future<Output = ()> + Send + 'static) -> Self {
+    fn new(
+        id: TaskId,
+        name: Option<String>,
+        spawn_frame: u64,
+        f: impl Future<Output = ()> + Send + 'static,
+        report_panic: ReportPanic,
+        wake_queue: WakeQueue,
+    ) -> Self {
         Self {
+            id,
+            #[cfg(feature = "tracing")]
+            span: task_span(name.as_deref(), spawn_frame),
+            name,
+            spawn_frame,
+            poll_count: 0,
+            last_polled_frame: None,
             future: Box::pin(f),
+            report_panic: Some(report_panic),
+            restart: None,
+            waker: task_waker(id, wake_queue),
         }
     }
 
-    fn poll(&mut self, mut ctx: Context) -> Poll<()> {
-        match Future::poll(self.future.as_mut(), &mut ctx) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(()) => Poll::Ready(()),
+    // panicしたときに作り直せるタスクを作る。
+    fn restartable(
+        id: TaskId,
+        name: String,
+        spawn_frame: u64,
+        restart: Restart,
+        wake_queue: WakeQueue,
+    ) -> Self {
+        Self {
+            id,
+            #[cfg(feature = "tracing")]
+            span: task_span(Some(&name), spawn_frame),
+            name: Some(name),
+            spawn_frame,
+            poll_count: 0,
+            last_polled_frame: None,
+            future: restart(),
+            report_panic: None,
+            restart: Some(restart),
+            waker: task_waker(id, wake_queue),
         }
     }
-}
 
-#[derive(Clone)]
-struct WakeFlag {
-    waked: Arc<Mutex<bool>>,
-}
-impl WakeFlag {
-    fn new() -> Self {
-        Self {
-            waked: Arc::new(Mutex::new(false)),
-        }
+    // タスク内のpanicは捕捉してペイロードを返す。
+    // pollしたタスクがnext_frame()で次フレームを予約したかどうかも返す。
+    fn poll(&mut self) -> (Result<Poll<()>, PanicPayload>, bool) {
+        // タスク内のイベントがどのタスクのどのフレームのものか分かるようにする
+        #[cfg(feature = "tracing")]
+        let _entered =
+            tracing::info_span!(parent: &self.span, "poll", frame = self.last_polled_frame)
+                .entered();
+        self.poll_count += 1;
+        let waker = futures::task::waker_ref(&self.waker);
+        let mut ctx = Context::from_waker(&waker);
+        let future = self.future.as_mut();
+
+        NEXT_FRAME_REQUESTED.with(|requested| requested.set(Some(false)));
+        let poll = catch_unwind(AssertUnwindSafe(|| Future::poll(future, &mut ctx)));
+        let next_frame_requested =
+            NEXT_FRAME_REQUESTED.with(|requested| requested.take()) == Some(true);
+        (poll, next_frame_requested)
     }
 
-    fn wake(&self) {
-        *self.waked.lock().unwrap() = true;
+    fn take_woken(&self) -> bool {
+        self.waker.woken.swap(false, Ordering::AcqRel)
     }
 
-    fn is_waked(&self) -> bool {
-        *self.waked.lock().unwrap()
+    fn snapshot<T>(&self, phase: T, state: TaskState) -> TaskSnapshot<T> {
+        TaskSnapshot::new(
+            self.name.clone(),
+            phase,
+            self.spawn_frame,
+            self.poll_count,
+            self.last_polled_frame,
+            state,
+        )
     }
 }
 
-#[derive(Clone)]
-struct WakeFlagWaker {
-    flag: WakeFlag,
-}
-impl WakeFlagWaker {
-    fn waker(flag: WakeFlag) -> Waker {
-        futures::task::waker(Arc::new(Self { flag }))
+// タスクのpollごとのspanの親になるspanを作る。
+// タスクは起動したタスクより長く生きることがあるので、親のないspanにする。
+#[cfg(feature = "tracing")]
+fn task_span(name: Option<&str>, spawn_frame: u64) -> tracing::Span {
+    let span = tracing::info_span!(
+        parent: None,
+        "task",
+        name = tracing::field::Empty,
+        spawn_frame
+    );
+    if let Some(name) = name {
+        span.record("name", name);
     }
+    span
 }
-impl ArcWake for WakeFlagWaker {
-    fn wake_by_ref(arc_self: &Arc<Self>) {
-        arc_self.flag.wake();
-    }
+
+// ワーカースレッドからメインスレッドに返すタスク。
+struct ProcessedTasks {
+    // next_frame()で次のフレームの実行を予約したタスク
+    next_frame: Vec<Task>,
+    // wakeされるまで実行しないタスク
+    parked: Vec<Task>,
+    panicked: Vec<(Task, PanicPayload)>,
 }
 
 /// 非同期タスクがすべて終了したかどうかのenum。
+#[derive(Debug)]
 pub enum RuntimeIsDone {
     Done,
     NotDone,
 }
 
-fn process_tasks(mut tasks: Vec<Task>) -> Vec<Task> {
-    let mut wait_tasks = vec![];
+fn process_tasks(mut tasks: Vec<Task>) -> ProcessedTasks {
+    let mut next_frame = vec![];
+    let mut parked = vec![];
+    let mut panicked = vec![];
 
     'current_frame: loop {
         let task = tasks.pop();
@@ -82,18 +210,23 @@ fn process_tasks(mut tasks: Vec<Task>) -> Vec<Task> {
             // tasksが空だった場合は次のphaseへ
             None => break 'current_frame,
             Some(mut task) => {
-                let flag = WakeFlag::new();
-                let waker = WakeFlagWaker::waker(flag.clone());
-
-                match task.poll(Context::from_waker(&waker)) {
-                    Poll::Ready(()) => (),
-                    Poll::Pending => {
-                        // タスクがwake済みだったらtasksにpush
-                        // そうでなかったらwait_tasksにpushする
-                        if flag.is_waked() {
+                // poll前にwakeフラグを下ろしておき、poll中や後のwakeを検出する
+                task.take_woken();
+
+                match task.poll() {
+                    (Ok(Poll::Ready(())), _) => (),
+                    // panicしたタスクはメインスレッドで報告する
+                    (Err(payload), _) => panicked.push((task, payload)),
+                    (Ok(Poll::Pending), next_frame_requested) => {
+                        // 次フレームを予約したタスクはnext_frameにpush
+                        // wake済みだったらtasksにpushしてこのPhaseの中でもう一度poll
+                        // そうでなかったらwakeされるまでparkedにpushする
+                        if next_frame_requested {
+                            next_frame.push(task);
+                        } else if task.take_woken() {
                             tasks.push(task);
                         } else {
-                            wait_tasks.push(task);
+                            parked.push(task);
                         }
                     }
                 }
@@ -101,20 +234,33 @@ fn process_tasks(mut tasks: Vec<Task>) -> Vec<Task> {
         }
     }
 
-    wait_tasks
+    ProcessedTasks {
+        next_frame,
+        parked,
+        panicked,
+    }
 }
 
 /// ゲームループ用の非同期ランタイム。
 #[derive(Clone)]
 pub struct Runtime<T: Eq + Hash + Clone + Debug> {
     frame_counter: u64,
+    next_task_id: Rc<Cell<TaskId>>,
     tasks: Rc<RefCell<HashMap<T, Vec<Task>>>>,
     wait_tasks: Rc<RefCell<HashMap<T, Vec<Task>>>>,
+    // wakeされるまでpollしないタスク
+    parked: Rc<RefCell<HashMap<T, HashMap<TaskId, Task>>>>,
+    wake_queue: WakeQueue,
     activated_phase: Rc<RefCell<HashMap<u16, T>>>,
-    threads: Rc<RefCell<Vec<Option<JoinHandle<()>>>>>,
-    receivers: Rc<[Receiver<Vec<Task>>; 2]>,
+    paused_phases: Rc<RefCell<HashSet<T>>>,
+    deactivated_phases: Rc<RefCell<Vec<T>>>,
+    threads: Rc<RefCell<Vec<Option<thread::JoinHandle<()>>>>>,
+    receivers: Rc<[Receiver<ProcessedTasks>; 2]>,
     senders: [Sender<Vec<Task>>; 2],
     thread_stop_flag: Arc<AtomicBool>,
+    task_panic_policy: Rc<Cell<TaskPanicPolicy>>,
+    task_panics: Rc<RefCell<Vec<TaskPanicked<T>>>>,
+    aborted: Rc<RefCell<Option<TaskPanicked<T>>>>,
 }
 impl<T: Eq + Hash + Clone + Debug> Runtime<T> {
     /// 新しくRuntimeを作成して返す。
@@ -127,8 +273,8 @@ impl<T: Eq + Hash + Clone + Debug> Runtime<T> {
         let thread1 = thread::spawn(move || loop {
             match thread_receiver1.recv_timeout(Duration::from_millis(16)) {
                 Ok(tasks) => {
-                    let wait_tasks = process_tasks(tasks);
-                    thread_sender1.send(wait_tasks).unwrap();
+                    let processed = process_tasks(tasks);
+                    thread_sender1.send(processed).unwrap();
                 }
                 Err(_) => (),
             }
@@ -144,8 +290,8 @@ impl<T: Eq + Hash + Clone + Debug> Runtime<T> {
         let thread2 = thread::spawn(move || loop {
             match thread_receiver2.recv_timeout(Duration::from_millis(16)) {
                 Ok(tasks) => {
-                    let wait_tasks = process_tasks(tasks);
-                    thread_sender2.send(wait_tasks).unwrap();
+                    let processed = process_tasks(tasks);
+                    thread_sender2.send(processed).unwrap();
                 }
                 Err(_) => (),
             }
@@ -157,37 +303,197 @@ impl<T: Eq + Hash + Clone + Debug> Runtime<T> {
 
         Self {
             frame_counter: 0,
+            next_task_id: Rc::new(Cell::new(0)),
             tasks: Rc::new(RefCell::new(HashMap::new())),
             wait_tasks: Rc::new(RefCell::new(HashMap::new())),
+            parked: Rc::new(RefCell::new(HashMap::new())),
+            wake_queue: Arc::new(Mutex::new(vec![])),
             activated_phase: Rc::new(RefCell::new(HashMap::new())),
+            paused_phases: Rc::new(RefCell::new(HashSet::new())),
+            deactivated_phases: Rc::new(RefCell::new(vec![])),
             threads: Rc::new(RefCell::new(vec![Some(thread1), Some(thread2)])),
             receivers: Rc::new([main_receiver1, main_receiver2]),
             senders: [main_sender1, main_sender2],
             thread_stop_flag,
+            task_panic_policy: Rc::new(Cell::new(TaskPanicPolicy::Ignore)),
+            task_panics: Rc::new(RefCell::new(vec![])),
+            aborted: Rc::new(RefCell::new(None)),
         }
     }
 
     /// タスクを起動する関数。
     /// 同一Phaseのタスクの実行順序は不定。
-    pub fn spawn(&self, phase: T, f: impl Future<Output = ()> + Send + 'static) {
+    ///
+    /// 返り値の[`JoinHandle`]を`.await`するとタスクの結果を受け取れる。
+    pub fn spawn<Fut>(&self, phase: T, f: Fut) -> JoinHandle<Fut::Output>
+    where
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        self.spawn_task(phase, None, f)
+    }
+
+    /// 名前を付けてタスクを起動する関数。
+    /// 名前は[`Runtime::tasks_snapshot`]や[`TaskPanicked`]で参照できる。
+    ///
+    /// 返り値の[`JoinHandle`]を`.await`するとタスクの結果を受け取れる。
+    pub fn spawn_named<Fut>(
+        &self,
+        phase: T,
+        name: impl Into<String>,
+        f: Fut,
+    ) -> JoinHandle<Fut::Output>
+    where
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        self.spawn_task(phase, Some(name.into()), f)
+    }
+
+    fn spawn_task<Fut>(&self, phase: T, name: Option<String>, f: Fut) -> JoinHandle<Fut::Output>
+    where
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        let (task, handle, report_panic) = joinable(f);
+        let task = Task::new(
+            self.next_task_id(),
+            name,
+            self.frame_counter,
+            task,
+            report_panic,
+            Arc::clone(&self.wake_queue),
+        );
+        self.push_task(phase, task);
+        handle
+    }
+
+    /// panicしても作り直せるタスクを起動する関数。
+    /// タスクの名前は`f`の型名になる。
+    ///
+    /// [`TaskPanicPolicy::Restart`]のとき、タスクがpanicすると`f`を呼んでタスクを作り直し、
+    /// 同じPhaseで次のフレームから実行する。
+    pub fn spawn_restartable<F, Fut>(&self, phase: T, f: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let restart: Restart = Arc::new(move || Box::pin(f()));
+        let task = Task::restartable(
+            self.next_task_id(),
+            type_name::<F>().to_string(),
+            self.frame_counter,
+            restart,
+            Arc::clone(&self.wake_queue),
+        );
+        self.push_task(phase, task);
+    }
+
+    /// タスクがpanicしたときのポリシーを設定する関数。
+    /// デフォルトは[`TaskPanicPolicy::Ignore`]。
+    pub fn set_task_panic_policy(&self, policy: TaskPanicPolicy) {
+        self.task_panic_policy.set(policy);
+    }
+
+    /// 前回の呼び出しからpanicしたタスクのイベントを取り出す関数。
+    pub fn task_panics(&self) -> Vec<TaskPanicked<T>> {
+        std::mem::take(&mut *self.task_panics.borrow_mut())
+    }
+
+    /// 生きているタスクの一覧を返す関数。順序は不定。
+    ///
+    /// wakeを待っているタスクと一時停止中のPhaseのタスクが[`TaskState::Parked`]になる。
+    /// Phaseの実行中に呼び出した場合、実行中のPhaseのタスクは含まれない。
+    pub fn tasks_snapshot(&self) -> Vec<TaskSnapshot<T>> {
+        let paused_phases = self.paused_phases.borrow();
+        let tasks = self.tasks.borrow();
+        let wait_tasks = self.wait_tasks.borrow();
+        let parked = self.parked.borrow();
+        let runnable = tasks
+            .iter()
+            .chain(wait_tasks.iter())
+            .flat_map(|(phase, ts)| {
+                let state = if paused_phases.contains(phase) {
+                    TaskState::Parked
+                } else {
+                    TaskState::Runnable
+                };
+                ts.iter()
+                    .map(move |task| task.snapshot(phase.clone(), state))
+            });
+        let parked = parked.iter().flat_map(|(phase, ts)| {
+            ts.values()
+                .map(move |task| task.snapshot(phase.clone(), TaskState::Parked))
+        });
+        runnable.chain(parked).collect()
+    }
+
+    fn next_task_id(&self) -> TaskId {
+        let id = self.next_task_id.get();
+        self.next_task_id.set(id + 1);
+        id
+    }
+
+    fn push_task(&self, phase: T, task: Task) {
         let mut tasks = self.tasks.borrow_mut();
         let ts = tasks.entry(phase).or_insert(vec![]);
-        ts.push(Task::new(f));
+        ts.push(task);
     }
 
     /// 毎フレーム呼び出すべき関数。
     /// 各Phaseのタスクを順に実行していく。
-    pub fn update(&mut self) -> RuntimeIsDone {
+    ///
+    /// 各Phaseでpollされるのは、next_frame()で次フレームを予約したタスクと
+    /// wakeされたタスクだけである。
+    ///
+    /// [`TaskPanicPolicy::Abort`]のとき、タスクがpanicしたフレームの終わりに
+    /// 最初にpanicしたタスクの[`TaskPanicked`]を返す。
+    pub fn update(&mut self) -> Result<RuntimeIsDone, TaskPanicked<T>> {
+        #[cfg(feature = "tracing")]
+        let _entered = tracing::info_span!("update", frame = self.frame_counter).entered();
+
+        // 前のフレームでDeactivateされたPhaseのタスクを破棄する
+        self.drop_deactivated_tasks();
+
         // ActivateされているPhaseをソートする
-        let activated_phase = self.activated_phase.borrow();
-        let mut phases = activated_phase.iter().collect::<Vec<_>>();
-        phases.sort_by_key(|(&order, _phase)| order);
-        let phases = phases.into_iter().map(|(_order, phase)| phase);
+        // フレームの途中でPhaseが変更されても次のフレームまで反映されないように複製しておく
+        let phases = {
+            let activated_phase = self.activated_phase.borrow();
+            let mut phases = activated_phase.iter().collect::<Vec<_>>();
+            phases.sort_by_key(|(&order, _phase)| order);
+            phases
+                .into_iter()
+                .map(|(_order, phase)| phase.clone())
+                .collect::<Vec<_>>()
+        };
+        let paused_phases = self.paused_phases.borrow().clone();
+
+        for (i, phase) in phases.iter().enumerate() {
+            #[cfg(feature = "tracing")]
+            let _entered = tracing::info_span!("phase", phase = ?phase).entered();
+
+            // Phaseの境界でwakeされたタスクを実行可能に戻す
+            self.wake_parked_tasks(&phases[..i]);
+
+            // 一時停止中のPhaseのタスクはpollせずにwait_tasksに残す
+            if paused_phases.contains(phase) {
+                let paused = self.tasks.borrow_mut().remove(phase).unwrap_or_default();
+                let mut wait_tasks = self.wait_tasks.borrow_mut();
+                wait_tasks
+                    .entry(phase.clone())
+                    .or_insert(vec![])
+                    .extend(paused);
+                continue;
+            }
 
-        for phase in phases {
             let mut tasks = self.tasks.borrow_mut();
             let tasks = tasks.entry(phase.clone()).or_insert(vec![]);
 
+            // 送ったタスクはすべてこのフレームでpollされる
+            for task in tasks.iter_mut() {
+                task.last_polled_frame = Some(self.frame_counter);
+            }
+
             // tasksを二分割する
             let tasks1 = tasks.split_off(tasks.len() / 2);
             let tasks2 = tasks.drain(0..).collect();
@@ -197,19 +503,42 @@ impl<T: Eq + Hash + Clone + Debug> Runtime<T> {
             self.senders[1].send(tasks2).unwrap();
 
             // スレッドからの応答を待つ
-            let wait_tasks1 = self.receivers[0].recv().unwrap();
-            let wait_tasks2 = self.receivers[1].recv().unwrap();
-            let wait_tasks = wait_tasks1
-                .into_iter()
-                .chain(wait_tasks2.into_iter())
-                .collect::<Vec<_>>();
-
-            let mut wts = self.wait_tasks.borrow_mut();
-            wts.insert(phase.clone(), wait_tasks);
+            let processed1 = self.receivers[0].recv().unwrap();
+            let processed2 = self.receivers[1].recv().unwrap();
+
+            // 各スレッドから帰ってきたタスクを戻す
+            self.wait_tasks
+                .borrow_mut()
+                .entry(phase.clone())
+                .or_insert(vec![])
+                .extend(
+                    processed1
+                        .next_frame
+                        .into_iter()
+                        .chain(processed2.next_frame),
+                );
+            self.parked
+                .borrow_mut()
+                .entry(phase.clone())
+                .or_default()
+                .extend(
+                    processed1
+                        .parked
+                        .into_iter()
+                        .chain(processed2.parked)
+                        .map(|task| (task.id, task)),
+                );
+
+            for (task, payload) in processed1.panicked.into_iter().chain(processed2.panicked) {
+                self.handle_panicked_task(phase, task, payload);
+            }
         }
 
-        {
-            // すべてのPhaseのwait_tasksが空の場合、全てのタスクの実行が終わっている。
+        // このフレームの中でwakeされたタスクは次のフレームで実行する
+        self.wake_parked_tasks(&phases);
+
+        let done_flag = {
+            // すべてのPhaseのwait_tasksとparkedが空の場合、全てのタスクの実行が終わっている。
             let mut done_flag = true;
             let wait_tasks = self.wait_tasks.borrow();
             for (_p, tasks) in wait_tasks.iter() {
@@ -217,18 +546,34 @@ impl<T: Eq + Hash + Clone + Debug> Runtime<T> {
                     done_flag = false;
                 }
             }
-            if done_flag {
-                return RuntimeIsDone::Done;
+            let parked = self.parked.borrow();
+            for (_p, tasks) in parked.iter() {
+                if !tasks.is_empty() {
+                    done_flag = false;
+                }
             }
-        }
+            done_flag
+        };
+
+        if !done_flag {
+            // 次のフレームに移る前にフレームカウンターを更新する
+            self.frame_counter += 1;
 
-        // 次のフレームに移る前にフレームカウンターを更新する
-        self.frame_counter += 1;
+            // wait_tasksを空のtasks_queueと交換する
+            std::mem::swap(&mut self.wait_tasks, &mut self.tasks);
+        }
 
-        // wait_tasksを空のtasks_queueと交換する
-        std::mem::swap(&mut self.wait_tasks, &mut self.tasks);
+        // Abortのときは最初にpanicしたタスクのイベントをフレームを終えてから返す
+        let aborted = self.aborted.borrow_mut().take();
+        if let Some(event) = aborted {
+            return Err(event);
+        }
 
-        RuntimeIsDone::NotDone
+        if done_flag {
+            Ok(RuntimeIsDone::Done)
+        } else {
+            Ok(RuntimeIsDone::NotDone)
+        }
     }
 
     /// 現在のフレームカウントを返す関数。
@@ -256,6 +601,164 @@ impl<T: Eq + Hash + Clone + Debug> Runtime<T> {
 
         activated_phase.insert(order, phase);
     }
+
+    /// Phaseの登録を解除する関数。
+    ///
+    /// DeactivateされたPhaseは次のフレームから実行されなくなり、
+    /// 次のフレームの開始時までに再びActivateされなければPhaseのタスクは破棄される。
+    /// タスクを残したまま実行を止める場合は[`Runtime::pause_phase`]を使う。
+    pub fn deactivate_phase(&self, phase: T) {
+        self.activated_phase
+            .borrow_mut()
+            .retain(|_order, p| *p != phase);
+        self.paused_phases.borrow_mut().remove(&phase);
+        self.deactivated_phases.borrow_mut().push(phase);
+    }
+
+    /// Phaseを一時停止する関数。
+    ///
+    /// 一時停止したPhaseは次のフレームから[`Runtime::resume_phase`]を呼ぶまで実行されない。
+    /// Phaseのタスクはpollされずにwait_tasksに残る。
+    pub fn pause_phase(&self, phase: T) {
+        self.paused_phases.borrow_mut().insert(phase);
+    }
+
+    /// 一時停止したPhaseを再開する関数。
+    /// Phaseは次のフレームから実行される。
+    pub fn resume_phase(&self, phase: &T) {
+        self.paused_phases.borrow_mut().remove(phase);
+    }
+
+    /// ActivateされているPhaseの実行順序を変更する関数。
+    /// 変更は次のフレームから反映される。
+    ///
+    /// ## panic
+    /// PhaseがActivateされていない場合や、
+    /// orderに他のPhaseと重複した値を指定した場合、panicする。
+    pub fn set_phase_order(&self, phase: T, order: u16) {
+        let mut activated_phase = self.activated_phase.borrow_mut();
+
+        if let Some(p) = activated_phase.get(&order) {
+            if *p != phase {
+                panic!(
+                    "Another PHASE has already been registered in this order: {:?}",
+                    p
+                );
+            }
+        }
+
+        let current = activated_phase
+            .iter()
+            .find(|(_order, p)| **p == phase)
+            .map(|(&order, _p)| order);
+        match current {
+            Some(current) => {
+                activated_phase.remove(&current);
+                activated_phase.insert(order, phase);
+            }
+            None => panic!("PHASE is not activated: {:?}", phase),
+        }
+    }
+
+    // panicしたタスクを報告し、ポリシーに従って処理する。
+    fn handle_panicked_task(&self, phase: &T, mut task: Task, payload: PanicPayload) {
+        // JoinHandleにはメッセージだけを報告し、ペイロードはイベントに渡す
+        if let Some(report_panic) = task.report_panic.take() {
+            report_panic(Box::new(panic_message(&*payload)));
+        }
+        let restart = task.restart.clone();
+        let event = TaskPanicked::new(
+            task.name.clone(),
+            phase.clone(),
+            self.frame_counter,
+            payload,
+        );
+        drop(task);
+
+        match self.task_panic_policy.get() {
+            TaskPanicPolicy::Abort => {
+                let mut aborted = self.aborted.borrow_mut();
+                if aborted.is_none() {
+                    *aborted = Some(event);
+                    return;
+                }
+            }
+            TaskPanicPolicy::Restart => {
+                // 作り直したタスクは次のフレームから実行する
+                if let Some(restart) = restart {
+                    let name = event.task_name().unwrap_or_default().to_string();
+                    let task = Task::restartable(
+                        self.next_task_id(),
+                        name,
+                        self.frame_counter,
+                        restart,
+                        Arc::clone(&self.wake_queue),
+                    );
+                    self.wait_tasks
+                        .borrow_mut()
+                        .entry(phase.clone())
+                        .or_insert(vec![])
+                        .push(task);
+                }
+            }
+            TaskPanicPolicy::Ignore => (),
+        }
+        self.task_panics.borrow_mut().push(event);
+    }
+
+    // wakeされたタスクをparkedから戻す。
+    // このフレームで実行済みのPhaseのタスクはwait_tasksに、それ以外はtasksに戻す。
+    fn wake_parked_tasks(&self, done_phases: &[T]) {
+        let woken = std::mem::take(&mut *self.wake_queue.lock().unwrap());
+        if woken.is_empty() {
+            return;
+        }
+
+        let mut parked = self.parked.borrow_mut();
+        let mut tasks = self.tasks.borrow_mut();
+        let mut wait_tasks = self.wait_tasks.borrow_mut();
+        for id in woken {
+            // poll中にwakeされてそのまま再pollされたタスクなど、
+            // parkedに見つからないIDは無視してよい
+            for (phase, ps) in parked.iter_mut() {
+                if let Some(task) = ps.remove(&id) {
+                    let queue = if done_phases.contains(phase) {
+                        &mut wait_tasks
+                    } else {
+                        &mut tasks
+                    };
+                    queue.entry(phase.clone()).or_insert(vec![]).push(task);
+                    break;
+                }
+            }
+        }
+    }
+
+    // Deactivateされたまま次のフレームを迎えたPhaseのタスクを破棄する。
+    fn drop_deactivated_tasks(&self) {
+        let deactivated = std::mem::take(&mut *self.deactivated_phases.borrow_mut());
+        let mut dropped = vec![];
+        {
+            let activated_phase = self.activated_phase.borrow();
+            for phase in deactivated.iter() {
+                // 再びActivateされたPhaseのタスクは残す
+                if activated_phase.values().any(|p| p == phase) {
+                    continue;
+                }
+                dropped.extend(self.tasks.borrow_mut().remove(phase));
+                dropped.extend(self.wait_tasks.borrow_mut().remove(phase));
+                dropped.extend(
+                    self.parked
+                        .borrow_mut()
+                        .remove(phase)
+                        .map(|ps| ps.into_values().collect()),
+                );
+            }
+        }
+
+        // borrowを解放してからdropする
+        drop(dropped);
+    }
 }
 impl<T: Eq + Hash + Clone + Debug> Drop for Runtime<T> {
     fn drop(&mut self) {
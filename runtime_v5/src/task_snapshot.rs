@@ -0,0 +1,91 @@
+use std::fmt::{self, Debug};
+
+/// タスクが次にpollされる条件。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Phaseが次に実行されるときにpollされる。
+    Runnable,
+    /// wakeされるか、一時停止されているPhaseが再開されるまでpollされない。
+    Parked,
+}
+
+/// [`Runtime::tasks_snapshot`]で取得する、生きているタスクの状態。
+///
+/// [`Runtime::tasks_snapshot`]: crate::Runtime::tasks_snapshot
+#[derive(Debug, Clone)]
+pub struct TaskSnapshot<T> {
+    name: Option<String>,
+    phase: T,
+    spawn_frame: u64,
+    poll_count: u64,
+    last_polled_frame: Option<u64>,
+    state: TaskState,
+}
+impl<T> TaskSnapshot<T> {
+    pub(crate) fn new(
+        name: Option<String>,
+        phase: T,
+        spawn_frame: u64,
+        poll_count: u64,
+        last_polled_frame: Option<u64>,
+        state: TaskState,
+    ) -> Self {
+        Self {
+            name,
+            phase,
+            spawn_frame,
+            poll_count,
+            last_polled_frame,
+            state,
+        }
+    }
+
+    /// タスクの名前を返す関数。
+    /// 名前のないタスクの場合はNoneを返す。
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// タスクが登録されているPhaseを返す関数。
+    pub fn phase(&self) -> &T {
+        &self.phase
+    }
+
+    /// タスクが起動されたフレームを返す関数。
+    pub fn spawn_frame(&self) -> u64 {
+        self.spawn_frame
+    }
+
+    /// タスクがpollされた回数を返す関数。
+    pub fn poll_count(&self) -> u64 {
+        self.poll_count
+    }
+
+    /// タスクが最後にpollされたフレームを返す関数。
+    /// まだpollされていない場合はNoneを返す。
+    pub fn last_polled_frame(&self) -> Option<u64> {
+        self.last_polled_frame
+    }
+
+    /// タスクがparkされているか実行可能かを返す関数。
+    pub fn state(&self) -> TaskState {
+        self.state
+    }
+}
+impl<T: Debug> fmt::Display for TaskSnapshot<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "task {}", name)?,
+            None => write!(f, "task")?,
+        }
+        write!(
+            f,
+            " in PHASE {:?}: {:?}, spawned at frame {}, polled {} times",
+            self.phase, self.state, self.spawn_frame, self.poll_count
+        )?;
+        match self.last_polled_frame {
+            Some(frame) => write!(f, ", last at frame {}", frame),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! parkされたタスクの数がフレームあたりのコストに影響しないことを確かめるベンチマーク。
+//!
+//! `cargo bench -p runtime_v6` で実行する。
+
+use std::convert::Infallible;
+use std::time::{Duration, Instant};
+
+use runtime_v6::{next_frame, Runtime, World};
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+enum Phase {
+    Update,
+}
+
+struct BenchWorld;
+impl World for BenchWorld {
+    type Command = ();
+    type Error = Infallible;
+    fn process_command(&mut self, _cmd: Self::Command) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+const ACTIVE_TASKS: usize = 100;
+const WARMUP_FRAMES: usize = 10;
+const MEASURE_FRAMES: usize = 300;
+
+fn measure(parked_tasks: usize) -> Duration {
+    let mut runtime = Runtime::new(BenchWorld);
+    runtime.activate_phase(Phase::Update, []).unwrap();
+
+    // wakeされることのないタスク
+    for _ in 0..parked_tasks {
+        runtime.spawn(Phase::Update, futures::future::pending::<()>());
+    }
+
+    // 毎フレーム実行されるタスク
+    for _ in 0..ACTIVE_TASKS {
+        runtime.spawn(Phase::Update, async {
+            loop {
+                next_frame().await;
+            }
+        });
+    }
+
+    for _ in 0..WARMUP_FRAMES {
+        runtime.update().unwrap();
+    }
+
+    let start = Instant::now();
+    for _ in 0..MEASURE_FRAMES {
+        runtime.update().unwrap();
+    }
+    start.elapsed() / MEASURE_FRAMES as u32
+}
+
+fn main() {
+    for &parked_tasks in [0, 1_000, 10_000].iter() {
+        let per_frame = measure(parked_tasks);
+        println!(
+            "active tasks: {:>5}, parked tasks: {:>6}, time per frame: {:?}",
+            ACTIVE_TASKS, parked_tasks, per_frame
+        );
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// タスクを識別するためのID。
+/// ランタイムをまたいでも重複しない。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(u64);
+impl TaskId {
+    pub(crate) fn next() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// タスクを外部から中断するためのハンドル。
+///
+/// [`AbortHandle::abort`]されたタスクは次のPhaseの境界でdropされる。
+/// Futureのデストラクタはその時点でメインスレッド上で実行される。
+#[derive(Clone)]
+pub struct AbortHandle {
+    id: TaskId,
+    abort_requests: Arc<Mutex<HashSet<TaskId>>>,
+}
+impl AbortHandle {
+    pub(crate) fn new(id: TaskId, abort_requests: Arc<Mutex<HashSet<TaskId>>>) -> Self {
+        Self { id, abort_requests }
+    }
+
+    /// タスクのIDを返す関数。
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// タスクの中断を要求する関数。
+    /// 既に終了しているタスクに対して呼び出しても何も起こらない。
+    pub fn abort(&self) {
+        self.abort_requests.lock().unwrap().insert(self.id);
+    }
+}
+impl fmt::Debug for AbortHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AbortHandle").field("id", &self.id).finish()
+    }
+}
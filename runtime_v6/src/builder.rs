@@ -0,0 +1,130 @@
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::thread;
+
+use crate::clock::{Clock, RealTimeClock};
+use crate::executor::Executor;
+use crate::rng::SeededRng;
+use crate::runtime::Runtime;
+use crate::world::World;
+
+/// [`Runtime`]の設定を行うビルダー。
+pub struct RuntimeBuilder {
+    worker_threads: Option<usize>,
+    single_threaded: bool,
+    thread_name: String,
+    thread_stack_size: Option<usize>,
+    clock: Option<Box<dyn Clock>>,
+    rng: Option<SeededRng>,
+    deterministic: bool,
+}
+impl RuntimeBuilder {
+    /// デフォルトの設定でビルダーを作成する。
+    /// ワーカースレッドの数は利用可能な並列度になる。
+    pub fn new() -> Self {
+        Self {
+            worker_threads: None,
+            single_threaded: false,
+            thread_name: "runtime-worker".to_string(),
+            thread_stack_size: None,
+            clock: None,
+            rng: None,
+            deterministic: false,
+        }
+    }
+
+    /// ワーカースレッドの数を指定する関数。
+    ///
+    /// ## panic
+    /// 0を指定した場合、panicする。
+    pub fn worker_threads(mut self, n: usize) -> Self {
+        assert!(n > 0, "worker_threads must be greater than 0");
+        self.worker_threads = Some(n);
+        self
+    }
+
+    /// ワーカースレッドを作らず、update()を呼び出したスレッドでタスクを実行する。
+    /// デバッグ用。
+    pub fn single_threaded(mut self) -> Self {
+        self.single_threaded = true;
+        self
+    }
+
+    /// ワーカースレッドの名前を指定する関数。
+    /// 各スレッドには`{name}-{index}`という名前がつけられる。
+    pub fn thread_name(mut self, name: impl Into<String>) -> Self {
+        self.thread_name = name.into();
+        self
+    }
+
+    /// ワーカースレッドのスタックサイズをバイト単位で指定する関数。
+    pub fn thread_stack_size(mut self, size: usize) -> Self {
+        self.thread_stack_size = Some(size);
+        self
+    }
+
+    /// ゲーム時間の元になる時計を指定する関数。
+    /// 指定しなかった場合は[`RealTimeClock`]が使われる。
+    pub fn clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Some(Box::new(clock));
+        self
+    }
+
+    /// ランタイムの乱数生成器を指定する関数。
+    /// システムが受け取る[`SeededRng`]はこの乱数生成器からforkされる。
+    /// 指定しなかった場合は[`SeededRng::from_entropy`]が使われる。
+    pub fn rng(mut self, rng: SeededRng) -> Self {
+        self.rng = Some(rng);
+        self
+    }
+
+    /// 決定的モードでタスクを実行する。
+    ///
+    /// 決定的モードでは各Phaseのタスクを登録された順にpollする。
+    /// [`RuntimeBuilder::rng`]でシードを固定すると、同じ入力に対して毎回同じ結果になる。
+    ///
+    /// [`RuntimeBuilder::worker_threads`]を指定しなかった場合は、ワーカースレッドを作らず
+    /// update()を呼び出したスレッドでpollする。
+    /// 指定した場合はワーカースレッドで並列にpollするが、poll中に起動されたタスクや作られた
+    /// [`Commands`]、forkされた乱数生成器は、pollした順ではなく起動したタスクの順に並べるので、
+    /// コマンドの適用順やシステムの乱数列は変わらない。
+    /// ただし、同じPhaseのタスクが共有するリソースをpoll中に書き換える場合、その順序は保証されない。
+    ///
+    /// [`Commands`]: crate::Commands
+    pub fn deterministic(mut self) -> Self {
+        self.deterministic = true;
+        self
+    }
+
+    /// 設定からRuntimeを作成する関数。
+    ///
+    /// ## panic
+    /// ワーカースレッドを作成できなかった場合、panicする。
+    pub fn build<T: Eq + Hash + Clone + Debug + 'static, W: World>(
+        self,
+        world: W,
+    ) -> Runtime<T, W> {
+        // 決定的モードでは、ワーカースレッドの数を指定したときだけワーカースレッドを作る
+        let current_thread =
+            self.single_threaded || (self.deterministic && self.worker_threads.is_none());
+        let executor = if current_thread {
+            Executor::current_thread()
+        } else {
+            let worker_threads = self.worker_threads.unwrap_or_else(|| {
+                thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1)
+            });
+            Executor::workers(worker_threads, &self.thread_name, self.thread_stack_size)
+                .expect("failed to spawn runtime worker thread")
+        };
+        let clock = self.clock.unwrap_or_else(|| Box::new(RealTimeClock::new()));
+        let rng = self.rng.unwrap_or_else(SeededRng::from_entropy);
+        Runtime::with_executor(world, executor, clock, rng, self.deterministic)
+    }
+}
+impl Default for RuntimeBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,185 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::task::with_frame_context;
+
+/// ランタイムのゲーム時間の元になる時計。
+///
+/// [`Runtime::update`]の開始時に1回だけ[`Clock::tick`]が呼ばれ、
+/// 返された時間がそのフレームのデルタタイムになる。
+///
+/// [`Runtime::update`]: crate::Runtime::update
+pub trait Clock: Send + 'static {
+    /// 前回の呼び出しからの経過時間を返す関数。
+    fn tick(&mut self) -> Duration;
+}
+
+/// 実時間で進む時計。
+/// 最初のフレームのデルタタイムは0になる。
+pub struct RealTimeClock {
+    last: Option<Instant>,
+}
+impl RealTimeClock {
+    pub fn new() -> Self {
+        Self { last: None }
+    }
+}
+impl Default for RealTimeClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Clock for RealTimeClock {
+    fn tick(&mut self) -> Duration {
+        let now = Instant::now();
+        let delta = self
+            .last
+            .map_or(Duration::from_secs(0), |last| now.duration_since(last));
+        self.last = Some(now);
+        delta
+    }
+}
+
+/// 毎フレーム決まった時間だけ進む時計。
+/// 実際にフレームにかかった時間に関係なく結果が再現できる。
+pub struct FixedStepClock {
+    step: Duration,
+}
+impl FixedStepClock {
+    pub fn new(step: Duration) -> Self {
+        Self { step }
+    }
+}
+impl Clock for FixedStepClock {
+    fn tick(&mut self) -> Duration {
+        self.step
+    }
+}
+
+/// [`ManualClockHandle::advance`]で進めた分だけ進む時計。
+/// テストなどで時間を外から操作するために使う。
+pub struct ManualClock {
+    pending: Arc<Mutex<Duration>>,
+}
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            pending: Arc::new(Mutex::new(Duration::from_secs(0))),
+        }
+    }
+
+    /// 時計を進めるためのハンドルを返す関数。
+    pub fn handle(&self) -> ManualClockHandle {
+        ManualClockHandle {
+            pending: Arc::clone(&self.pending),
+        }
+    }
+}
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Clock for ManualClock {
+    fn tick(&mut self) -> Duration {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+}
+
+/// [`ManualClock`]を進めるためのハンドル。
+#[derive(Clone)]
+pub struct ManualClockHandle {
+    pending: Arc<Mutex<Duration>>,
+}
+impl ManualClockHandle {
+    /// 次のフレームのデルタタイムにdurationを加える関数。
+    pub fn advance(&self, duration: Duration) {
+        *self.pending.lock().unwrap() += duration;
+    }
+}
+
+/// あるフレームのゲーム時間。
+///
+/// デルタタイムと経過時間にはtime_scaleがかけられている。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Time {
+    frame_counter: u64,
+    delta_time: Duration,
+    elapsed: Duration,
+    unscaled_delta_time: Duration,
+    unscaled_elapsed: Duration,
+    time_scale: f64,
+}
+impl Time {
+    pub(crate) fn new() -> Self {
+        Self {
+            frame_counter: 0,
+            delta_time: Duration::from_secs(0),
+            elapsed: Duration::from_secs(0),
+            unscaled_delta_time: Duration::from_secs(0),
+            unscaled_elapsed: Duration::from_secs(0),
+            time_scale: 1.0,
+        }
+    }
+
+    // 新しいフレームの時間を計算する。
+    pub(crate) fn advance(&mut self, frame_counter: u64, delta: Duration) {
+        self.frame_counter = frame_counter;
+        self.unscaled_delta_time = delta;
+        self.unscaled_elapsed += delta;
+        self.delta_time = delta.mul_f64(self.time_scale);
+        self.elapsed += self.delta_time;
+    }
+
+    // 固定Phaseから見える時間。デルタタイムを固定タイムステップにする。
+    pub(crate) fn with_fixed_step(mut self, step: Duration) -> Self {
+        self.delta_time = step;
+        self
+    }
+
+    pub(crate) fn set_time_scale(&mut self, time_scale: f64) {
+        assert!(
+            time_scale >= 0.0 && time_scale.is_finite(),
+            "time_scale must be a finite non-negative number"
+        );
+        self.time_scale = time_scale;
+    }
+
+    /// フレームカウントを返す関数。
+    pub fn frame_counter(&self) -> u64 {
+        self.frame_counter
+    }
+
+    /// 前のフレームからの経過時間を返す関数。
+    pub fn delta_time(&self) -> Duration {
+        self.delta_time
+    }
+
+    /// ランタイムの開始からの経過時間を返す関数。
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// time_scaleをかける前のデルタタイムを返す関数。
+    pub fn unscaled_delta_time(&self) -> Duration {
+        self.unscaled_delta_time
+    }
+
+    /// time_scaleをかける前の経過時間を返す関数。
+    pub fn unscaled_elapsed(&self) -> Duration {
+        self.unscaled_elapsed
+    }
+
+    /// 時間の進む速さの倍率を返す関数。
+    pub fn time_scale(&self) -> f64 {
+        self.time_scale
+    }
+}
+
+/// 実行中のタスクから現在のフレームのゲーム時間を返す関数。
+///
+/// ## panic
+/// ランタイムの外で呼び出した場合、panicする。
+pub fn time() -> Time {
+    with_frame_context(|ctx| ctx.time).expect("time() must be called inside the runtime")
+}
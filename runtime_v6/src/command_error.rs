@@ -0,0 +1,92 @@
+use std::fmt::{self, Debug, Display};
+
+/// [`World::process_command`]が拒否したコマンドのエラー。
+///
+/// [`World::process_command`]: crate::World::process_command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandError<T, E> {
+    frame_counter: u64,
+    phase: Option<T>,
+    error: E,
+}
+impl<T, E> CommandError<T, E> {
+    pub(crate) fn new(frame_counter: u64, phase: Option<T>, error: E) -> Self {
+        Self {
+            frame_counter,
+            phase,
+            error,
+        }
+    }
+
+    /// コマンドが拒否されたフレームを返す関数。
+    pub fn frame_counter(&self) -> u64 {
+        self.frame_counter
+    }
+
+    /// コマンドを適用したPhaseを返す関数。
+    /// フレームの開始時や終わりに適用された場合はNoneを返す。
+    pub fn phase(&self) -> Option<&T> {
+        self.phase.as_ref()
+    }
+
+    /// [`World::process_command`]が返したエラーを返す関数。
+    ///
+    /// [`World::process_command`]: crate::World::process_command
+    pub fn error(&self) -> &E {
+        &self.error
+    }
+
+    /// [`World::process_command`]が返したエラーを取り出す関数。
+    ///
+    /// [`World::process_command`]: crate::World::process_command
+    pub fn into_error(self) -> E {
+        self.error
+    }
+}
+impl<T: Debug, E: Display> Display for CommandError<T, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.phase {
+            Some(phase) => write!(
+                f,
+                "command rejected after PHASE {:?} in frame {}: {}",
+                phase, self.frame_counter, self.error
+            ),
+            None => write!(
+                f,
+                "command rejected in frame {}: {}",
+                self.frame_counter, self.error
+            ),
+        }
+    }
+}
+impl<T: Debug, E: std::error::Error + 'static> std::error::Error for CommandError<T, E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// 拒否されたコマンドのエラーの扱い方。
+pub enum CommandErrorPolicy<T, E> {
+    /// エラーを溜めておき、[`Runtime::command_errors`]で取り出せるようにする。デフォルト。
+    ///
+    /// [`Runtime::command_errors`]: crate::Runtime::command_errors
+    Collect,
+    /// エラーごとにコールバックを呼ぶ。
+    /// コールバックはコマンドの適用が終わった後、ランタイムを実行しているスレッドで呼ばれる。
+    Callback(Box<dyn FnMut(CommandError<T, E>) + Send>),
+    /// フレームの終わりに[`Runtime::update`]が最初のエラーを返す。
+    /// 2つ目以降のエラーは[`Runtime::command_errors`]で取り出せる。
+    ///
+    /// [`Runtime::update`]: crate::Runtime::update
+    /// [`Runtime::command_errors`]: crate::Runtime::command_errors
+    Fail,
+}
+impl<T, E> Debug for CommandErrorPolicy<T, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandErrorPolicy::Collect => write!(f, "CommandErrorPolicy::Collect"),
+            CommandErrorPolicy::Callback(_) => write!(f, "CommandErrorPolicy::Callback(..)"),
+            CommandErrorPolicy::Fail => write!(f, "CommandErrorPolicy::Fail"),
+        }
+    }
+}
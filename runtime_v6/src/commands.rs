@@ -0,0 +1,206 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::task::{self, ChildKey};
+use crate::world::World;
+
+/// コマンドを適用するタイミング。
+#[derive(Debug, Clone)]
+pub enum FlushPoint {
+    /// 送信したPhaseの後の最初の同期点で適用する。
+    ///
+    /// 同期点は[`Runtime::add_sync_point`]で追加する。
+    /// 同期点が1つも追加されていない場合は、すべてのPhaseの後が同期点になる。
+    ///
+    /// [`Runtime::add_sync_point`]: crate::Runtime::add_sync_point
+    NextSyncPoint,
+    /// 指定したPhaseの後に適用する。[`FlushPoint::after_phase`]で作成する。
+    ///
+    /// 指定したPhaseがこのフレームで既に実行されていた場合は、次のフレームでそのPhaseの後に適用する。
+    /// 指定したPhaseがこのフレームで実行されない場合はフレームの終わりに適用する。
+    AfterPhase(PhaseKey),
+    /// このフレームのすべてのPhaseの後に適用する。
+    EndOfFrame,
+    /// 次のフレームの最初のPhaseの前に適用する。
+    NextFrame,
+}
+impl FlushPoint {
+    /// 指定したPhaseの後に適用するFlushPointを返す関数。
+    ///
+    /// ランタイムと異なる型のPhaseを指定した場合、コマンドはフレームの終わりに適用される。
+    pub fn after_phase<T: Debug + Send + Sync + 'static>(phase: T) -> Self {
+        FlushPoint::AfterPhase(PhaseKey {
+            name: format!("{:?}", phase),
+            phase: Arc::new(phase),
+        })
+    }
+}
+
+/// [`FlushPoint::AfterPhase`]で指定されたPhase。
+/// Commandsはランタイムのphaseの型を知らないので、型を消して保持する。
+#[derive(Clone)]
+pub struct PhaseKey {
+    name: String,
+    phase: Arc<dyn Any + Send + Sync>,
+}
+impl PhaseKey {
+    /// 指定したPhaseと同じPhaseかどうかを返す関数。
+    pub fn is<T: PartialEq + 'static>(&self, phase: &T) -> bool {
+        self.phase.downcast_ref::<T>() == Some(phase)
+    }
+}
+impl Debug for PhaseKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+/// コマンドを実際に適用した時点。
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "replay", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum AppliedAt<T> {
+    /// フレームの最初のPhaseの前。
+    FrameStart,
+    /// Phaseの後。
+    AfterPhase(T),
+    /// フレームのすべてのPhaseの後。
+    FrameEnd,
+}
+impl<T> AppliedAt<T> {
+    /// コマンドを適用したPhaseを返す。フレームの開始時と終わりはNoneになる。
+    pub(crate) fn phase(&self) -> Option<&T> {
+        match self {
+            AppliedAt::AfterPhase(phase) => Some(phase),
+            AppliedAt::FrameStart | AppliedAt::FrameEnd => None,
+        }
+    }
+}
+
+type Buffer<C> = Arc<Mutex<Vec<(FlushPoint, C)>>>;
+
+/// ランタイムに登録されたコマンドバッファ。
+/// バッファは作成された順に並んでいる。
+pub(crate) struct CommandRegistry<C> {
+    // ランタイムのWorldの識別子
+    world: usize,
+    next_seq: AtomicU64,
+    buffers: Mutex<BTreeMap<u64, Buffer<C>>>,
+    // タスクのpoll中に作られたバッファ。
+    // ワーカースレッドでは作られる順番が決まらないので、作ったタスクの順に並べてから登録する
+    pending: Mutex<Vec<(ChildKey, Buffer<C>)>>,
+}
+impl<C> CommandRegistry<C> {
+    pub(crate) fn new(world: usize) -> Self {
+        Self {
+            world,
+            next_seq: AtomicU64::new(0),
+            buffers: Mutex::new(BTreeMap::new()),
+            pending: Mutex::new(vec![]),
+        }
+    }
+
+    fn register(&self) -> Buffer<C> {
+        let buffer = Arc::new(Mutex::new(vec![]));
+        match task::next_child(self.world) {
+            Some(key) => self
+                .pending
+                .lock()
+                .unwrap()
+                .push((key, Arc::clone(&buffer))),
+            None => {
+                let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+                self.buffers
+                    .lock()
+                    .unwrap()
+                    .insert(seq, Arc::clone(&buffer));
+            }
+        }
+        buffer
+    }
+
+    /// すべてのバッファから、適用するタイミングがfilterに合うコマンドを取り出す。
+    /// コマンドはバッファが作成された順、バッファの中では送信された順に並ぶ。
+    pub(crate) fn drain(&self, mut filter: impl FnMut(&FlushPoint) -> bool) -> Vec<C> {
+        let mut buffers = self.buffers.lock().unwrap();
+        let mut pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        pending.sort_by_key(|(key, _)| *key);
+        for (_, buffer) in pending {
+            let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+            buffers.insert(seq, buffer);
+        }
+
+        let mut commands = vec![];
+        for buffer in buffers.values() {
+            let mut buffer = buffer.lock().unwrap();
+            let mut rest = vec![];
+            for (target, cmd) in buffer.drain(..) {
+                if filter(&target) {
+                    commands.push(cmd);
+                } else {
+                    rest.push((target, cmd));
+                }
+            }
+            *buffer = rest;
+        }
+
+        // Commandsがすべてdropされ、コマンドも残っていないバッファにはもうコマンドが送られない
+        buffers.retain(|_seq, buffer| {
+            Arc::strong_count(buffer) > 1 || !buffer.lock().unwrap().is_empty()
+        });
+        commands
+    }
+}
+
+/// Worldへのコマンドを送るためのバッファ。
+///
+/// システムごとに別のバッファを持ち、コマンドは[`FlushPoint`]で指定したタイミングで
+/// システムが登録された順、バッファの中では送信された順に[`World::process_command`]へ渡される。
+/// そのため、タスクがどのワーカースレッドで実行されてもコマンドの適用順は変わらない。
+///
+/// cloneすると新しいバッファが作られ、それまでに作られたすべてのバッファの後ろに並ぶ。
+/// タスクのpoll中に作られたバッファは、作ったタスクが起動された順に並ぶ。
+pub struct Commands<W: World> {
+    buffer: Buffer<W::Command>,
+    registry: Arc<CommandRegistry<W::Command>>,
+    flush_point: FlushPoint,
+}
+impl<W: World> Commands<W> {
+    pub(crate) fn new(registry: &Arc<CommandRegistry<W::Command>>) -> Self {
+        Self::with_target(registry, FlushPoint::NextSyncPoint)
+    }
+
+    fn with_target(registry: &Arc<CommandRegistry<W::Command>>, flush_point: FlushPoint) -> Self {
+        Self {
+            buffer: registry.register(),
+            registry: Arc::clone(registry),
+            flush_point,
+        }
+    }
+
+    /// コマンドをバッファに積む関数。
+    /// コマンドはこのCommandsのFlushPointで適用される。
+    pub fn send(&self, cmd: W::Command) {
+        let target = self.flush_point.clone();
+        self.buffer.lock().unwrap().push((target, cmd));
+    }
+
+    /// 適用するタイミングを指定してコマンドをバッファに積む関数。
+    pub fn send_at(&self, cmd: W::Command, point: FlushPoint) {
+        self.buffer.lock().unwrap().push((point, cmd));
+    }
+
+    /// 送信したコマンドを指定したタイミングで適用するCommandsを返す関数。
+    ///
+    /// 返り値は新しいバッファを持ち、cloneと同じくそれまでに作られたすべてのバッファの後ろに並ぶ。
+    pub fn flush_at(&self, point: FlushPoint) -> Self {
+        Self::with_target(&self.registry, point)
+    }
+}
+impl<W: World> Clone for Commands<W> {
+    fn clone(&self) -> Self {
+        Self::with_target(&self.registry, self.flush_point.clone())
+    }
+}
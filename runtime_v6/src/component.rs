@@ -0,0 +1,236 @@
+use std::any::{type_name, Any, TypeId};
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use crate::abort_handle::TaskId;
+use crate::container::{Container, Read, Write};
+
+/// ランタイムに登録されたコンポーネント。
+/// 各コンポーネントはWorldと状態を共有するContainerに入っている。
+// SystemParamsのメソッドから参照されるためpubにしているが、クレートの外からは名前を付けられない。
+pub struct Components {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+impl Components {
+    pub(crate) fn new() -> Self {
+        Self {
+            map: HashMap::new(),
+        }
+    }
+
+    /// コンポーネントを登録する。
+    /// 既に登録されている場合は値を置き換える。
+    pub(crate) fn insert<C, W>(&mut self, component: C, world: &Container<W>)
+    where
+        C: Send + Sync + 'static,
+    {
+        match self.get::<C>() {
+            Some(container) => *container.apply() = component,
+            None => {
+                let container = Arc::new(Container::with_state_of(component, world));
+                self.map.insert(TypeId::of::<C>(), Box::new(container));
+            }
+        }
+    }
+
+    pub(crate) fn get<C: Send + Sync + 'static>(&self) -> Option<Arc<Container<C>>> {
+        self.map
+            .get(&TypeId::of::<C>())
+            .and_then(|c| c.downcast_ref::<Arc<Container<C>>>())
+            .cloned()
+    }
+}
+
+/// システムが宣言したコンポーネントへのアクセス。
+#[derive(Clone, Copy)]
+pub struct Access {
+    type_id: TypeId,
+    name: &'static str,
+    write: bool,
+}
+impl Access {
+    fn conflicts_with(&self, other: &Access) -> bool {
+        self.type_id == other.type_id && (self.write || other.write)
+    }
+}
+
+/// システムを登録できなかったことを表すエラー。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SystemAccessError<T> {
+    /// 登録されていないコンポーネントにアクセスしようとした。
+    MissingComponent(&'static str),
+    /// 登録されていないリソースを受け取ろうとした。
+    MissingResource(&'static str),
+    /// 同じPhaseの他のシステムや、同じシステムの他の引数とアクセスが衝突した。
+    Conflict { phase: T, component: &'static str },
+}
+impl<T: Debug> fmt::Display for SystemAccessError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SystemAccessError::MissingComponent(component) => {
+                write!(f, "component is not registered: {}", component)
+            }
+            SystemAccessError::MissingResource(resource) => {
+                write!(f, "resource is not registered: {}", resource)
+            }
+            SystemAccessError::Conflict { phase, component } => write!(
+                f,
+                "conflicting access to {} in PHASE {:?}",
+                component, phase
+            ),
+        }
+    }
+}
+impl<T: Debug> std::error::Error for SystemAccessError<T> {}
+
+/// Phaseごとに、実行中のシステムが宣言しているアクセス。
+pub(crate) struct AccessTable<T> {
+    declarations: HashMap<TaskId, (T, Vec<Access>)>,
+}
+impl<T: Eq + Hash + Clone> AccessTable<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            declarations: HashMap::new(),
+        }
+    }
+
+    /// システムのアクセスを宣言する。
+    /// 同じPhaseで衝突するアクセスが宣言されている場合はエラーを返し、何も変更しない。
+    pub(crate) fn declare(
+        &mut self,
+        phase: &T,
+        id: TaskId,
+        accesses: Vec<Access>,
+    ) -> Result<(), SystemAccessError<T>> {
+        let declared = self
+            .declarations
+            .values()
+            .filter(|(p, _)| p == phase)
+            .flat_map(|(_, accesses)| accesses.iter());
+
+        for (i, access) in accesses.iter().enumerate() {
+            let conflict = accesses[..i]
+                .iter()
+                .chain(declared.clone())
+                .any(|other| access.conflicts_with(other));
+            if conflict {
+                return Err(SystemAccessError::Conflict {
+                    phase: phase.clone(),
+                    component: access.name,
+                });
+            }
+        }
+
+        self.declarations.insert(id, (phase.clone(), accesses));
+        Ok(())
+    }
+
+    fn release(&mut self, id: TaskId) {
+        self.declarations.remove(&id);
+    }
+}
+
+/// システムのタスクが終了またはキャンセルされたときに、宣言したアクセスを解放する。
+pub(crate) struct AccessGuard<T: Eq + Hash + Clone> {
+    table: Arc<Mutex<AccessTable<T>>>,
+    id: TaskId,
+}
+impl<T: Eq + Hash + Clone> AccessGuard<T> {
+    pub(crate) fn new(table: Arc<Mutex<AccessTable<T>>>, id: TaskId) -> Self {
+        Self { table, id }
+    }
+}
+impl<T: Eq + Hash + Clone> Drop for AccessGuard<T> {
+    fn drop(&mut self) {
+        self.table.lock().unwrap().release(self.id);
+    }
+}
+
+pub(crate) mod private {
+    use super::{Access, Components, TaskId};
+
+    pub trait Fetch: Sized {
+        // 引数が行うアクセスをaccessに追加する。
+        fn declare(access: &mut Vec<Access>);
+        // コンポーネントから、ownerのタスクの中でだけ参照できる引数を作る。
+        // 登録されていないコンポーネントの名前をエラーとして返す。
+        fn fetch(components: &Components, owner: TaskId) -> Result<Self, &'static str>;
+    }
+}
+
+/// コンポーネントへのアクセスを宣言するシステムの引数。
+///
+/// コンポーネントの[`Read`]と[`Write`]、それらのタプルに実装されている。
+pub trait SystemParams: private::Fetch + Send + 'static {}
+impl<P: private::Fetch + Send + 'static> SystemParams for P {}
+
+impl<C: Send + Sync + 'static> private::Fetch for Read<C> {
+    fn declare(access: &mut Vec<Access>) {
+        access.push(Access {
+            type_id: TypeId::of::<C>(),
+            name: type_name::<C>(),
+            write: false,
+        });
+    }
+
+    fn fetch(components: &Components, owner: TaskId) -> Result<Self, &'static str> {
+        components
+            .get::<C>()
+            .map(|c| c.read_in(owner))
+            .ok_or_else(type_name::<C>)
+    }
+}
+
+impl<C: Send + Sync + 'static> private::Fetch for Write<C> {
+    fn declare(access: &mut Vec<Access>) {
+        access.push(Access {
+            type_id: TypeId::of::<C>(),
+            name: type_name::<C>(),
+            write: true,
+        });
+    }
+
+    fn fetch(components: &Components, owner: TaskId) -> Result<Self, &'static str> {
+        components
+            .get::<C>()
+            .map(|c| c.write_in(owner))
+            .ok_or_else(type_name::<C>)
+    }
+}
+
+macro_rules! impl_fetch_for_tuple {
+    ($($p:ident),+) => {
+        impl<$($p: private::Fetch),+> private::Fetch for ($($p,)+) {
+            fn declare(access: &mut Vec<Access>) {
+                $($p::declare(access);)+
+            }
+
+            fn fetch(components: &Components, owner: TaskId) -> Result<Self, &'static str> {
+                Ok(($($p::fetch(components, owner)?,)+))
+            }
+        }
+    };
+}
+impl_fetch_for_tuple!(P1);
+impl_fetch_for_tuple!(P1, P2);
+impl_fetch_for_tuple!(P1, P2, P3);
+impl_fetch_for_tuple!(P1, P2, P3, P4);
+impl_fetch_for_tuple!(P1, P2, P3, P4, P5);
+impl_fetch_for_tuple!(P1, P2, P3, P4, P5, P6);
+
+/// システムの引数が行うアクセスを返す。
+pub(crate) fn accesses<P: SystemParams>() -> Vec<Access> {
+    let mut access = vec![];
+    P::declare(&mut access);
+    access
+}
+
+/// システムの引数をコンポーネントから作る。引数はownerのタスクの中でだけ参照できる。
+pub(crate) fn fetch<P: SystemParams>(
+    components: &Components,
+    owner: TaskId,
+) -> Result<P, &'static str> {
+    P::fetch(components, owner)
+}
@@ -1,62 +1,276 @@
 use std::{
     cell::UnsafeCell,
     ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex,
+    },
 };
 
+use crate::abort_handle::TaskId;
+use crate::task;
+
+// Containerの状態。
+// ReadとWriteの参照はPOLLINGの間だけ、Applyingの参照はAPPLYINGの間だけ作ることができる。
+// 1つのランタイムのWorldとコンポーネントは状態を共有する。
+const IDLE: u8 = 0;
+const POLLING: u8 = 1;
+const APPLYING: u8 = 2;
+
+/// Worldやコンポーネントへの読み取り専用のアクセス。
+///
+/// [`Read::with`]に渡した関数の中でだけ参照できる。
+/// 参照できるのはランタイムがこのWorldのタスクをpollしている間だけで、
+/// コマンドの適用中やタスクの外で参照した場合はpanicする。
+/// コンポーネントへのアクセスは、アクセスを宣言したシステムのタスクの中でだけ参照できる。
 pub struct Read<T: ?Sized + 'static> {
-    value: &'static T,
+    container: Arc<Container<T>>,
+    // アクセスを宣言したシステムのタスク。Worldへのアクセスの場合はNone
+    owner: Option<TaskId>,
 }
-impl<T: ?Sized + 'static> Deref for Read<T> {
-    type Target = T;
-
-    fn deref(&self) -> &Self::Target {
-        self.value
+impl<T: ?Sized + 'static> Read<T> {
+    /// 値への参照をfに渡して、fの結果を返す関数。
+    ///
+    /// 参照はfの外に持ち出せないので、`.await`やコマンドの適用をまたいで保持されることはない。
+    ///
+    /// ```compile_fail
+    /// # use runtime_v6::Read;
+    /// fn leak(world: &Read<Vec<i32>>) -> &Vec<i32> {
+    ///     world.with(|world| world)
+    /// }
+    /// ```
+    ///
+    /// ## panic
+    /// ランタイムがこのWorldのタスクをpollしている間以外に呼び出した場合、panicする。
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        self.container.check_access(self.owner);
+        f(unsafe { &*self.container.data.get() })
     }
 }
-impl<T: ?Sized + 'static> Copy for Read<T> {}
 impl<T: ?Sized + 'static> Clone for Read<T> {
     fn clone(&self) -> Self {
-        Self { value: self.value }
+        Self {
+            container: Arc::clone(&self.container),
+            owner: self.owner,
+        }
     }
 }
 
+/// コンポーネントへの書き込みアクセス。
+///
+/// [`Read`]と同じく、アクセスを宣言したシステムのタスクをpollしている間だけ、
+/// [`Write::with`]や[`Write::with_mut`]に渡した関数の中で参照できる。
+/// 同じPhaseの他のシステムが同じコンポーネントにアクセスしないことは、
+/// システムの登録時にランタイムが確認する。
+/// 他のタスクに渡した場合は、そのタスクがどのPhaseで実行されても参照できない。
 pub struct Write<T: ?Sized + 'static> {
-    value: &'static mut T,
+    container: Arc<Container<T>>,
+    owner: TaskId,
 }
-impl<T: ?Sized + 'static> Deref for Write<T> {
+impl<T: ?Sized + 'static> Write<T> {
+    /// 値への参照をfに渡して、fの結果を返す関数。
+    ///
+    /// ## panic
+    /// アクセスを宣言したシステムのタスクをpollしている間以外に呼び出した場合、panicする。
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        self.container.check_access(Some(self.owner));
+        f(unsafe { &*self.container.data.get() })
+    }
+
+    /// 値への可変参照をfに渡して、fの結果を返す関数。
+    ///
+    /// ## panic
+    /// アクセスを宣言したシステムのタスクをpollしている間以外に呼び出した場合、panicする。
+    pub fn with_mut<R>(&mut self, f: impl FnOnce(&mut T) -> R) -> R {
+        self.container.check_access(Some(self.owner));
+        f(unsafe { &mut *self.container.data.get() })
+    }
+}
+
+/// コマンドを適用するための書き込みアクセス。
+/// 存在する間はReadとWriteを参照できない。
+pub struct Applying<'a, T: ?Sized + 'static> {
+    container: &'a Container<T>,
+}
+impl<T: ?Sized + 'static> Deref for Applying<'_, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        self.value
+        unsafe { &*self.container.data.get() }
     }
 }
-impl<T: ?Sized + 'static> DerefMut for Write<T> {
+impl<T: ?Sized + 'static> DerefMut for Applying<'_, T> {
     fn deref_mut(&mut self) -> &mut T {
-        self.value
+        unsafe { &mut *self.container.data.get() }
+    }
+}
+impl<T: ?Sized + 'static> Drop for Applying<'_, T> {
+    fn drop(&mut self) {
+        self.container.state.store(IDLE, Ordering::Release);
+    }
+}
+
+/// [`Container::poll`]が返す、タスクをpollしている間を表すガード。
+/// dropされるとContainerは待機状態に戻る。
+pub struct Polling<'a, T: ?Sized + 'static> {
+    container: &'a Container<T>,
+}
+impl<T: ?Sized + 'static> Drop for Polling<'_, T> {
+    fn drop(&mut self) {
+        self.container.state.store(IDLE, Ordering::Release);
+    }
+}
+
+/// [`Container::inspect`]が返す、フレームの間の読み取り専用のアクセス。
+/// 存在する間はタスクのpollとコマンドの適用ができない。
+pub struct Inspecting<'a, T: ?Sized + 'static> {
+    container: &'a Container<T>,
+}
+impl<T: ?Sized + 'static> Deref for Inspecting<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.container.data.get() }
+    }
+}
+impl<T: ?Sized + 'static> Drop for Inspecting<'_, T> {
+    fn drop(&mut self) {
+        let mut inspectors = self.container.inspectors.lock().unwrap();
+        *inspectors -= 1;
+        if *inspectors == 0 {
+            self.container.state.store(IDLE, Ordering::Release);
+        }
     }
 }
 
 pub struct Container<T: ?Sized> {
+    state: Arc<AtomicU8>,
+    // 存在するInspectingの数
+    inspectors: Mutex<usize>,
     data: UnsafeCell<T>,
 }
 impl<T: Sized> Container<T> {
     pub fn new(data: T) -> Self {
         Self {
+            state: Arc::new(AtomicU8::new(IDLE)),
+            inspectors: Mutex::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// 他のContainerと状態を共有するContainerを作成する関数。
+    pub fn with_state_of<U: ?Sized>(data: T, other: &Container<U>) -> Self {
+        Self {
+            state: Arc::clone(&other.state),
+            inspectors: Mutex::new(0),
             data: UnsafeCell::new(data),
         }
     }
 }
 impl<T: ?Sized> Container<T> {
-    pub unsafe fn read(&self) -> Read<T> {
+    /// Worldへの読み取りアクセスを返す関数。
+    /// このランタイムのどのタスクからでも参照できる。
+    pub fn read(self: &Arc<Self>) -> Read<T> {
+        Read {
+            container: Arc::clone(self),
+            owner: None,
+        }
+    }
+
+    /// ownerのタスクの中でだけ参照できる、コンポーネントへの読み取りアクセスを返す関数。
+    pub fn read_in(self: &Arc<Self>, owner: TaskId) -> Read<T> {
         Read {
-            value: &*self.data.get(),
+            container: Arc::clone(self),
+            owner: Some(owner),
         }
     }
 
-    pub unsafe fn write(&self) -> Write<T> {
+    /// ownerのタスクの中でだけ参照できる、コンポーネントへの書き込みアクセスを返す関数。
+    ///
+    /// 同時に存在するReadやWriteと同じタイミングで参照されないことは、
+    /// 呼び出し側がownerのアクセスを宣言して保証する。
+    pub fn write_in(self: &Arc<Self>, owner: TaskId) -> Write<T> {
         Write {
-            value: &mut *self.data.get(),
+            container: Arc::clone(self),
+            owner,
+        }
+    }
+
+    /// タスクのpollを始める関数。
+    /// 返り値のガードが存在する間はReadを参照できる。
+    ///
+    /// ## panic
+    /// 他のpollやコマンドの適用の途中で呼び出した場合、panicする。
+    pub fn poll(&self) -> Polling<'_, T> {
+        self.transition(POLLING);
+        Polling { container: self }
+    }
+
+    /// コマンドの適用を始める関数。
+    /// 返り値のApplyingが存在する間はReadとWriteを参照できない。
+    ///
+    /// ## panic
+    /// タスクのpollやコマンドの適用の途中で呼び出した場合、panicする。
+    pub fn apply(&self) -> Applying<'_, T> {
+        self.transition(APPLYING);
+        Applying { container: self }
+    }
+
+    /// フレームの間にデータを参照する関数。
+    /// 返り値のInspectingは同時に複数存在できる。
+    ///
+    /// ## panic
+    /// タスクのpollやコマンドの適用の途中で呼び出した場合、panicする。
+    pub fn inspect(&self) -> Inspecting<'_, T> {
+        let mut inspectors = self.inspectors.lock().unwrap();
+        if *inspectors == 0 {
+            self.transition(APPLYING);
+        }
+        *inspectors += 1;
+        Inspecting { container: self }
+    }
+
+    /// 状態を共有するContainerに共通の識別子。
+    pub fn id(&self) -> usize {
+        Arc::as_ptr(&self.state) as usize
+    }
+
+    fn check_polling(&self) {
+        match self.state.load(Ordering::Acquire) {
+            POLLING => (),
+            APPLYING => panic!("world cannot be read while commands are being applied"),
+            _ => panic!("world can only be read while the runtime is polling tasks"),
+        }
+    }
+
+    // このスレッドでこのランタイムのタスクをpollしている途中であることを確認する。
+    // 他のスレッドでは参照している間にpollが終わってコマンドが適用されるかもしれない。
+    // ownerが指定されている場合は、アクセスを宣言したタスクであることも確認する。
+    // 他のタスクは衝突するアクセスを宣言したシステムと並列に実行されるかもしれない。
+    fn check_access(&self, owner: Option<TaskId>) {
+        self.check_polling();
+        match task::current_task(self.id()) {
+            None => panic!("world can only be read from a task polled by its runtime"),
+            Some(current) if owner.is_some_and(|owner| owner != current) => {
+                panic!("component can only be accessed from the system that declared the access")
+            }
+            Some(_) => (),
+        }
+    }
+
+    fn transition(&self, next: u8) {
+        if let Err(current) =
+            self.state
+                .compare_exchange(IDLE, next, Ordering::AcqRel, Ordering::Acquire)
+        {
+            let current = match current {
+                POLLING => "tasks are being polled",
+                _ => "commands are being applied",
+            };
+            panic!("world access conflict: {}", current);
         }
     }
 }
-unsafe impl<T: Sized> Sync for Container<T> {}
+// Readの参照とWriteの参照が同時に存在しないことは状態によって保証される。
+// 参照はタスクのpollの中でだけ作られ、pollの外に持ち出せない。
+unsafe impl<T: ?Sized + Send + Sync> Sync for Container<T> {}
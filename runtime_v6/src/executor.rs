@@ -0,0 +1,327 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::abort_handle::TaskId;
+#[cfg(feature = "profiler")]
+use crate::profiler::SpanKind;
+use crate::task::{self, poll_task, FrameContext, PanicPayload, PollOutcome, Task, WakeQueue};
+
+/// Phaseのwakeされるまで実行しないタスク。
+/// Phaseの実行中はワーカーと共有し、wakeされたタスクだけを取り出す。
+pub(crate) type ParkedTasks = Arc<Mutex<HashMap<TaskId, Task>>>;
+
+/// 1つのPhaseのタスクをすべてpollした結果。
+/// wakeされるまで実行しないタスクは、[`Executor::run`]に渡したParkedTasksに入る。
+pub(crate) struct ProcessedTasks {
+    /// next_frame()で次のフレームの実行を予約したタスク。
+    pub(crate) next_frame: Vec<Task>,
+    /// poll中にpanicしたタスクと、そのペイロード。
+    pub(crate) panicked: Vec<(Task, PanicPayload)>,
+}
+
+// 1つのPhaseの実行でワーカー間で共有する状態。
+pub(crate) struct PhaseJob {
+    // ワーカーごとのキュー。
+    // 自分のキューは先頭から取り出し、他のワーカーのキューは末尾から盗む。
+    queues: Vec<Mutex<VecDeque<Task>>>,
+    // まだ結果の決まっていないタスクの数。0になるとPhaseが終わる。
+    remaining: AtomicUsize,
+    // 手の空いたワーカーは、タスクがキューに戻るかPhaseが終わるまでここで待つ
+    idle: Mutex<()>,
+    wakeup: Condvar,
+    next_frame: Mutex<Vec<Task>>,
+    parked: ParkedTasks,
+    panicked: Mutex<Vec<(Task, PanicPayload)>>,
+    wake_queue: WakeQueue,
+    frame: FrameContext,
+}
+impl PhaseJob {
+    fn new(
+        tasks: Vec<Task>,
+        parked: ParkedTasks,
+        workers: usize,
+        frame: FrameContext,
+        wake_queue: WakeQueue,
+    ) -> Self {
+        let remaining = AtomicUsize::new(tasks.len());
+
+        // タスクを各ワーカーのキューに順番に配る
+        let mut queues = (0..workers).map(|_| VecDeque::new()).collect::<Vec<_>>();
+        for (i, task) in tasks.into_iter().enumerate() {
+            queues[i % workers].push_back(task);
+        }
+
+        Self {
+            queues: queues.into_iter().map(Mutex::new).collect(),
+            remaining,
+            idle: Mutex::new(()),
+            wakeup: Condvar::new(),
+            next_frame: Mutex::new(vec![]),
+            parked,
+            panicked: Mutex::new(vec![]),
+            wake_queue,
+            frame,
+        }
+    }
+
+    // 自分のキュー、他のワーカーのキューの順にタスクを探す。
+    fn find_task(&self, index: usize) -> Option<Task> {
+        if let Some(task) = self.queues[index].lock().unwrap().pop_front() {
+            return Some(task);
+        }
+
+        let len = self.queues.len();
+        (1..len)
+            .map(|offset| (index + offset) % len)
+            .find_map(|i| self.queues[i].lock().unwrap().pop_back())
+    }
+
+    fn has_queued_tasks(&self) -> bool {
+        self.queues.iter().any(|q| !q.lock().unwrap().is_empty())
+    }
+
+    // 待っているワーカーを起こす。
+    // 待つ前の確認と入れ違いにならないように、idleのロックを取ってから通知する。
+    fn notify_idle_workers(&self) {
+        let _idle = self.idle.lock().unwrap();
+        self.wakeup.notify_all();
+    }
+
+    // タスクの結果が決まったことを記録する。最後のタスクなら待っているワーカーを起こす。
+    fn finish_task(&self) {
+        if self.remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.notify_idle_workers();
+        }
+    }
+
+    // このPhaseでparkされたタスクのうち、Phaseの実行中にwakeされたものを自分のキューに戻す。
+    fn unpark_woken_tasks(&self, index: usize) -> bool {
+        let mut wake_queue = self.wake_queue.lock().unwrap();
+        if wake_queue.is_empty() {
+            return false;
+        }
+
+        let mut parked = self.parked.lock().unwrap();
+        let mut queue = self.queues[index].lock().unwrap();
+        let mut unparked = false;
+        wake_queue.retain(|id| match parked.remove(id) {
+            Some(task) => {
+                self.remaining.fetch_add(1, Ordering::AcqRel);
+                queue.push_back(task);
+                unparked = true;
+                false
+            }
+            // 他のPhaseのタスクはPhaseの境界でランタイムが処理する
+            None => true,
+        });
+        unparked
+    }
+
+    fn run_worker(&self, index: usize) {
+        task::enter(self.frame.clone());
+
+        loop {
+            match self.find_task(index) {
+                Some(t) => match self.poll(t) {
+                    PollOutcome::Ready => self.finish_task(),
+                    PollOutcome::NextFrame(t) => {
+                        self.next_frame.lock().unwrap().push(t);
+                        self.finish_task();
+                    }
+                    PollOutcome::Parked(t) => {
+                        self.parked.lock().unwrap().insert(t.id, t);
+                        self.finish_task();
+                    }
+                    // 自分のキューに戻すので、手の空いたワーカーが盗んでいける
+                    PollOutcome::Woken(t) => {
+                        self.queues[index].lock().unwrap().push_back(t);
+                        self.notify_idle_workers();
+                    }
+                    // panicしたタスクの後始末はランタイムがPhaseの後で行う
+                    PollOutcome::Panicked(t, payload) => {
+                        self.panicked.lock().unwrap().push((t, payload));
+                        self.finish_task();
+                    }
+                },
+                None => {
+                    if self.unpark_woken_tasks(index) {
+                        self.notify_idle_workers();
+                        continue;
+                    }
+                    let idle = self.idle.lock().unwrap();
+                    if self.remaining.load(Ordering::Acquire) == 0 {
+                        break;
+                    }
+                    if self.has_queued_tasks() {
+                        continue;
+                    }
+                    // 他のワーカーがpoll中のタスクがキューに戻るか、Phaseが終わるまで待つ。
+                    // poll中にwakeされたタスクは、pollを終えたワーカーがキューに戻す
+                    drop(self.wakeup.wait(idle).unwrap());
+                }
+            }
+        }
+
+        task::exit();
+    }
+
+    fn poll(&self, task: Task) -> PollOutcome {
+        #[cfg(feature = "profiler")]
+        let _span = self
+            .frame
+            .profiler
+            .span(SpanKind::TaskPoll, self.frame.frame_counter, || {
+                task.name
+                    .clone()
+                    .unwrap_or_else(|| format!("task {}", task.id))
+            });
+        // タスク内のイベントがどのタスクのどのフレームのものか分かるようにする
+        #[cfg(feature = "tracing")]
+        let _entered = tracing::info_span!(
+            parent: &task.span,
+            "poll",
+            frame = self.frame.frame_counter
+        )
+        .entered();
+        poll_task(task)
+    }
+
+    fn into_processed(self) -> ProcessedTasks {
+        ProcessedTasks {
+            next_frame: self.next_frame.into_inner().unwrap(),
+            panicked: self.panicked.into_inner().unwrap(),
+        }
+    }
+}
+
+pub(crate) struct Worker {
+    sender: Option<Sender<(Arc<PhaseJob>, usize)>>,
+    receiver: Receiver<()>,
+    handle: Option<JoinHandle<()>>,
+}
+
+/// Phaseのタスクを実行する実行器。
+pub(crate) enum Executor {
+    /// 呼び出し元のスレッドでタスクを実行する。
+    CurrentThread,
+    /// ワーカースレッドにタスクを分配して実行する。
+    /// 同じPhaseの中では手の空いたワーカーが他のワーカーのタスクを盗む。
+    Workers(Vec<Worker>),
+}
+impl Executor {
+    pub(crate) fn current_thread() -> Self {
+        Executor::CurrentThread
+    }
+
+    pub(crate) fn workers(
+        worker_threads: usize,
+        thread_name: &str,
+        thread_stack_size: Option<usize>,
+    ) -> io::Result<Self> {
+        let mut workers = vec![];
+        for i in 0..worker_threads {
+            let (main_sender, thread_receiver) = channel::<(Arc<PhaseJob>, usize)>();
+            let (thread_sender, main_receiver) = channel();
+
+            let mut builder = thread::Builder::new().name(format!("{}-{}", thread_name, i));
+            if let Some(size) = thread_stack_size {
+                builder = builder.stack_size(size);
+            }
+
+            // メインスレッド側のSenderがdropされるとループを抜けて終了する
+            let handle = builder.spawn(move || {
+                for (job, index) in thread_receiver.iter() {
+                    job.run_worker(index);
+                    // メインスレッドがjobを取り出せるように、応答する前に参照を手放す
+                    drop(job);
+                    if thread_sender.send(()).is_err() {
+                        break;
+                    }
+                }
+            })?;
+
+            workers.push(Worker {
+                sender: Some(main_sender),
+                receiver: main_receiver,
+                handle: Some(handle),
+            });
+        }
+        Ok(Executor::Workers(workers))
+    }
+
+    /// タスクをすべてpollして、その結果を返す。
+    ///
+    /// parkedにはPhaseのparkされているタスクを渡す。
+    /// Phaseの実行中にwakeされたタスクは取り出されて同じPhaseの中でpollされ、
+    /// このPhaseでparkされたタスクはparkedに追加される。
+    pub(crate) fn run(
+        &self,
+        tasks: Vec<Task>,
+        parked: &ParkedTasks,
+        frame: FrameContext,
+        wake_queue: &WakeQueue,
+    ) -> ProcessedTasks {
+        match self {
+            Executor::CurrentThread => {
+                let job =
+                    PhaseJob::new(tasks, Arc::clone(parked), 1, frame, Arc::clone(wake_queue));
+                job.run_worker(0);
+                job.into_processed()
+            }
+            Executor::Workers(workers) => {
+                let job = Arc::new(PhaseJob::new(
+                    tasks,
+                    Arc::clone(parked),
+                    workers.len(),
+                    frame,
+                    Arc::clone(wake_queue),
+                ));
+
+                for (index, worker) in workers.iter().enumerate() {
+                    worker
+                        .sender
+                        .as_ref()
+                        .unwrap()
+                        .send((Arc::clone(&job), index))
+                        .unwrap();
+                }
+
+                // すべてのワーカーの終了を待つ
+                {
+                    #[cfg(feature = "profiler")]
+                    let _span = job.frame.profiler.span(
+                        SpanKind::WorkerWait,
+                        job.frame.frame_counter,
+                        || "wait workers".to_string(),
+                    );
+                    for worker in workers.iter() {
+                        worker.receiver.recv().unwrap();
+                    }
+                }
+
+                match Arc::try_unwrap(job) {
+                    Ok(job) => job.into_processed(),
+                    Err(_) => unreachable!("all workers have released the phase job"),
+                }
+            }
+        }
+    }
+}
+impl Drop for Executor {
+    fn drop(&mut self) {
+        if let Executor::Workers(workers) = self {
+            for worker in workers.iter_mut() {
+                worker.sender.take();
+            }
+            for worker in workers.iter_mut() {
+                if let Some(handle) = worker.handle.take() {
+                    let _ = handle.join();
+                }
+            }
+        }
+    }
+}
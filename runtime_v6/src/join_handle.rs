@@ -0,0 +1,210 @@
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, TryLockError};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use crate::abort_handle::{AbortHandle, TaskId};
+use crate::task::ReportPanic;
+
+/// タスクが正常に終了しなかった理由を表すエラー。
+pub enum JoinError {
+    /// タスクがpanicした。panicのメッセージをペイロードとして保持する。
+    /// 元のペイロードは[`TaskPanicked`]に渡される。
+    ///
+    /// [`TaskPanicked`]: crate::TaskPanicked
+    Panicked(Box<dyn Any + Send + 'static>),
+    /// タスクが完了する前に破棄された。
+    Cancelled,
+}
+impl JoinError {
+    /// タスクがpanicしたかどうかを返す関数。
+    pub fn is_panic(&self) -> bool {
+        matches!(self, JoinError::Panicked(_))
+    }
+
+    /// タスクがキャンセルされたかどうかを返す関数。
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, JoinError::Cancelled)
+    }
+
+    /// panicのペイロードを取り出す関数。
+    ///
+    /// ## panic
+    /// タスクがpanicしていない場合、panicする。
+    pub fn into_panic(self) -> Box<dyn Any + Send + 'static> {
+        match self {
+            JoinError::Panicked(payload) => payload,
+            JoinError::Cancelled => panic!("`into_panic` called on a cancelled task's JoinError"),
+        }
+    }
+}
+impl fmt::Debug for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Panicked(_) => write!(f, "JoinError::Panicked(..)"),
+            JoinError::Cancelled => write!(f, "JoinError::Cancelled"),
+        }
+    }
+}
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Panicked(_) => write!(f, "task panicked"),
+            JoinError::Cancelled => write!(f, "task was cancelled"),
+        }
+    }
+}
+impl std::error::Error for JoinError {}
+
+struct JoinState<T> {
+    output: Option<Result<T, JoinError>>,
+    completed: bool,
+    waker: Option<Waker>,
+}
+
+fn complete<T>(state: &Mutex<JoinState<T>>, output: Result<T, JoinError>) {
+    let waker = set_output(&mut state.lock().unwrap(), output);
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+// 結果を書き込んで、起こすべきwakerを返す。
+fn set_output<T>(state: &mut JoinState<T>, output: Result<T, JoinError>) -> Option<Waker> {
+    if state.completed {
+        // タスクのpanicで巻き戻されている間にCancelledになった場合は、
+        // pollした側が後から報告するpanicで上書きする
+        let cancelled = matches!(state.output, Some(Err(JoinError::Cancelled)));
+        if !cancelled || !matches!(output, Err(JoinError::Panicked(_))) {
+            return None;
+        }
+    }
+    state.completed = true;
+    state.output = Some(output);
+    state.waker.take()
+}
+
+// タスクのFutureが完了前にdropされたときにCancelledを書き込むためのガード。
+struct CancelOnDrop<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+impl<T> Drop for CancelOnDrop<T> {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            complete(&self.state, Err(JoinError::Cancelled));
+            return;
+        }
+
+        // 巻き戻し中にもう一度panicするとabortするので、lockをunwrapしない。
+        // 毒されたロックは中身を使い、保持されていて取れないロックは諦める
+        let waker = match self.state.try_lock() {
+            Ok(mut state) => set_output(&mut state, Err(JoinError::Cancelled)),
+            Err(TryLockError::Poisoned(poisoned)) => {
+                set_output(&mut poisoned.into_inner(), Err(JoinError::Cancelled))
+            }
+            Err(TryLockError::WouldBlock) => None,
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// spawnしたタスクの結果を待つためのFuture。
+///
+/// どのPhaseのタスクからでも`.await`できる。
+/// JoinHandleをdropしてもタスクは実行され続ける。
+pub struct JoinHandle<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+    abort_handle: AbortHandle,
+}
+impl<T> JoinHandle<T> {
+    /// タスクのIDを返す関数。
+    pub fn id(&self) -> TaskId {
+        self.abort_handle.id()
+    }
+
+    /// タスクの中断を要求する関数。
+    /// 詳しくは[`AbortHandle::abort`]を参照。
+    pub fn abort(&self) {
+        self.abort_handle.abort();
+    }
+
+    /// タスクを中断するための[`AbortHandle`]を返す関数。
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort_handle.clone()
+    }
+
+    /// タスクが終了しているかどうかを返す関数。
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().unwrap().completed
+    }
+}
+impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JoinHandle")
+            .field("id", &self.id())
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        match state.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                if state.completed {
+                    panic!("JoinHandle polled after completion");
+                }
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Futureをランタイムに積めるタスクと、その結果を受け取るJoinHandle、
+/// タスクがpanicしたときにJoinHandleへ報告する関数に分ける関数。
+pub(crate) fn joinable<F>(
+    f: F,
+    abort_handle: AbortHandle,
+) -> (impl Future<Output = ()>, JoinHandle<F::Output>, ReportPanic)
+where
+    F: Future,
+    F::Output: Send + 'static,
+{
+    let state = Arc::new(Mutex::new(JoinState {
+        output: None,
+        completed: false,
+        waker: None,
+    }));
+    let guard = CancelOnDrop {
+        state: Arc::clone(&state),
+    };
+
+    let task = async move {
+        let guard = guard;
+        let output = f.await;
+        complete(&guard.state, Ok(output));
+    };
+
+    // panicしたタスクはpollした側で捕捉され、dropされる前にこの関数で報告される
+    let report_state = Arc::clone(&state);
+    let report_panic: ReportPanic =
+        Box::new(move |payload| complete(&report_state, Err(JoinError::Panicked(payload))));
+
+    (
+        task,
+        JoinHandle {
+            state,
+            abort_handle,
+        },
+        report_panic,
+    )
+}
@@ -1,9 +1,2009 @@
+mod abort_handle;
+mod builder;
+mod clock;
+mod command_error;
+mod commands;
+mod component;
 mod container;
+mod executor;
+mod join_handle;
+mod pacing;
+mod phase_graph;
+#[cfg(feature = "profiler")]
+mod profiler;
+#[cfg(feature = "replay")]
+mod replay;
+mod resource;
+mod rng;
+mod rollback;
 mod runtime;
+mod snapshot;
+mod system;
+mod task;
+mod task_panic;
+mod task_snapshot;
+mod test_runtime;
+mod timer;
+mod update_error;
 mod wait_next_frame_future;
 mod world;
 
-pub use container::Read;
+pub use abort_handle::{AbortHandle, TaskId};
+pub use builder::RuntimeBuilder;
+pub use clock::{time, Clock, FixedStepClock, ManualClock, ManualClockHandle, RealTimeClock, Time};
+pub use command_error::{CommandError, CommandErrorPolicy};
+pub use commands::{Commands, FlushPoint, PhaseKey};
+pub use component::{SystemAccessError, SystemParams};
+pub use container::{Read, Write};
+pub use join_handle::{JoinError, JoinHandle};
+pub use pacing::FramePacing;
+pub use phase_graph::{after, before, PhaseConstraint, PhaseGraphError};
+#[cfg(feature = "profiler")]
+pub use profiler::{PhaseStats, Profiler};
+#[cfg(feature = "replay")]
+pub use replay::{ReplayDesync, ReplayError, ReplayRuntime, REPLAY_FORMAT_VERSION};
+pub use resource::Res;
+pub use rng::SeededRng;
+pub use rollback::{InputMessage, LoopbackTransport, RollbackSession, RollbackStatus, Transport};
 pub use runtime::{Runtime, RuntimeIsDone};
+pub use snapshot::{RuntimeSnapshot, Snapshot};
+pub use system::{AsyncSystem, SystemParam};
+pub use task_panic::{TaskPanicPolicy, TaskPanicked};
+pub use task_snapshot::{TaskSnapshot, TaskState};
+pub use test_runtime::{RunUntilError, TestRuntime, WorldRef};
+pub use timer::{
+    timeout_frames, wait_for, wait_frames, wait_until_frame, Elapsed, Timeout, WaitFor, WaitFrames,
+};
+pub use update_error::UpdateError;
 pub use wait_next_frame_future::next_frame;
 pub use world::World;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Eq, PartialEq, Clone, Hash, Debug)]
+    #[cfg_attr(feature = "replay", derive(serde::Serialize, serde::Deserialize))]
+    enum Phase {
+        Phase1,
+        Phase2,
+    }
+
+    #[cfg_attr(feature = "replay", derive(serde::Serialize, serde::Deserialize))]
+    enum TestCommand {
+        Add(i32),
+        Mul(i32),
+        Div(i32),
+    }
+
+    struct TestWorld {
+        value: i32,
+    }
+    impl World for TestWorld {
+        type Command = TestCommand;
+        type Error = &'static str;
+        fn process_command(&mut self, cmd: Self::Command) -> Result<(), Self::Error> {
+            match cmd {
+                TestCommand::Add(v) => self.value += v,
+                TestCommand::Mul(v) => self.value *= v,
+                TestCommand::Div(0) => return Err("division by zero"),
+                TestCommand::Div(v) => self.value /= v,
+            }
+            Ok(())
+        }
+    }
+
+    impl Snapshot for TestWorld {
+        type State = i32;
+        fn snapshot(&self) -> Self::State {
+            self.value
+        }
+        fn restore(&mut self, state: &Self::State) {
+            self.value = *state;
+        }
+    }
+
+    fn run<W: World>(runtime: &mut Runtime<Phase, W>) {
+        'update_loop: loop {
+            match runtime.update().unwrap() {
+                RuntimeIsDone::Done => break 'update_loop,
+                RuntimeIsDone::NotDone => (),
+            }
+        }
+    }
+
+    #[test]
+    fn add_async_system_returns_join_handle() {
+        async fn system(
+            world: Read<TestWorld>,
+            commands: Commands<TestWorld>,
+            _runtime: Runtime<Phase, TestWorld>,
+        ) -> i32 {
+            commands.send(TestCommand::Add(2));
+            next_frame().await;
+            world.with(|world| world.value) * 10
+        }
+
+        let mut runtime = Runtime::new(TestWorld { value: 1 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime
+            .activate_phase(Phase::Phase2, [after(Phase::Phase1)])
+            .unwrap();
+
+        let handle = runtime.add_async_system(Phase::Phase1, system);
+        let result = runtime.spawn(Phase::Phase2, async move { handle.await.unwrap() + 1 });
+
+        run(&mut runtime);
+
+        assert_eq!(futures::executor::block_on(result).unwrap(), 31);
+    }
+
+    #[test]
+    fn join_handle_reports_panicked_task() {
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+
+        let handle = runtime.spawn(Phase::Phase1, async {
+            next_frame().await;
+            panic!("task panic");
+        });
+
+        run(&mut runtime);
+
+        let err = futures::executor::block_on(handle).unwrap_err();
+        assert!(err.is_panic());
+    }
+
+    #[test]
+    fn join_handle_reports_task_dropped_during_unwinding() {
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+
+        let handle = runtime.spawn(Phase::Phase1, futures::future::pending::<()>());
+        runtime.update().unwrap();
+
+        // 別のpanicで巻き戻されている間に破棄されたタスクもキャンセル扱いになる
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || {
+            let _runtime = runtime;
+            panic!("unwinding");
+        }));
+        assert!(result.is_err());
+
+        assert!(handle.is_finished());
+        let err = futures::executor::block_on(handle).unwrap_err();
+        assert!(err.is_cancelled());
+    }
+
+    #[test]
+    fn aborted_task_is_dropped_at_next_phase_boundary() {
+        struct DropFlag(Arc<AtomicBool>);
+        impl Drop for DropFlag {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::Relaxed);
+            }
+        }
+
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime
+            .activate_phase(Phase::Phase2, [after(Phase::Phase1)])
+            .unwrap();
+
+        let dropped = Arc::new(AtomicBool::new(false));
+        let polled = Arc::new(AtomicU32::new(0));
+
+        let flag = DropFlag(Arc::clone(&dropped));
+        let count = Arc::clone(&polled);
+        let handle = runtime.spawn(Phase::Phase2, async move {
+            let _flag = flag;
+            loop {
+                count.fetch_add(1, Ordering::Relaxed);
+                next_frame().await;
+            }
+        });
+
+        let abort_handle = handle.abort_handle();
+        let dropped_in_task = Arc::clone(&dropped);
+        runtime.spawn(Phase::Phase1, async move {
+            next_frame().await;
+            abort_handle.abort();
+            assert!(!dropped_in_task.load(Ordering::Relaxed));
+        });
+
+        run(&mut runtime);
+
+        // 0フレーム目の1回だけ実行され、1フレーム目のPhase2の前に破棄される
+        assert_eq!(polled.load(Ordering::Relaxed), 1);
+        assert!(dropped.load(Ordering::Relaxed));
+        assert!(futures::executor::block_on(handle)
+            .unwrap_err()
+            .is_cancelled());
+    }
+
+    #[test]
+    fn cancel_phase_cancels_every_task_of_the_phase() {
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime
+            .activate_phase(Phase::Phase2, [after(Phase::Phase1)])
+            .unwrap();
+
+        let handles = (0..3)
+            .map(|_| {
+                runtime.spawn(Phase::Phase2, async {
+                    loop {
+                        next_frame().await;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let rt = runtime.clone();
+        let survivor = runtime.spawn(Phase::Phase1, async move {
+            next_frame().await;
+            rt.cancel_phase(Phase::Phase2);
+            next_frame().await;
+            "done"
+        });
+
+        run(&mut runtime);
+
+        for handle in handles {
+            assert!(futures::executor::block_on(handle)
+                .unwrap_err()
+                .is_cancelled());
+        }
+        assert_eq!(futures::executor::block_on(survivor).unwrap(), "done");
+    }
+
+    #[test]
+    fn pending_task_is_parked_until_woken() {
+        use futures::channel::oneshot;
+        use std::pin::Pin;
+        use std::task::{Context, Poll};
+
+        struct CountPolls<F> {
+            inner: F,
+            count: Arc<AtomicU32>,
+        }
+        impl<F: Future + Unpin> Future for CountPolls<F> {
+            type Output = F::Output;
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+                self.count.fetch_add(1, Ordering::Relaxed);
+                Pin::new(&mut self.inner).poll(cx)
+            }
+        }
+
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime
+            .activate_phase(Phase::Phase2, [after(Phase::Phase1)])
+            .unwrap();
+
+        let (sender, receiver) = oneshot::channel();
+        let polled = Arc::new(AtomicU32::new(0));
+
+        let count = Arc::clone(&polled);
+        let handle = runtime.spawn(Phase::Phase2, async move {
+            CountPolls {
+                inner: receiver,
+                count,
+            }
+            .await
+            .unwrap()
+        });
+
+        runtime.spawn(Phase::Phase1, async move {
+            for _ in 0..10 {
+                next_frame().await;
+            }
+            sender.send(7).unwrap();
+        });
+
+        run(&mut runtime);
+
+        // 最初のpollとwakeされた後のpollの2回だけ
+        assert_eq!(polled.load(Ordering::Relaxed), 2);
+        assert_eq!(futures::executor::block_on(handle).unwrap(), 7);
+        assert_eq!(runtime.frame_counter(), 10);
+    }
+
+    #[test]
+    fn task_woken_during_poll_is_polled_again_in_the_same_frame() {
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+
+        runtime.spawn(Phase::Phase1, async {
+            let mut yielded = false;
+            futures::future::poll_fn(|cx| {
+                if yielded {
+                    std::task::Poll::Ready(())
+                } else {
+                    yielded = true;
+                    cx.waker().wake_by_ref();
+                    std::task::Poll::Pending
+                }
+            })
+            .await;
+        });
+
+        run(&mut runtime);
+
+        assert_eq!(runtime.frame_counter(), 0);
+    }
+
+    #[test]
+    fn wait_frames_resumes_after_n_frames() {
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+
+        let rt = runtime.clone();
+        let handle = runtime.spawn(Phase::Phase1, async move {
+            let mut frames = vec![];
+            wait_frames(3).await;
+            frames.push(rt.frame_counter());
+            // ホイールのスロット数を超える待機
+            wait_frames(200).await;
+            frames.push(rt.frame_counter());
+            wait_until_frame(250).await;
+            frames.push(rt.frame_counter());
+            wait_frames(0).await;
+            frames.push(rt.frame_counter());
+            frames
+        });
+
+        run(&mut runtime);
+
+        assert_eq!(
+            futures::executor::block_on(handle).unwrap(),
+            vec![3, 203, 250, 250]
+        );
+    }
+
+    #[test]
+    fn waiting_on_timer_does_not_poll_the_task() {
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+
+        let polled = Arc::new(AtomicU32::new(0));
+        let count = Arc::clone(&polled);
+        runtime.spawn(Phase::Phase1, async move {
+            let wait = wait_frames(100);
+            futures::pin_mut!(wait);
+            futures::future::poll_fn(|cx| {
+                count.fetch_add(1, Ordering::Relaxed);
+                wait.as_mut().poll(cx)
+            })
+            .await;
+        });
+
+        run(&mut runtime);
+
+        assert_eq!(polled.load(Ordering::Relaxed), 2);
+        assert_eq!(runtime.frame_counter(), 100);
+    }
+
+    #[test]
+    fn timeout_frames_returns_elapsed_error() {
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+
+        let handle = runtime.spawn(Phase::Phase1, async {
+            let fast = timeout_frames(5, async {
+                wait_frames(2).await;
+                "fast"
+            })
+            .await;
+            let slow = timeout_frames(5, futures::future::pending::<()>()).await;
+            (fast, slow)
+        });
+
+        run(&mut runtime);
+
+        let (fast, slow) = futures::executor::block_on(handle).unwrap();
+        assert_eq!(fast, Ok("fast"));
+        assert!(slow.is_err());
+        assert_eq!(runtime.frame_counter(), 7);
+    }
+
+    #[test]
+    fn timers_polled_every_frame_register_once() {
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+
+        // 中のFutureが毎フレームwakeされるので、タイマーのFutureも毎フレームpollされる
+        async fn tick(frames: u32) {
+            for _ in 0..frames {
+                next_frame().await;
+            }
+        }
+        runtime.spawn(Phase::Phase1, async {
+            timeout_frames(1000, tick(10)).await.unwrap();
+        });
+        runtime.spawn(Phase::Phase1, async {
+            let wait = wait_for(std::time::Duration::from_secs(1000));
+            futures::future::select(Box::pin(wait), Box::pin(tick(10))).await;
+        });
+
+        for _ in 0..10 {
+            runtime.update().unwrap();
+            assert!(runtime.timer_count() <= 2);
+        }
+    }
+
+    #[test]
+    fn wait_for_resumes_after_duration() {
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+
+        runtime.spawn(Phase::Phase1, async {
+            wait_for(std::time::Duration::from_millis(30)).await;
+        });
+
+        let start = std::time::Instant::now();
+        run(&mut runtime);
+
+        assert!(start.elapsed() >= std::time::Duration::from_millis(30));
+        assert!(runtime.frame_counter() > 0);
+    }
+
+    #[test]
+    fn builder_partitions_tasks_across_named_workers() {
+        let mut runtime = RuntimeBuilder::new()
+            .worker_threads(4)
+            .thread_name("test-worker")
+            .thread_stack_size(512 * 1024)
+            .build(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+
+        let handles = (0..16)
+            .map(|_| {
+                runtime.spawn(Phase::Phase1, async {
+                    next_frame().await;
+                    std::thread::current().name().unwrap().to_string()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        run(&mut runtime);
+
+        for handle in handles {
+            let name = futures::executor::block_on(handle).unwrap();
+            assert!(name.starts_with("test-worker-"), "{}", name);
+        }
+    }
+
+    #[test]
+    fn single_threaded_runtime_polls_tasks_on_the_caller_thread() {
+        let mut runtime = RuntimeBuilder::new()
+            .single_threaded()
+            .build(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+
+        let handle = runtime.spawn(Phase::Phase1, async {
+            next_frame().await;
+            std::thread::current().id()
+        });
+
+        run(&mut runtime);
+
+        assert_eq!(
+            futures::executor::block_on(handle).unwrap(),
+            std::thread::current().id()
+        );
+    }
+
+    #[test]
+    fn idle_worker_steals_tasks_queued_behind_a_heavy_task() {
+        let mut runtime = RuntimeBuilder::new()
+            .worker_threads(2)
+            .build(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+
+        let light_done = Arc::new(AtomicU32::new(0));
+
+        // 他のタスクがすべて終わるまでワーカーを占有し続けるタスク
+        let done = Arc::clone(&light_done);
+        let heavy = runtime.spawn(Phase::Phase1, async move {
+            let start = std::time::Instant::now();
+            while done.load(Ordering::Acquire) < 8
+                && start.elapsed() < std::time::Duration::from_secs(5)
+            {
+                std::thread::yield_now();
+            }
+            done.load(Ordering::Acquire)
+        });
+        for _ in 0..8 {
+            let done = Arc::clone(&light_done);
+            runtime.spawn(Phase::Phase1, async move {
+                done.fetch_add(1, Ordering::AcqRel);
+            });
+        }
+
+        run(&mut runtime);
+
+        assert_eq!(futures::executor::block_on(heavy).unwrap(), 8);
+    }
+
+    #[test]
+    fn task_woken_mid_phase_runs_in_the_same_phase() {
+        use futures::channel::oneshot;
+
+        let mut runtime = RuntimeBuilder::new()
+            .worker_threads(2)
+            .build(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+
+        let (sender, receiver) = oneshot::channel();
+
+        let rt = runtime.clone();
+        let receiver = runtime.spawn(Phase::Phase1, async move {
+            receiver.await.unwrap();
+            rt.frame_counter()
+        });
+        let rt = runtime.clone();
+        let sender = runtime.spawn(Phase::Phase1, async move {
+            wait_frames(3).await;
+            sender.send(()).unwrap();
+            rt.frame_counter()
+        });
+
+        run(&mut runtime);
+
+        let sent = futures::executor::block_on(sender).unwrap();
+        let received = futures::executor::block_on(receiver).unwrap();
+        assert_eq!(sent, 3);
+        assert_eq!(received, 3);
+    }
+
+    #[test]
+    fn fixed_step_clock_scales_delta_time() {
+        let step = std::time::Duration::from_millis(20);
+        let mut runtime = RuntimeBuilder::new()
+            .single_threaded()
+            .clock(FixedStepClock::new(step))
+            .build(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+
+        let handle = runtime.spawn(Phase::Phase1, async {
+            let mut times = vec![];
+            for _ in 0..3 {
+                times.push(time());
+                next_frame().await;
+            }
+            times
+        });
+
+        runtime.update().unwrap();
+        assert_eq!(runtime.delta_time(), step);
+        runtime.set_time_scale(0.5);
+        run(&mut runtime);
+
+        let times = futures::executor::block_on(handle).unwrap();
+        assert_eq!(times[0].delta_time(), step);
+        assert_eq!(times[1].delta_time(), step / 2);
+        assert_eq!(times[1].unscaled_delta_time(), step);
+        assert_eq!(times[2].elapsed(), step * 2);
+        assert_eq!(times[2].frame_counter(), 2);
+        assert_eq!(runtime.time_scale(), 0.5);
+    }
+
+    #[test]
+    fn wait_for_follows_the_runtime_clock() {
+        let clock = ManualClock::new();
+        let clock_handle = clock.handle();
+        let mut runtime = RuntimeBuilder::new()
+            .single_threaded()
+            .clock(clock)
+            .build(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+
+        let handle = runtime.spawn(Phase::Phase1, async {
+            wait_for(std::time::Duration::from_secs(1)).await;
+            time().elapsed()
+        });
+
+        // 時計を進めない限り、何フレーム経っても完了しない
+        for _ in 0..10 {
+            runtime.update().unwrap();
+        }
+        assert!(!handle.is_finished());
+
+        clock_handle.advance(std::time::Duration::from_millis(600));
+        runtime.update().unwrap();
+        assert!(!handle.is_finished());
+
+        clock_handle.advance(std::time::Duration::from_millis(600));
+        run(&mut runtime);
+        assert_eq!(
+            futures::executor::block_on(handle).unwrap(),
+            std::time::Duration::from_millis(1200)
+        );
+    }
+
+    // Phase1を固定Phaseにして、Phase2が3フレーム実行されるまでに
+    // Phase1が実行された回数を返す。
+    fn count_fixed_steps(step: std::time::Duration, update_hz: u32, max_catchup: u32) -> u32 {
+        let mut runtime = RuntimeBuilder::new()
+            .single_threaded()
+            .clock(FixedStepClock::new(step))
+            .build(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime
+            .activate_phase(Phase::Phase2, [after(Phase::Phase1)])
+            .unwrap();
+        runtime.set_fixed_phase(Phase::Phase1);
+
+        let steps = Arc::new(AtomicU32::new(0));
+        let fixed_steps = Arc::clone(&steps);
+        runtime.spawn(Phase::Phase1, async move {
+            loop {
+                assert_eq!(
+                    time().delta_time(),
+                    std::time::Duration::from_secs(1) / update_hz
+                );
+                fixed_steps.fetch_add(1, Ordering::AcqRel);
+                next_frame().await;
+            }
+        });
+        let render_runtime = runtime.clone();
+        runtime.spawn(Phase::Phase2, async move {
+            for _ in 0..2 {
+                next_frame().await;
+            }
+            render_runtime.cancel_phase(Phase::Phase1);
+        });
+
+        runtime
+            .run_with(FramePacing::FixedTimestep {
+                update_hz,
+                max_catchup,
+            })
+            .unwrap();
+        assert_eq!(runtime.frame_counter(), 2);
+        steps.load(Ordering::Acquire)
+    }
+
+    #[test]
+    fn fixed_timestep_runs_fixed_phases_by_elapsed_time() {
+        // 50msのフレームで20msの固定Phaseは2回、3回、2回実行される
+        assert_eq!(
+            count_fixed_steps(std::time::Duration::from_millis(50), 50, 10),
+            7
+        );
+    }
+
+    #[test]
+    fn fixed_timestep_limits_catch_up_steps() {
+        assert_eq!(
+            count_fixed_steps(std::time::Duration::from_millis(100), 50, 2),
+            6
+        );
+    }
+
+    #[test]
+    fn fixed_pacing_sleeps_until_the_next_frame() {
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime.spawn(Phase::Phase1, async {
+            for _ in 0..5 {
+                next_frame().await;
+            }
+        });
+
+        let start = std::time::Instant::now();
+        runtime.run_with(FramePacing::Fixed(100)).unwrap();
+
+        assert_eq!(runtime.frame_counter(), 5);
+        assert!(start.elapsed() >= std::time::Duration::from_millis(50));
+    }
+
+    #[test]
+    fn paused_phase_keeps_tasks_until_resumed() {
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime
+            .activate_phase(Phase::Phase2, [after(Phase::Phase1)])
+            .unwrap();
+
+        let count = Arc::new(AtomicU32::new(0));
+        let task_count = Arc::clone(&count);
+        runtime.spawn(Phase::Phase1, async move {
+            for _ in 0..3 {
+                task_count.fetch_add(1, Ordering::AcqRel);
+                next_frame().await;
+            }
+        });
+        // ポーズメニューのように、一時停止中も動き続けるPhase
+        let menu_frames = Arc::new(AtomicU32::new(0));
+        let frames = Arc::clone(&menu_frames);
+        let menu_runtime = runtime.clone();
+        runtime.spawn(Phase::Phase2, async move {
+            next_frame().await;
+            menu_runtime.pause_phase(Phase::Phase1);
+            for _ in 0..3 {
+                frames.fetch_add(1, Ordering::AcqRel);
+                next_frame().await;
+            }
+            menu_runtime.resume_phase(&Phase::Phase1);
+        });
+
+        for _ in 0..4 {
+            runtime.update().unwrap();
+        }
+        assert_eq!(count.load(Ordering::Acquire), 2);
+        assert_eq!(menu_frames.load(Ordering::Acquire), 3);
+
+        run(&mut runtime);
+        assert_eq!(count.load(Ordering::Acquire), 3);
+    }
+
+    #[test]
+    fn reorder_and_deactivate_phases() {
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime.activate_phase(Phase::Phase2, []).unwrap();
+
+        let log = Arc::new(std::sync::Mutex::new(vec![]));
+        let log1 = Arc::clone(&log);
+        runtime.spawn(Phase::Phase1, async move {
+            for _ in 0..3 {
+                log1.lock().unwrap().push(1);
+                next_frame().await;
+            }
+        });
+        let log2 = Arc::clone(&log);
+        let handle = runtime.spawn(Phase::Phase2, async move {
+            for _ in 0..3 {
+                log2.lock().unwrap().push(2);
+                next_frame().await;
+            }
+        });
+
+        runtime.update().unwrap();
+        runtime
+            .set_phase_order(Phase::Phase1, [after(Phase::Phase2)])
+            .unwrap();
+        runtime.update().unwrap();
+        runtime.deactivate_phase(Phase::Phase2);
+        run(&mut runtime);
+
+        assert_eq!(*log.lock().unwrap(), vec![1, 2, 2, 1, 1]);
+        assert!(futures::executor::block_on(handle)
+            .unwrap_err()
+            .is_cancelled());
+    }
+
+    #[test]
+    fn activate_phase_from_runtime_clone() {
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+
+        // タスクが持っているRuntimeのクローンからPhaseをActivateする
+        let handle = runtime.clone();
+        runtime.spawn(Phase::Phase1, async move {
+            handle
+                .activate_phase(Phase::Phase2, [after(Phase::Phase1)])
+                .unwrap();
+            handle.set_fixed_phase(Phase::Phase2);
+        });
+        let phase2 = runtime.spawn(Phase::Phase2, async {});
+
+        runtime.update().unwrap();
+        runtime.update().unwrap();
+        assert!(futures::executor::block_on(phase2).is_ok());
+    }
+
+    #[test]
+    fn phases_are_sorted_by_constraints() {
+        #[derive(Eq, PartialEq, Clone, Hash, Debug)]
+        enum GamePhase {
+            Input,
+            Update,
+            Render,
+        }
+
+        let mut runtime = Runtime::<GamePhase, TestWorld>::new(TestWorld { value: 0 });
+        runtime
+            .activate_phase(GamePhase::Render, [after(GamePhase::Update)])
+            .unwrap();
+        runtime.activate_phase(GamePhase::Update, []).unwrap();
+        runtime
+            .activate_phase(GamePhase::Input, [before(GamePhase::Update)])
+            .unwrap();
+
+        let log = Arc::new(std::sync::Mutex::new(vec![]));
+        for phase in [GamePhase::Render, GamePhase::Update, GamePhase::Input] {
+            let log = Arc::clone(&log);
+            let p = phase.clone();
+            runtime.spawn(phase, async move {
+                log.lock().unwrap().push(p);
+            });
+        }
+        runtime.update().unwrap();
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![GamePhase::Input, GamePhase::Update, GamePhase::Render]
+        );
+    }
+
+    #[test]
+    fn phase_graph_errors_leave_the_graph_unchanged() {
+        #[derive(Eq, PartialEq, Clone, Hash, Debug)]
+        enum GamePhase {
+            A,
+            B,
+            C,
+        }
+
+        let runtime = Runtime::<GamePhase, TestWorld>::new(TestWorld { value: 0 });
+        runtime.activate_phase(GamePhase::A, []).unwrap();
+        runtime
+            .activate_phase(GamePhase::B, [after(GamePhase::A)])
+            .unwrap();
+
+        assert_eq!(
+            runtime.activate_phase(GamePhase::A, []),
+            Err(PhaseGraphError::AlreadyActivated(GamePhase::A))
+        );
+        assert_eq!(
+            runtime.set_phase_order(GamePhase::C, []),
+            Err(PhaseGraphError::NotActivated(GamePhase::C))
+        );
+
+        let err = runtime
+            .activate_phase(GamePhase::C, [after(GamePhase::B), before(GamePhase::A)])
+            .unwrap_err();
+        assert_eq!(
+            err,
+            PhaseGraphError::Cycle(vec![GamePhase::A, GamePhase::B, GamePhase::C])
+        );
+        assert_eq!(
+            err.to_string(),
+            "PHASE constraints form a cycle: A -> B -> C -> A"
+        );
+
+        // 失敗した変更は反映されないので、Cは後からActivateできる
+        runtime
+            .activate_phase(GamePhase::C, [after(GamePhase::B)])
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "world can only be read while the runtime is polling tasks")]
+    fn read_outside_polling_panics() {
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+
+        let handle = runtime.add_async_system(
+            Phase::Phase1,
+            |world, _commands, _runtime| async move { world },
+        );
+        run(&mut runtime);
+
+        // タスクの外に持ち出したReadは参照できない
+        let world = futures::executor::block_on(handle).unwrap();
+        world.with(|world| world.value);
+    }
+
+    #[test]
+    fn read_cannot_be_used_from_another_thread_during_poll() {
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+
+        let handle =
+            runtime.add_async_system(Phase::Phase1, |world, _commands, _runtime| async move {
+                // pollの途中でも、他のスレッドで参照している間にpollが終わるかもしれないので参照できない
+                let reader = world.clone();
+                std::thread::spawn(move || reader.with(|world| world.value)).join()
+            });
+        run(&mut runtime);
+
+        let payload = futures::executor::block_on(handle).unwrap().unwrap_err();
+        assert_eq!(
+            payload.downcast_ref::<&str>(),
+            Some(&"world can only be read from a task polled by its runtime")
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "world cannot be read while commands are being applied")]
+    fn read_kept_across_command_flush_cannot_alias_write() {
+        // 自分自身へのReadをコマンドとして受け取るWorld
+        struct PeekWorld {
+            value: i32,
+        }
+        impl World for PeekWorld {
+            type Command = Read<PeekWorld>;
+            type Error = std::convert::Infallible;
+            fn process_command(&mut self, world: Self::Command) -> Result<(), Self::Error> {
+                // &mut selfが存在する間に同じWorldを読もうとする
+                self.value = world.with(|world| world.value) + 1;
+                Ok(())
+            }
+        }
+
+        let mut runtime = Runtime::<Phase, PeekWorld>::new(PeekWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime.add_async_system(Phase::Phase1, |world, commands, _runtime| async move {
+            commands.send(world);
+        });
+
+        run(&mut runtime);
+    }
+
+    struct Player {
+        x: i32,
+    }
+    struct Enemies {
+        xs: Vec<i32>,
+    }
+
+    #[test]
+    fn component_systems_write_components_directly() {
+        let mut runtime = RuntimeBuilder::new()
+            .worker_threads(2)
+            .build(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime
+            .activate_phase(Phase::Phase2, [after(Phase::Phase1)])
+            .unwrap();
+        runtime.insert_component(Player { x: 0 });
+        runtime.insert_component(Enemies { xs: vec![10, 20] });
+
+        // Phase1の2つのシステムはアクセスが重ならないので並列に実行される
+        runtime
+            .add_component_system(Phase::Phase1, |mut player: Write<Player>| async move {
+                for _ in 0..3 {
+                    player.with_mut(|player| player.x += 1);
+                    next_frame().await;
+                }
+            })
+            .unwrap();
+        runtime
+            .add_component_system(
+                Phase::Phase1,
+                |(mut enemies,): (Write<Enemies>,)| async move {
+                    for _ in 0..3 {
+                        enemies.with_mut(|enemies| enemies.xs.iter_mut().for_each(|x| *x -= 1));
+                        next_frame().await;
+                    }
+                },
+            )
+            .unwrap();
+        let handle = runtime
+            .add_component_system(
+                Phase::Phase2,
+                |(player, enemies): (Read<Player>, Read<Enemies>)| async move {
+                    for _ in 0..3 {
+                        next_frame().await;
+                    }
+                    (player.with(|p| p.x), enemies.with(|e| e.xs.clone()))
+                },
+            )
+            .unwrap();
+
+        run(&mut runtime);
+
+        assert_eq!(
+            futures::executor::block_on(handle).unwrap(),
+            (3, vec![7, 17])
+        );
+    }
+
+    #[test]
+    fn component_access_moved_to_another_task_panics() {
+        let mut runtime = RuntimeBuilder::new()
+            .worker_threads(2)
+            .build(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime
+            .activate_phase(Phase::Phase2, [after(Phase::Phase1)])
+            .unwrap();
+        runtime.insert_component(Player { x: 0 });
+
+        // Phase1で宣言したアクセスを、宣言の確認を通らずにPhase2のタスクに渡す
+        let spawner = runtime.clone();
+        let handle = runtime
+            .add_component_system(Phase::Phase1, move |mut player: Write<Player>| {
+                spawner.spawn(Phase::Phase2, async move {
+                    player.with_mut(|player| player.x += 1);
+                })
+            })
+            .unwrap();
+        // Phase2でPlayerを読むシステムと並列に実行されても、渡されたWriteは参照できない
+        runtime
+            .add_component_system(Phase::Phase2, |player: Read<Player>| async move {
+                player.with(|player| player.x)
+            })
+            .unwrap();
+
+        run(&mut runtime);
+
+        // Phase1のシステムは、Phase2に渡したタスクの結果を返す
+        let err = futures::executor::block_on(handle).unwrap().unwrap_err();
+        assert_eq!(
+            task_panic::panic_message(&*err.into_panic()),
+            "component can only be accessed from the system that declared the access"
+        );
+    }
+
+    #[test]
+    fn conflicting_component_access_is_rejected_in_the_same_phase() {
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime
+            .activate_phase(Phase::Phase2, [after(Phase::Phase1)])
+            .unwrap();
+        runtime.insert_component(Player { x: 0 });
+
+        let conflict = SystemAccessError::Conflict {
+            phase: Phase::Phase1,
+            component: std::any::type_name::<Player>(),
+        };
+
+        // 1つのシステムの中での衝突
+        let result = runtime.add_component_system(
+            Phase::Phase1,
+            |(_r, _w): (Read<Player>, Write<Player>)| async {},
+        );
+        assert_eq!(result.unwrap_err(), conflict);
+
+        runtime
+            .add_component_system(Phase::Phase1, |_player: Write<Player>| async {
+                next_frame().await;
+            })
+            .unwrap();
+        let result = runtime.add_component_system(Phase::Phase1, |_player: Read<Player>| async {});
+        assert_eq!(result.unwrap_err(), conflict);
+
+        // 別のPhaseからは読める
+        runtime
+            .add_component_system(Phase::Phase2, |_player: Read<Player>| async {})
+            .unwrap();
+
+        assert_eq!(
+            runtime
+                .add_component_system(Phase::Phase1, |_enemies: Read<Enemies>| async {})
+                .unwrap_err(),
+            SystemAccessError::MissingComponent(std::any::type_name::<Enemies>())
+        );
+
+        // タスクが終了すると宣言したアクセスは解放される
+        run(&mut runtime);
+        runtime
+            .add_component_system(Phase::Phase1, |_player: Read<Player>| async {})
+            .unwrap();
+    }
+
+    #[test]
+    fn commands_are_applied_in_system_registration_order() {
+        let mut runtime = RuntimeBuilder::new()
+            .worker_threads(2)
+            .build(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+
+        let second_sent = Arc::new(AtomicBool::new(false));
+
+        // 先に登録したシステムは、後に登録したシステムがコマンドを送るのを待ってから送る
+        let sent = Arc::clone(&second_sent);
+        runtime.add_async_system(Phase::Phase1, move |_world, commands, _runtime| {
+            let sent = Arc::clone(&sent);
+            async move {
+                let start = std::time::Instant::now();
+                while !sent.load(Ordering::Acquire)
+                    && start.elapsed() < std::time::Duration::from_secs(5)
+                {
+                    std::thread::yield_now();
+                }
+                commands.send(TestCommand::Add(1));
+                commands.send(TestCommand::Mul(3));
+            }
+        });
+        let sent = Arc::clone(&second_sent);
+        let handle = runtime.add_async_system(Phase::Phase1, move |world, commands, _runtime| {
+            let sent = Arc::clone(&sent);
+            async move {
+                commands.send(TestCommand::Add(5));
+                sent.store(true, Ordering::Release);
+                next_frame().await;
+                world.with(|world| world.value)
+            }
+        });
+
+        run(&mut runtime);
+
+        // 送信された時刻に関わらず、(0 + 1) * 3 + 5の順に適用される
+        assert!(second_sent.load(Ordering::Acquire));
+        assert_eq!(futures::executor::block_on(handle).unwrap(), 8);
+    }
+
+    // Phaseで見えるWorldの値をフレームごとに記録するシステムを登録する。
+    fn record_values<T>(
+        runtime: &Runtime<T, TestWorld>,
+        phase: T,
+        frames: usize,
+    ) -> JoinHandle<Vec<i32>>
+    where
+        T: Eq + std::hash::Hash + Clone + std::fmt::Debug + Send + 'static,
+    {
+        runtime.add_async_system(phase, move |world, _commands, _runtime| async move {
+            let mut values = vec![];
+            for _ in 0..frames {
+                values.push(world.with(|world| world.value));
+                next_frame().await;
+            }
+            values
+        })
+    }
+
+    #[test]
+    fn flush_points_control_when_commands_become_visible() {
+        #[derive(Eq, PartialEq, Clone, Hash, Debug)]
+        enum GamePhase {
+            Input,
+            Update,
+            Render,
+        }
+
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(GamePhase::Input, []).unwrap();
+        runtime
+            .activate_phase(GamePhase::Update, [after(GamePhase::Input)])
+            .unwrap();
+        runtime
+            .activate_phase(GamePhase::Render, [after(GamePhase::Update)])
+            .unwrap();
+        // Inputの後は同期点ではなくなる
+        runtime.add_sync_point(GamePhase::Update);
+
+        runtime.add_async_system(GamePhase::Input, |_world, commands, _runtime| async move {
+            commands.send(TestCommand::Add(1));
+            commands.send_at(
+                TestCommand::Add(10),
+                FlushPoint::after_phase(GamePhase::Input),
+            );
+            commands
+                .flush_at(FlushPoint::EndOfFrame)
+                .send(TestCommand::Add(100));
+            commands.send_at(TestCommand::Add(1000), FlushPoint::NextFrame);
+        });
+        let input = record_values(&runtime, GamePhase::Input, 2);
+        let update = record_values(&runtime, GamePhase::Update, 2);
+        let render = record_values(&runtime, GamePhase::Render, 2);
+
+        while let RuntimeIsDone::NotDone = runtime.update().unwrap() {}
+
+        let values = |handle: JoinHandle<Vec<i32>>| futures::executor::block_on(handle).unwrap();
+        assert_eq!(values(input), vec![0, 1111]);
+        assert_eq!(values(update), vec![10, 1111]);
+        assert_eq!(values(render), vec![11, 1111]);
+    }
+
+    #[test]
+    fn after_phase_waits_for_the_phase_in_the_next_frame() {
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime
+            .activate_phase(Phase::Phase2, [after(Phase::Phase1)])
+            .unwrap();
+
+        // Phase1はこのフレームで既に実行されているので、次のフレームのPhase1の後に適用される
+        runtime.add_async_system(Phase::Phase2, |_world, commands, _runtime| async move {
+            commands.send_at(TestCommand::Add(1), FlushPoint::after_phase(Phase::Phase1));
+        });
+        let phase1 = record_values(&runtime, Phase::Phase1, 3);
+        let phase2 = record_values(&runtime, Phase::Phase2, 3);
+
+        run(&mut runtime);
+
+        let values = |handle: JoinHandle<Vec<i32>>| futures::executor::block_on(handle).unwrap();
+        assert_eq!(values(phase1), vec![0, 0, 1]);
+        assert_eq!(values(phase2), vec![0, 1, 1]);
+    }
+
+    #[test]
+    fn rejected_commands_are_collected_or_passed_to_callback() {
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime
+            .activate_phase(Phase::Phase2, [after(Phase::Phase1)])
+            .unwrap();
+
+        runtime.add_async_system(Phase::Phase1, |_world, commands, _runtime| async move {
+            commands.send(TestCommand::Add(1));
+            commands.send(TestCommand::Div(0));
+            commands.send(TestCommand::Add(2));
+            next_frame().await;
+            commands.send(TestCommand::Div(0));
+        });
+        let handle =
+            runtime.add_async_system(Phase::Phase2, |world, _commands, _runtime| async move {
+                next_frame().await;
+                world.with(|world| world.value)
+            });
+
+        // 拒否されたコマンドはWorldを変更せず、後のコマンドは適用される
+        runtime.update().unwrap();
+        let errors = runtime.command_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].frame_counter(), 0);
+        assert_eq!(errors[0].phase(), Some(&Phase::Phase1));
+        assert_eq!(*errors[0].error(), "division by zero");
+        assert!(runtime.command_errors().is_empty());
+
+        let rejected = Arc::new(std::sync::Mutex::new(vec![]));
+        let r = Arc::clone(&rejected);
+        runtime.set_command_error_policy(CommandErrorPolicy::Callback(Box::new(move |error| {
+            r.lock().unwrap().push(error);
+        })));
+        run(&mut runtime);
+
+        assert!(runtime.command_errors().is_empty());
+        let rejected = rejected.lock().unwrap();
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].frame_counter(), 1);
+        assert_eq!(futures::executor::block_on(handle).unwrap(), 3);
+    }
+
+    #[test]
+    fn fail_policy_fails_update_after_the_frame() {
+        let mut runtime = Runtime::new(TestWorld { value: 1 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime
+            .activate_phase(Phase::Phase2, [after(Phase::Phase1)])
+            .unwrap();
+        runtime.set_command_error_policy(CommandErrorPolicy::Fail);
+
+        runtime.add_async_system(Phase::Phase1, |_world, commands, _runtime| async move {
+            commands.send(TestCommand::Div(0));
+            commands
+                .flush_at(FlushPoint::EndOfFrame)
+                .send(TestCommand::Div(0));
+        });
+        let handle =
+            runtime.add_async_system(Phase::Phase2, |world, commands, _runtime| async move {
+                commands.send(TestCommand::Mul(5));
+                next_frame().await;
+                world.with(|world| world.value)
+            });
+
+        // 最初のエラーが返るが、フレームの残りのPhaseは実行される
+        let error = match runtime.update() {
+            Err(UpdateError::CommandRejected(error)) => error,
+            _ => panic!("expected CommandRejected"),
+        };
+        assert_eq!(error.phase(), Some(&Phase::Phase1));
+        assert_eq!(
+            error.to_string(),
+            "command rejected after PHASE Phase1 in frame 0: division by zero"
+        );
+        assert_eq!(runtime.frame_counter(), 1);
+
+        // 2つ目以降のエラーは溜められる
+        let errors = runtime.command_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].phase(), None);
+
+        run(&mut runtime);
+        assert_eq!(futures::executor::block_on(handle).unwrap(), 5);
+    }
+
+    #[test]
+    fn systems_receive_any_subset_of_params() {
+        struct Offset(i32);
+
+        async fn add_offset(offset: Res<Offset>, commands: Commands<TestWorld>) {
+            commands.send(TestCommand::Add(offset.0));
+        }
+
+        let mut runtime = Runtime::new(TestWorld { value: 1 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime
+            .activate_phase(Phase::Phase2, [after(Phase::Phase1)])
+            .unwrap();
+        runtime.insert_resource(Offset(10));
+
+        runtime.add_system(Phase::Phase1, add_offset).unwrap();
+        let nothing = runtime.add_system(Phase::Phase1, || async { 1 }).unwrap();
+        let handle = runtime
+            .add_system(
+                Phase::Phase2,
+                |world: Read<TestWorld>,
+                 offset: Res<Offset>,
+                 runtime: Runtime<Phase, TestWorld>| async move {
+                    (
+                        world.with(|world| world.value) + offset.0,
+                        runtime.frame_counter(),
+                    )
+                },
+            )
+            .unwrap();
+
+        run(&mut runtime);
+
+        assert_eq!(futures::executor::block_on(nothing).unwrap(), 1);
+        assert_eq!(futures::executor::block_on(handle).unwrap(), (21, 0));
+    }
+
+    #[test]
+    fn system_with_missing_resource_is_rejected() {
+        struct Missing;
+
+        let runtime = Runtime::<Phase, TestWorld>::new(TestWorld { value: 0 });
+        let result = runtime.add_system(Phase::Phase1, |_missing: Res<Missing>| async {});
+
+        match result {
+            Err(SystemAccessError::MissingResource(name)) => assert!(name.ends_with("Missing")),
+            _ => panic!("expected MissingResource"),
+        }
+    }
+
+    #[test]
+    fn panicking_task_is_reported_and_workers_survive() {
+        async fn panicking_system(runtime: Runtime<Phase, TestWorld>) {
+            next_frame().await;
+            if runtime.frame_counter() == 1 {
+                panic!("system panic");
+            }
+        }
+
+        let mut runtime = RuntimeBuilder::new()
+            .worker_threads(2)
+            .build(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+
+        let panicked = runtime.add_system(Phase::Phase1, panicking_system).unwrap();
+        let survivor = runtime.spawn(Phase::Phase1, async {
+            for _ in 0..3 {
+                next_frame().await;
+            }
+            true
+        });
+
+        run(&mut runtime);
+
+        // panicしたタスクだけが破棄され、他のタスクは最後まで実行される
+        assert!(futures::executor::block_on(survivor).unwrap());
+        assert!(futures::executor::block_on(panicked)
+            .unwrap_err()
+            .is_panic());
+
+        let panics = runtime.task_panics();
+        assert_eq!(panics.len(), 1);
+        assert!(panics[0].task_name().unwrap().ends_with("panicking_system"));
+        assert_eq!(panics[0].phase(), &Phase::Phase1);
+        assert_eq!(panics[0].frame(), 1);
+        assert_eq!(
+            panics[0].payload().downcast_ref::<&str>(),
+            Some(&"system panic")
+        );
+    }
+
+    #[test]
+    fn task_panic_policy_restarts_or_aborts() {
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime.insert_resource(AtomicU32::new(0));
+        runtime.set_task_panic_policy(TaskPanicPolicy::Restart);
+
+        // 最初の実行だけpanicするので、登録し直されたシステムは完了する
+        runtime
+            .add_system(Phase::Phase1, |runs: Res<AtomicU32>| async move {
+                if runs.fetch_add(1, Ordering::AcqRel) == 0 {
+                    panic!("first run");
+                }
+            })
+            .unwrap();
+        run(&mut runtime);
+
+        let runs = runtime.add_system(Phase::Phase1, |runs: Res<AtomicU32>| async move {
+            runs.load(Ordering::Acquire)
+        });
+        run(&mut runtime);
+        assert_eq!(futures::executor::block_on(runs.unwrap()).unwrap(), 2);
+        assert_eq!(runtime.task_panics().len(), 1);
+
+        // Abortの場合はフレームの終わりにupdateがエラーを返す
+        runtime.set_task_panic_policy(TaskPanicPolicy::Abort);
+        runtime.spawn(Phase::Phase1, async { panic!("abort") });
+        match runtime.update() {
+            Err(UpdateError::TaskPanicked(panicked)) => {
+                assert_eq!(panicked.task_name(), None);
+                assert!(panicked
+                    .to_string()
+                    .ends_with("panicked in PHASE Phase1 at frame 1: abort"));
+            }
+            _ => panic!("expected TaskPanicked"),
+        }
+        assert!(runtime.task_panics().is_empty());
+    }
+
+    #[test]
+    fn tasks_snapshot_lists_live_tasks() {
+        async fn looping_system() {
+            loop {
+                next_frame().await;
+            }
+        }
+
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime.activate_phase(Phase::Phase2, []).unwrap();
+
+        runtime.add_system(Phase::Phase1, looping_system).unwrap();
+        runtime.update().unwrap();
+        let stuck = runtime.spawn_named(Phase::Phase2, "stuck", futures::future::pending::<()>());
+        runtime.update().unwrap();
+
+        let snapshot = runtime.tasks_snapshot();
+        assert_eq!(snapshot.len(), 2);
+
+        // 毎フレームpollされるシステムは実行可能なまま残る
+        assert!(snapshot[0].name().unwrap().ends_with("looping_system"));
+        assert_eq!(snapshot[0].phase(), &Phase::Phase1);
+        assert_eq!(snapshot[0].state(), TaskState::Runnable);
+        assert_eq!(snapshot[0].spawn_frame(), 0);
+        assert_eq!(snapshot[0].poll_count(), 2);
+        assert_eq!(snapshot[0].last_polled_frame(), Some(1));
+
+        // 一度もwakeされないタスクは最初のpollからparkされたまま
+        assert_eq!(snapshot[1].id(), stuck.abort_handle().id());
+        assert_eq!(snapshot[1].name(), Some("stuck"));
+        assert_eq!(snapshot[1].state(), TaskState::Parked);
+        assert_eq!(snapshot[1].spawn_frame(), 1);
+        assert_eq!(snapshot[1].poll_count(), 1);
+        assert_eq!(snapshot[1].last_polled_frame(), Some(1));
+        assert_eq!(
+            snapshot[1].to_string(),
+            format!(
+                "task stuck (id {}) in PHASE Phase2: Parked, spawned at frame 1, polled 1 times, last at frame 1",
+                stuck.abort_handle().id()
+            )
+        );
+
+        // 完了したタスクは一覧から消える
+        stuck.abort_handle().abort();
+        runtime.update().unwrap();
+        assert_eq!(runtime.tasks_snapshot().len(), 1);
+    }
+
+    #[test]
+    fn deterministic_runs_with_same_seed_are_identical() {
+        // 乱数で値を反転してから足すシステム。コマンドの適用順が変わると結果が変わる
+        async fn random_system(mut rng: SeededRng, commands: Commands<TestWorld>) {
+            for _ in 0..5 {
+                let value = rng.gen_range(0..10);
+                commands.send(TestCommand::Mul(-1));
+                commands.send(TestCommand::Add(value as i32));
+                next_frame().await;
+            }
+        }
+
+        // 登録したシステムごとに違う値を足すシステムをPhase2に登録する
+        fn add_child_system(runtime: &Runtime<Phase, TestWorld>, i: u64) {
+            runtime.add_async_system(Phase::Phase2, move |_world, commands, runtime| {
+                let mut rng = runtime.rng();
+                async move {
+                    for _ in 0..3 {
+                        let value = rng.gen_range(0..10) + 10 * i;
+                        commands.send(TestCommand::Add(value as i32));
+                        commands.send(TestCommand::Div(2));
+                        next_frame().await;
+                    }
+                }
+            });
+        }
+
+        fn simulate(seed: u64, worker_threads: Option<usize>) -> Vec<i32> {
+            let mut builder = RuntimeBuilder::new()
+                .deterministic()
+                .rng(SeededRng::new(seed));
+            if let Some(n) = worker_threads {
+                builder = builder.worker_threads(n);
+            }
+            let mut runtime = builder.build(TestWorld { value: 0 });
+            runtime.activate_phase(Phase::Phase1, []).unwrap();
+            runtime
+                .activate_phase(Phase::Phase2, [after(Phase::Phase1)])
+                .unwrap();
+
+            // 入力のスクリプトを1フレームに1つずつ送るシステム
+            runtime.add_async_system(Phase::Phase1, |_world, commands, _runtime| async move {
+                for cmd in [
+                    TestCommand::Mul(2),
+                    TestCommand::Add(-3),
+                    TestCommand::Mul(3),
+                ] {
+                    commands.send(cmd);
+                    next_frame().await;
+                }
+            });
+            for i in 0..3 {
+                runtime.add_system(Phase::Phase1, random_system).unwrap();
+                // poll中に、乱数を使うシステムをPhase2に登録するシステム。
+                // 先に登録したものほど長く待つので、ワーカースレッドでは後のものが先に登録する
+                runtime.add_async_system(
+                    Phase::Phase1,
+                    move |_world, _commands, runtime| async move {
+                        for _ in 0..2 {
+                            std::thread::sleep(std::time::Duration::from_millis(2 * (3 - i)));
+                            add_child_system(&runtime, i);
+                            next_frame().await;
+                        }
+                    },
+                );
+            }
+            let values = record_values(&runtime, Phase::Phase2, 8);
+            run(&mut runtime);
+            futures::executor::block_on(values).unwrap()
+        }
+
+        // 同じシードなら、ワーカースレッドで並列にpollしても
+        // 1つのスレッドで登録順にpollしたときとフレームごとのWorldの状態が一致する
+        let values = simulate(42, None);
+        assert_eq!(values.len(), 8);
+        for _ in 0..3 {
+            assert_eq!(simulate(42, Some(4)), values);
+        }
+        assert_ne!(simulate(7, Some(4)), values);
+    }
+
+    #[cfg(feature = "profiler")]
+    #[test]
+    fn profiler_records_spans_and_phase_stats() {
+        let mut runtime = RuntimeBuilder::new()
+            .worker_threads(2)
+            .thread_name("profiled")
+            .build(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime.activate_phase(Phase::Phase2, []).unwrap();
+
+        runtime.spawn_named(Phase::Phase1, "counter", async {
+            for _ in 0..3 {
+                next_frame().await;
+            }
+        });
+        runtime.add_async_system(Phase::Phase2, |_world, commands, _runtime| async move {
+            commands.send(TestCommand::Add(1));
+        });
+        run(&mut runtime);
+
+        let stats = runtime.phase_stats(&Phase::Phase1).unwrap();
+        assert_eq!(stats.samples, 4);
+        assert!(stats.avg <= stats.p99 && stats.p99 <= stats.max);
+        assert!(runtime.phase_stats(&Phase::Phase2).is_some());
+
+        let mut trace = vec![];
+        runtime.profiler().write_chrome_trace(&mut trace).unwrap();
+        let trace = String::from_utf8(trace).unwrap();
+        assert!(trace.starts_with("{\"traceEvents\":["));
+        assert!(trace.contains("\"name\":\"Phase1\",\"cat\":\"phase\""));
+        assert!(trace.contains("\"name\":\"counter\",\"cat\":\"task\""));
+        assert!(trace.contains("\"cat\":\"worker_wait\""));
+        assert!(trace.contains("\"name\":\"commands after Phase2\",\"cat\":\"command\""));
+        assert!(
+            trace.contains("\"args\":{\"name\":\"profiled-0\"}")
+                || trace.contains("\"args\":{\"name\":\"profiled-1\"}")
+        );
+
+        // 区間は直近のフレームの分だけ残る
+        let frames = |runtime: &Runtime<Phase, TestWorld>| {
+            let mut trace = vec![];
+            runtime.profiler().write_chrome_trace(&mut trace).unwrap();
+            String::from_utf8(trace)
+                .unwrap()
+                .matches("\"cat\":\"frame\"")
+                .count()
+        };
+        assert_eq!(frames(&runtime), 4);
+        runtime.profiler().set_trace_frames(2);
+        assert_eq!(frames(&runtime), 2);
+        for _ in 0..3 {
+            runtime.update().unwrap();
+        }
+        assert_eq!(frames(&runtime), 2);
+
+        // 記録を止めると区間も統計も増えない
+        runtime.profiler().clear();
+        runtime.profiler().set_enabled(false);
+        runtime.update().unwrap();
+        assert!(runtime.phase_stats(&Phase::Phase1).is_none());
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn task_events_are_attributed_to_task_and_frame() {
+        use std::sync::Mutex;
+
+        #[derive(Clone)]
+        struct Buffer(Arc<Mutex<Vec<u8>>>);
+        impl std::io::Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buffer = Buffer(Arc::new(Mutex::new(vec![])));
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::NEW)
+            .with_max_level(tracing::Level::DEBUG)
+            .finish();
+
+        // デフォルトのsubscriberはスレッドごとなので、呼び出し元のスレッドでタスクを実行する
+        tracing::subscriber::with_default(subscriber, || {
+            let mut runtime = RuntimeBuilder::new()
+                .single_threaded()
+                .build(TestWorld { value: 0 });
+            runtime.activate_phase(Phase::Phase1, []).unwrap();
+            runtime.spawn_named(Phase::Phase1, "logger", async {
+                next_frame().await;
+                tracing::info!("hello from task");
+            });
+            runtime.add_async_system(Phase::Phase1, |_world, commands, _runtime| async move {
+                commands.send(TestCommand::Add(1));
+            });
+            run(&mut runtime);
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line = output
+            .lines()
+            .find(|line| line.contains("hello from task"))
+            .unwrap();
+        assert!(line.contains("spawn_frame=0 name=\"logger\"}:poll{frame=1}:"));
+        assert!(output.contains(
+            "update{frame=0}:phase{phase=Phase1}:process_commands{count=1 phase=Some(Phase1)}: "
+        ));
+    }
+
+    #[test]
+    fn restore_reruns_async_system_factories() {
+        use std::sync::Mutex;
+
+        let mut runtime = Runtime::new(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+
+        // 始めたときのWorldの値を記録して、毎フレーム1を足すシステム
+        let starts = Arc::new(Mutex::new(vec![]));
+        let log = Arc::clone(&starts);
+        runtime.add_async_system(Phase::Phase1, move |world, commands, _runtime| {
+            let log = Arc::clone(&log);
+            async move {
+                log.lock().unwrap().push(world.with(|world| world.value));
+                loop {
+                    commands.send(TestCommand::Add(1));
+                    next_frame().await;
+                }
+            }
+        });
+
+        for _ in 0..2 {
+            runtime.update().unwrap();
+        }
+        let snapshot = runtime.snapshot();
+        assert_eq!(snapshot.system_count(), 1);
+        for _ in 0..3 {
+            runtime.update().unwrap();
+        }
+        assert_eq!(*snapshot.world(), 2);
+        assert_eq!(runtime.snapshot().world(), &5);
+
+        // 復元すると、システムは戻したWorldから始め直す
+        runtime.restore(&snapshot);
+        runtime.update().unwrap();
+        assert_eq!(*starts.lock().unwrap(), [0, 2]);
+        assert_eq!(runtime.snapshot().world(), &3);
+        assert_eq!(runtime.tasks_snapshot().len(), 1);
+    }
+
+    #[test]
+    fn restore_rebuilds_systems_from_snapshot() {
+        use std::sync::Mutex;
+
+        async fn add_random(mut rng: SeededRng, commands: Commands<TestWorld>) {
+            loop {
+                commands.send(TestCommand::Add(rng.gen_range(0..10) as i32));
+                next_frame().await;
+            }
+        }
+
+        async fn log_values(
+            world: Read<TestWorld>,
+            runtime: Runtime<Phase, TestWorld>,
+            log: Res<Mutex<Vec<(u64, i32)>>>,
+        ) {
+            loop {
+                log.lock()
+                    .unwrap()
+                    .push((runtime.frame_counter(), world.with(|world| world.value)));
+                next_frame().await;
+            }
+        }
+
+        let mut runtime = RuntimeBuilder::new()
+            .worker_threads(2)
+            .rng(SeededRng::new(1))
+            .build(TestWorld { value: 0 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime
+            .activate_phase(Phase::Phase2, [after(Phase::Phase1)])
+            .unwrap();
+        runtime.insert_resource(Mutex::new(Vec::<(u64, i32)>::new()));
+        runtime.add_system(Phase::Phase1, add_random).unwrap();
+        runtime.add_system(Phase::Phase2, log_values).unwrap();
+        let log = runtime.resource::<Mutex<Vec<(u64, i32)>>>().unwrap();
+
+        for _ in 0..2 {
+            runtime.update().unwrap();
+        }
+        let snapshot = runtime.snapshot();
+        assert_eq!(snapshot.frame_counter(), 2);
+        assert_eq!(snapshot.system_count(), 2);
+
+        // 保存した後に起動したタスクは復元すると破棄される
+        let spawned = runtime.spawn(Phase::Phase1, async {
+            loop {
+                next_frame().await;
+            }
+        });
+        let run_frames = |runtime: &mut Runtime<Phase, TestWorld>| {
+            log.lock().unwrap().clear();
+            for _ in 0..3 {
+                runtime.update().unwrap();
+            }
+            std::mem::take(&mut *log.lock().unwrap())
+        };
+        let first = run_frames(&mut runtime);
+
+        runtime.restore(&snapshot);
+        assert_eq!(runtime.frame_counter(), 2);
+        assert_eq!(runtime.snapshot().world(), snapshot.world());
+        let err = futures::executor::block_on(spawned).unwrap_err();
+        assert!(err.is_cancelled());
+
+        // システムは戻したWorldと乱数生成器から始め直すので、何度戻しても同じように進む
+        let second = run_frames(&mut runtime);
+        runtime.restore(&snapshot);
+        let third = run_frames(&mut runtime);
+        assert_eq!(first.len(), 3);
+        assert_eq!(first[0].0, 2);
+        assert_eq!(
+            second.iter().map(|(frame, _)| *frame).collect::<Vec<_>>(),
+            [2, 3, 4]
+        );
+        assert_eq!(second, third);
+        assert_eq!(runtime.tasks_snapshot().len(), 2);
+    }
+
+    #[test]
+    fn rollback_sessions_converge_over_laggy_transport() {
+        // 前のフレームの値によって結果が変わるように、毎フレーム値を3で割るシステム
+        async fn divide(commands: Commands<TestWorld>) {
+            loop {
+                commands.send(TestCommand::Div(3));
+                next_frame().await;
+            }
+        }
+
+        fn new_session(
+            transport: LoopbackTransport<i32>,
+            local_player: usize,
+        ) -> RollbackSession<Phase, TestWorld, i32, LoopbackTransport<i32>> {
+            let runtime = RuntimeBuilder::new()
+                .deterministic()
+                .build(TestWorld { value: 0 });
+            runtime.activate_phase(Phase::Phase1, []).unwrap();
+            runtime.add_system(Phase::Phase1, divide).unwrap();
+            RollbackSession::new(
+                runtime,
+                transport,
+                local_player,
+                |commands, player, input| {
+                    // プレイヤーごとに違う重みで足す
+                    let weight = if player == 0 { 1 } else { 100 };
+                    commands.send(TestCommand::Add(input * weight));
+                },
+            )
+        }
+
+        let inputs = |player: usize, frame: usize| ((frame * 7 + player * 3) % 5) as i32;
+
+        let (a, b) = LoopbackTransport::pair(3);
+        let mut sessions = [new_session(a, 0), new_session(b, 1)];
+        for frame in 0..20 {
+            for (player, session) in sessions.iter_mut().enumerate() {
+                match session.advance(inputs(player, frame)).unwrap() {
+                    RollbackStatus::Advanced(_) => (),
+                    RollbackStatus::WaitingForRemote => panic!("latency is within max_rollback"),
+                }
+            }
+        }
+        // 相手の入力がすべて届くまで待つ
+        while !sessions.iter().all(RollbackSession::is_synchronized) {
+            for session in sessions.iter_mut() {
+                session.poll_remote().unwrap();
+            }
+        }
+
+        // 遅延なしで両方の入力を適用した場合と同じ結果になる
+        let mut expected = 0;
+        for frame in 0..20 {
+            expected += inputs(0, frame) + inputs(1, frame) * 100;
+            expected /= 3;
+        }
+        for session in sessions.iter() {
+            assert_eq!(session.runtime().frame_counter(), 20);
+            assert_eq!(*session.runtime().snapshot().world(), expected);
+            assert!(session.rollbacks() > 0);
+        }
+    }
+
+    // 記録したコマンドを書き込んで、後から読めるようにするバッファ。
+    #[cfg(feature = "replay")]
+    #[derive(Clone)]
+    struct Buffer(Arc<std::sync::Mutex<Vec<u8>>>);
+    #[cfg(feature = "replay")]
+    impl std::io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "replay")]
+    #[test]
+    fn replay_applies_recorded_commands_without_input_systems() {
+        let new_runtime = || {
+            let runtime = RuntimeBuilder::new()
+                .worker_threads(2)
+                .build(TestWorld { value: 1 });
+            runtime.activate_phase(Phase::Phase1, []).unwrap();
+            runtime
+                .activate_phase(Phase::Phase2, [after(Phase::Phase1)])
+                .unwrap();
+            runtime
+        };
+
+        // 乱数と入力のスクリプトでコマンドを送るランタイムを記録する
+        let buffer = Buffer(Arc::new(std::sync::Mutex::new(vec![])));
+        let mut runtime = new_runtime();
+        runtime.record_commands(buffer.clone()).unwrap();
+        runtime.add_async_system(Phase::Phase1, |_world, commands, _runtime| async move {
+            for cmd in [
+                TestCommand::Add(2),
+                TestCommand::Div(0),
+                TestCommand::Mul(3),
+                TestCommand::Add(-1),
+            ] {
+                commands.send(cmd);
+                next_frame().await;
+            }
+        });
+        runtime
+            .add_system(
+                Phase::Phase1,
+                |mut rng: SeededRng, commands: Commands<TestWorld>| async move {
+                    for _ in 0..4 {
+                        commands.send(TestCommand::Add(rng.gen_range(0..100) as i32));
+                        next_frame().await;
+                    }
+                },
+            )
+            .unwrap();
+        let recorded = record_values(&runtime, Phase::Phase2, 5);
+        run(&mut runtime);
+        runtime.stop_recording().unwrap();
+        let recorded = futures::executor::block_on(recorded).unwrap();
+        assert_eq!(runtime.command_errors().len(), 1);
+
+        // 入力のシステムを登録せずに再生しても、フレームごとのWorldの状態が一致する
+        let bytes = buffer.0.lock().unwrap().clone();
+        let mut replay = ReplayRuntime::new(new_runtime(), &bytes[..]).unwrap();
+        let replayed = record_values(replay.runtime(), Phase::Phase2, 5);
+        replay.run().unwrap();
+        assert!(replay.is_finished());
+        assert_eq!(futures::executor::block_on(replayed).unwrap(), recorded);
+        // 拒否されたコマンドも記録されていて、再生でも拒否される
+        assert_eq!(replay.runtime().command_errors().len(), 1);
+
+        // ヘッダーが違う記録は読み込まない
+        let err = ReplayRuntime::new(new_runtime(), &b"nope"[..])
+            .err()
+            .unwrap();
+        assert!(matches!(err, ReplayError::InvalidHeader));
+        let mut future_version = bytes;
+        future_version[4..6].copy_from_slice(&(REPLAY_FORMAT_VERSION + 1).to_le_bytes());
+        let err = ReplayRuntime::new(new_runtime(), &future_version[..])
+            .err()
+            .unwrap();
+        assert!(
+            matches!(err, ReplayError::UnsupportedVersion(v) if v == REPLAY_FORMAT_VERSION + 1)
+        );
+    }
+
+    #[cfg(feature = "replay")]
+    #[test]
+    fn replay_reports_desync_when_recorded_phase_is_missing() {
+        // Phase2の後に適用したコマンドを記録する
+        let buffer = Buffer(Arc::new(std::sync::Mutex::new(vec![])));
+        let mut runtime = Runtime::new(TestWorld { value: 1 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        runtime
+            .activate_phase(Phase::Phase2, [after(Phase::Phase1)])
+            .unwrap();
+        runtime.record_commands(buffer.clone()).unwrap();
+        runtime.add_async_system(Phase::Phase2, |_world, commands, _runtime| async move {
+            commands.send(TestCommand::Add(1));
+            next_frame().await;
+            commands.send(TestCommand::Add(2));
+        });
+        run(&mut runtime);
+        runtime.stop_recording().unwrap();
+
+        // Phase2をActivateせずに再生すると、記録したコマンドを適用できずにエラーで止まる
+        let runtime = Runtime::new(TestWorld { value: 1 });
+        runtime.activate_phase(Phase::Phase1, []).unwrap();
+        let bytes = buffer.0.lock().unwrap().clone();
+        let mut replay = ReplayRuntime::new(runtime, &bytes[..]).unwrap();
+        let Err(UpdateError::ReplayDesync(desync)) = replay.run() else {
+            panic!("replay should report desync");
+        };
+        assert_eq!(desync.frame(), 0);
+        assert_eq!(desync.recorded_frame(), 0);
+        assert_eq!(desync.recorded_phase(), Some(&Phase::Phase2));
+        // 残りの記録は捨てられて、再生は終わる
+        assert!(replay.is_finished());
+        replay.run().unwrap();
+    }
+
+    #[test]
+    fn test_runtime_steps_frames_and_injects_commands() {
+        async fn add_one(commands: Commands<TestWorld>) {
+            loop {
+                commands.send(TestCommand::Add(1));
+                next_frame().await;
+            }
+        }
+
+        let mut runtime = TestRuntime::<Phase, _>::with_world(TestWorld { value: 0 });
+        runtime.runtime().activate_phase(Phase::Phase1, []).unwrap();
+        runtime
+            .runtime()
+            .add_system(Phase::Phase1, add_one)
+            .unwrap();
+
+        runtime.step().unwrap();
+        assert_eq!(runtime.world().value, 1);
+        runtime.step_frames(4).unwrap();
+        assert_eq!(runtime.world().value, 5);
+
+        // テストから送ったコマンドは次のフレームの最初に適用される
+        runtime.send(TestCommand::Mul(10));
+        runtime.step().unwrap();
+        assert_eq!(runtime.world().value, 51);
+
+        assert_eq!(
+            runtime.run_until(100, |world| world.value >= 60).unwrap(),
+            9
+        );
+        assert_eq!(runtime.frames(), 15);
+        match runtime.run_until(3, |world| world.value < 0) {
+            Err(RunUntilError::FrameLimit(3)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -1,5 +1,6 @@
+use std::convert::Infallible;
+
 use runtime_v6::*;
-use std::sync::mpsc::Sender;
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 enum Phase {
@@ -17,38 +18,36 @@ struct MyWorld {
 }
 impl World for MyWorld {
     type Command = MyWorldCommand;
-    fn process_command(&mut self, cmd: Self::Command) {
+    type Error = Infallible;
+    fn process_command(&mut self, cmd: Self::Command) -> Result<(), Self::Error> {
         match cmd {
             MyWorldCommand::Add(f) => self.field += f,
             MyWorldCommand::Sub(f) => self.field -= f,
         }
+        Ok(())
     }
 }
 
-async fn hey(
-    world: Read<MyWorld>,
-    sender: Sender<MyWorldCommand>,
-    _runtime: Runtime<Phase, MyWorld>,
-) {
-    let f = world.field;
+async fn hey(world: Read<MyWorld>, commands: Commands<MyWorld>, _runtime: Runtime<Phase, MyWorld>) {
+    let f = world.with(|world| world.field);
     println!("{}", f);
 
-    sender.send(MyWorldCommand::Add(10.0)).unwrap();
+    commands.send(MyWorldCommand::Add(10.0));
     next_frame().await;
 
-    let f = world.field;
+    let f = world.with(|world| world.field);
     println!("{}", f);
 
-    sender.send(MyWorldCommand::Sub(10.0)).unwrap();
+    commands.send(MyWorldCommand::Sub(10.0));
     next_frame().await;
 
-    let f = world.field;
+    let f = world.with(|world| world.field);
     println!("{}", f);
 }
 
 async fn update2(
     _world: Read<MyWorld>,
-    _sender: Sender<MyWorldCommand>,
+    _commands: Commands<MyWorld>,
     _runtime: Runtime<Phase, MyWorld>,
 ) {
     for i in 0..5 {
@@ -59,7 +58,7 @@ async fn update2(
 
 async fn update(
     _world: Read<MyWorld>,
-    _sender: Sender<MyWorldCommand>,
+    _commands: Commands<MyWorld>,
     runtime: Runtime<Phase, MyWorld>,
 ) {
     println!("update: {}", 0);
@@ -81,14 +80,16 @@ fn main() {
 
     let mut runtime = Runtime::<Phase, MyWorld>::new(world);
 
-    runtime.activate_phase(Phase::Input, 0);
-    runtime.activate_phase(Phase::Update, 10);
+    runtime.activate_phase(Phase::Input, []).unwrap();
+    runtime
+        .activate_phase(Phase::Update, [after(Phase::Input)])
+        .unwrap();
 
     runtime.add_async_system(Phase::Input, hey);
     runtime.add_async_system(Phase::Update, update);
 
     'update_loop: loop {
-        match runtime.update() {
+        match runtime.update().unwrap() {
             RuntimeIsDone::Done => break 'update_loop,
             RuntimeIsDone::NotDone => (),
         }
@@ -0,0 +1,59 @@
+use std::time::Duration;
+
+/// [`Runtime::run_with`]でフレームを進める間隔。
+///
+/// [`Runtime::run_with`]: crate::Runtime::run_with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramePacing {
+    /// 指定したFPSを上限にフレームを進める。
+    /// フレームが早く終わった場合は残りの時間sleepする。
+    Fixed(u32),
+    /// sleepせずに続けてフレームを進める。
+    Uncapped,
+    /// 固定Phaseを1秒間にupdate_hz回実行する。
+    ///
+    /// 固定Phaseは経過したゲーム時間に応じて1フレームに0回以上実行され、
+    /// それ以外のPhaseは1フレームに1回実行される。
+    /// フレームが遅れた場合でも、1フレームで実行する回数はmax_catchup回までに制限される。
+    FixedTimestep { update_hz: u32, max_catchup: u32 },
+}
+
+/// 固定タイムステップの経過時間を積算する。
+pub(crate) struct FixedTimestep {
+    step: Duration,
+    max_catchup: u32,
+    accumulator: Duration,
+}
+impl FixedTimestep {
+    pub(crate) fn new(update_hz: u32, max_catchup: u32) -> Self {
+        assert!(update_hz > 0, "update_hz must be greater than 0");
+        assert!(max_catchup > 0, "max_catchup must be greater than 0");
+        Self {
+            step: Duration::from_secs(1) / update_hz,
+            max_catchup,
+            accumulator: Duration::from_secs(0),
+        }
+    }
+
+    pub(crate) fn step(&self) -> Duration {
+        self.step
+    }
+
+    /// 経過時間を積算して、このフレームで固定Phaseを実行する回数を返す。
+    pub(crate) fn advance(&mut self, delta: Duration) -> u32 {
+        self.accumulator += delta;
+        let mut steps = 0;
+        while self.accumulator >= self.step {
+            self.accumulator -= self.step;
+            steps += 1;
+        }
+
+        // 追いつけない分は捨てて、1フレームにかかる時間が伸び続けないようにする
+        steps.min(self.max_catchup)
+    }
+
+    /// 次の固定Phaseの実行までに必要な時間を返す。
+    pub(crate) fn until_next_step(&self) -> Duration {
+        self.step - self.accumulator
+    }
+}
@@ -0,0 +1,206 @@
+use std::fmt::{self, Debug};
+
+/// Phaseの実行順序の制約。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PhaseConstraint<T> {
+    /// 指定したPhaseより前に実行する。
+    Before(T),
+    /// 指定したPhaseより後に実行する。
+    After(T),
+}
+
+/// 指定したPhaseより前に実行する制約を返す関数。
+pub fn before<T>(phase: T) -> PhaseConstraint<T> {
+    PhaseConstraint::Before(phase)
+}
+
+/// 指定したPhaseより後に実行する制約を返す関数。
+pub fn after<T>(phase: T) -> PhaseConstraint<T> {
+    PhaseConstraint::After(phase)
+}
+
+/// Phaseの実行順序を決められなかったことを表すエラー。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PhaseGraphError<T> {
+    /// 既にActivateされているPhaseをActivateしようとした。
+    AlreadyActivated(T),
+    /// ActivateされていないPhaseの制約を変更しようとした。
+    NotActivated(T),
+    /// 制約が循環している。
+    /// 先頭のPhaseから順に実行する必要があり、最後のPhaseの次に先頭のPhaseを実行する必要がある。
+    Cycle(Vec<T>),
+}
+impl<T: Debug> fmt::Display for PhaseGraphError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PhaseGraphError::AlreadyActivated(phase) => {
+                write!(f, "PHASE has already been activated: {:?}", phase)
+            }
+            PhaseGraphError::NotActivated(phase) => {
+                write!(f, "PHASE is not activated: {:?}", phase)
+            }
+            PhaseGraphError::Cycle(phases) => {
+                write!(f, "PHASE constraints form a cycle: ")?;
+                for phase in phases.iter() {
+                    write!(f, "{:?} -> ", phase)?;
+                }
+                write!(f, "{:?}", phases[0])
+            }
+        }
+    }
+}
+impl<T: Debug> std::error::Error for PhaseGraphError<T> {}
+
+#[derive(Clone)]
+struct PhaseNode<T> {
+    phase: T,
+    constraints: Vec<PhaseConstraint<T>>,
+}
+
+/// ActivateされているPhaseと、その実行順序の制約。
+///
+/// 制約のないPhase同士はActivateされた順に実行される。
+/// ActivateされていないPhaseへの制約は、そのPhaseがActivateされるまで無視される。
+#[derive(Clone)]
+pub(crate) struct PhaseGraph<T> {
+    // Activateされた順に並んでいる
+    nodes: Vec<PhaseNode<T>>,
+    // 制約を満たすように並べたPhase
+    sorted: Vec<T>,
+}
+impl<T: PartialEq + Clone> PhaseGraph<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            nodes: vec![],
+            sorted: vec![],
+        }
+    }
+
+    /// 実行順に並んだPhaseを返す。
+    pub(crate) fn sorted(&self) -> &[T] {
+        &self.sorted
+    }
+
+    pub(crate) fn contains(&self, phase: &T) -> bool {
+        self.nodes.iter().any(|node| node.phase == *phase)
+    }
+
+    /// Phaseを追加する。
+    /// 制約を満たせない場合はグラフを変更せずにエラーを返す。
+    pub(crate) fn insert(
+        &mut self,
+        phase: T,
+        constraints: Vec<PhaseConstraint<T>>,
+    ) -> Result<(), PhaseGraphError<T>> {
+        if self.contains(&phase) {
+            return Err(PhaseGraphError::AlreadyActivated(phase));
+        }
+
+        self.nodes.push(PhaseNode { phase, constraints });
+        self.resort().inspect_err(|_| {
+            self.nodes.pop();
+        })
+    }
+
+    /// Phaseの制約を置き換える。
+    /// 制約を満たせない場合はグラフを変更せずにエラーを返す。
+    pub(crate) fn set_constraints(
+        &mut self,
+        phase: T,
+        constraints: Vec<PhaseConstraint<T>>,
+    ) -> Result<(), PhaseGraphError<T>> {
+        let index = match self.nodes.iter().position(|node| node.phase == phase) {
+            Some(index) => index,
+            None => return Err(PhaseGraphError::NotActivated(phase)),
+        };
+
+        let old = std::mem::replace(&mut self.nodes[index].constraints, constraints);
+        self.resort().inspect_err(|_| {
+            self.nodes[index].constraints = old;
+        })
+    }
+
+    /// Phaseを取り除く。Phaseが含まれていた場合はtrueを返す。
+    pub(crate) fn remove(&mut self, phase: &T) -> bool {
+        let len = self.nodes.len();
+        self.nodes.retain(|node| node.phase != *phase);
+        self.sorted.retain(|p| p != phase);
+        self.nodes.len() != len
+    }
+
+    // 制約からPhaseの実行順序を決め直す。
+    fn resort(&mut self) -> Result<(), PhaseGraphError<T>> {
+        let len = self.nodes.len();
+        let index_of = |phase: &T| self.nodes.iter().position(|node| node.phase == *phase);
+
+        // edges[i]にはiより後に実行するPhaseが入る
+        let mut edges = vec![vec![]; len];
+        let mut in_degree = vec![0; len];
+        for (i, node) in self.nodes.iter().enumerate() {
+            for constraint in node.constraints.iter() {
+                let (from, to) = match constraint {
+                    PhaseConstraint::Before(p) => match index_of(p) {
+                        Some(j) => (i, j),
+                        None => continue,
+                    },
+                    PhaseConstraint::After(p) => match index_of(p) {
+                        Some(j) => (j, i),
+                        None => continue,
+                    },
+                };
+                edges[from].push(to);
+                in_degree[to] += 1;
+            }
+        }
+
+        // 前に実行すべきPhaseが残っていないPhaseのうち、先にActivateされたものから並べる
+        let mut sorted = Vec::with_capacity(len);
+        let mut done = vec![false; len];
+        while let Some(i) = (0..len).find(|&i| !done[i] && in_degree[i] == 0) {
+            done[i] = true;
+            sorted.push(i);
+            for &j in edges[i].iter() {
+                in_degree[j] -= 1;
+            }
+        }
+
+        if sorted.len() < len {
+            return Err(PhaseGraphError::Cycle(self.find_cycle(&edges, &done)));
+        }
+
+        self.sorted = sorted
+            .into_iter()
+            .map(|i| self.nodes[i].phase.clone())
+            .collect();
+        Ok(())
+    }
+
+    // 並べられずに残ったPhaseから循環を1つ探す。
+    // 残ったPhaseには必ず残ったPhaseからの辺が入っているので、辺を逆に辿ればいずれ循環に戻る。
+    fn find_cycle(&self, edges: &[Vec<usize>], done: &[bool]) -> Vec<T> {
+        let predecessor = |j: usize| {
+            (0..edges.len())
+                .find(|&i| !done[i] && edges[i].contains(&j))
+                .unwrap()
+        };
+
+        let start = (0..done.len()).find(|&i| !done[i]).unwrap();
+        let mut path = vec![start];
+        let mut current = predecessor(start);
+        while !path.contains(&current) {
+            path.push(current);
+            current = predecessor(current);
+        }
+
+        // 逆に辿ったので実行順に並べ直し、最初にActivateされたPhaseから始まるようにする
+        let begin = path.iter().position(|&i| i == current).unwrap();
+        let mut cycle = path.split_off(begin);
+        cycle.reverse();
+        let first = (0..cycle.len()).min_by_key(|&i| cycle[i]).unwrap();
+        cycle.rotate_left(first);
+        cycle
+            .into_iter()
+            .map(|i| self.nodes[i].phase.clone())
+            .collect()
+    }
+}
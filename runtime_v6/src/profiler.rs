@@ -0,0 +1,334 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+/// 統計を計算するときに使う、Phaseごとの直近の実行時間の数のデフォルト。
+const DEFAULT_WINDOW: usize = 120;
+
+/// 区間を残しておく直近のフレーム数のデフォルト。
+const DEFAULT_TRACE_FRAMES: usize = 600;
+
+/// 計測した区間の種類。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SpanKind {
+    /// 1フレーム全体。
+    Frame,
+    /// 1つのPhaseの実行。
+    Phase,
+    /// ワーカースレッドがPhaseのタスクをpollし終えるのを待つ間。
+    WorkerWait,
+    /// 1つのタスクの1回のpoll。
+    TaskPoll,
+    /// コマンドの適用。
+    CommandFlush,
+}
+impl SpanKind {
+    fn category(self) -> &'static str {
+        match self {
+            SpanKind::Frame => "frame",
+            SpanKind::Phase => "phase",
+            SpanKind::WorkerWait => "worker_wait",
+            SpanKind::TaskPoll => "task",
+            SpanKind::CommandFlush => "command",
+        }
+    }
+}
+
+struct Span {
+    kind: SpanKind,
+    name: String,
+    frame: u64,
+    // 何番目に記録したフレームの区間か。古い区間を捨てるときに使う
+    recorded_frame: u64,
+    thread: ThreadId,
+    start: Duration,
+    duration: Duration,
+}
+
+/// Phaseの直近の実行時間の統計。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhaseStats {
+    /// 統計に使った実行回数。
+    pub samples: usize,
+    /// 実行時間の平均。
+    pub avg: Duration,
+    /// 実行時間の99パーセンタイル。
+    pub p99: Duration,
+    /// 実行時間の最大値。
+    pub max: Duration,
+}
+
+struct Records {
+    spans: VecDeque<Span>,
+    // 記録し終えたフレームの数
+    recorded_frames: u64,
+    trace_frames: usize,
+    // スレッドごとのChrome TraceのtidとスレッドのID
+    threads: Vec<(ThreadId, String)>,
+    // Phaseごとの直近の実行時間
+    phase_durations: HashMap<String, VecDeque<Duration>>,
+    window: usize,
+}
+
+/// [`Runtime::update`]の実時間を計測するプロファイラ。
+///
+/// Phaseの実行、タスクのpoll、コマンドの適用ごとに、
+/// どのフレームのどのスレッドでどれだけ時間がかかったかを記録する。
+/// 記録した区間は[`Profiler::write_chrome_trace`]でChrome Traceの形式で書き出せる。
+///
+/// メモリを使い続けないように、区間は直近のフレームの分だけ残す。
+/// 残すフレーム数は[`Profiler::set_trace_frames`]で設定する。
+///
+/// [`Runtime::update`]: crate::Runtime::update
+pub struct Profiler {
+    epoch: Instant,
+    enabled: AtomicBool,
+    records: Mutex<Records>,
+}
+impl Profiler {
+    pub(crate) fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            enabled: AtomicBool::new(true),
+            records: Mutex::new(Records {
+                spans: VecDeque::new(),
+                recorded_frames: 0,
+                trace_frames: DEFAULT_TRACE_FRAMES,
+                threads: vec![],
+                phase_durations: HashMap::new(),
+                window: DEFAULT_WINDOW,
+            }),
+        }
+    }
+
+    /// 記録するかどうかを設定する関数。デフォルトでは記録する。
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// 記録しているかどうかを返す関数。
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Phaseの統計に使う直近の実行回数を設定する関数。デフォルトは120回。
+    ///
+    /// ## panic
+    /// 0を指定した場合、panicする。
+    pub fn set_window(&self, window: usize) {
+        assert!(window > 0, "window must be greater than 0");
+        let mut records = self.records.lock().unwrap();
+        records.window = window;
+        for durations in records.phase_durations.values_mut() {
+            while durations.len() > window {
+                durations.pop_front();
+            }
+        }
+    }
+
+    /// 区間を残しておく直近のフレーム数を設定する関数。デフォルトは600フレーム。
+    /// これより古いフレームの区間は、新しいフレームを記録するときに捨てられる。
+    ///
+    /// ## panic
+    /// 0を指定した場合、panicする。
+    pub fn set_trace_frames(&self, frames: usize) {
+        assert!(frames > 0, "trace frames must be greater than 0");
+        let mut records = self.records.lock().unwrap();
+        records.trace_frames = frames;
+        records.discard_old_spans();
+    }
+
+    /// 記録した区間とPhaseの統計を破棄する関数。
+    pub fn clear(&self) {
+        let mut records = self.records.lock().unwrap();
+        records.spans.clear();
+        records.phase_durations.clear();
+    }
+
+    /// Phaseの直近の実行時間の統計を返す関数。
+    /// Phaseは`Debug`で表示した名前で指定する。
+    /// 一度も実行されていない場合はNoneを返す。
+    pub fn phase_stats(&self, phase: &str) -> Option<PhaseStats> {
+        let records = self.records.lock().unwrap();
+        let durations = records.phase_durations.get(phase)?;
+        if durations.is_empty() {
+            return None;
+        }
+
+        let mut sorted = durations.iter().copied().collect::<Vec<_>>();
+        sorted.sort();
+        let samples = sorted.len();
+        let total = sorted.iter().sum::<Duration>();
+        // 最も近い順位の値を99パーセンタイルとする
+        let rank = (samples * 99).div_ceil(100);
+        Some(PhaseStats {
+            samples,
+            avg: total / samples as u32,
+            p99: sorted[rank - 1],
+            max: sorted[samples - 1],
+        })
+    }
+
+    /// 記録した区間をChrome Trace Event形式のJSONで書き出す関数。
+    /// 書き出したファイルはchrome://tracingやPerfettoで開ける。
+    pub fn write_chrome_trace<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let records = self.records.lock().unwrap();
+
+        write!(writer, "{{\"traceEvents\":[")?;
+        let mut first = true;
+        for (tid, (_id, name)) in records.threads.iter().enumerate() {
+            if !first {
+                write!(writer, ",")?;
+            }
+            first = false;
+            write!(
+                writer,
+                "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":{},\"args\":{{\"name\":{}}}}}",
+                tid,
+                json_string(name)
+            )?;
+        }
+        for span in records.spans.iter() {
+            if !first {
+                write!(writer, ",")?;
+            }
+            first = false;
+            let tid = records
+                .threads
+                .iter()
+                .position(|(id, _name)| *id == span.thread)
+                .unwrap_or(0);
+            write!(
+                writer,
+                "{{\"name\":{},\"cat\":\"{}\",\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":1,\"tid\":{},\"args\":{{\"frame\":{}}}}}",
+                json_string(&span.name),
+                span.kind.category(),
+                micros(span.start),
+                micros(span.duration),
+                tid,
+                span.frame
+            )?;
+        }
+        write!(writer, "],\"displayTimeUnit\":\"ms\"}}")?;
+        writer.flush()
+    }
+
+    /// 区間の計測を始める。返り値をdropしたときに記録される。
+    /// 記録していない場合は名前を作らない。
+    pub(crate) fn span(
+        &self,
+        kind: SpanKind,
+        frame: u64,
+        name: impl FnOnce() -> String,
+    ) -> SpanGuard<'_> {
+        let name = if self.is_enabled() {
+            Some(name())
+        } else {
+            None
+        };
+        SpanGuard {
+            profiler: self,
+            kind,
+            name,
+            frame,
+            start: Instant::now(),
+        }
+    }
+
+    fn record(&self, kind: SpanKind, name: String, frame: u64, start: Instant) {
+        let duration = start.elapsed();
+        let current = thread::current();
+        let mut records = self.records.lock().unwrap();
+
+        if !records.threads.iter().any(|(id, _)| *id == current.id()) {
+            let thread_name = current
+                .name()
+                .map_or_else(|| format!("{:?}", current.id()), str::to_string);
+            records.threads.push((current.id(), thread_name));
+        }
+
+        if kind == SpanKind::Phase {
+            let window = records.window;
+            let durations = records.phase_durations.entry(name.clone()).or_default();
+            if durations.len() == window {
+                durations.pop_front();
+            }
+            durations.push_back(duration);
+        }
+
+        let recorded_frame = records.recorded_frames;
+        records.spans.push_back(Span {
+            kind,
+            name,
+            frame,
+            recorded_frame,
+            thread: current.id(),
+            start: start.duration_since(self.epoch),
+            duration,
+        });
+
+        // フレーム全体の区間はフレームの最後に記録される
+        if kind == SpanKind::Frame {
+            records.recorded_frames += 1;
+            records.discard_old_spans();
+        }
+    }
+}
+impl Records {
+    // 直近のtrace_framesフレームより前に記録した区間を捨てる。
+    fn discard_old_spans(&mut self) {
+        let oldest = self
+            .recorded_frames
+            .saturating_sub(self.trace_frames as u64);
+        while self
+            .spans
+            .front()
+            .is_some_and(|span| span.recorded_frame < oldest)
+        {
+            self.spans.pop_front();
+        }
+    }
+}
+
+/// [`Profiler::span`]で計測を始めた区間。
+pub(crate) struct SpanGuard<'a> {
+    profiler: &'a Profiler,
+    kind: SpanKind,
+    name: Option<String>,
+    frame: u64,
+    start: Instant,
+}
+impl Drop for SpanGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(name) = self.name.take() {
+            self.profiler
+                .record(self.kind, name, self.frame, self.start);
+        }
+    }
+}
+
+// Chrome Traceの時間はマイクロ秒で書く。
+fn micros(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1_000_000.0
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
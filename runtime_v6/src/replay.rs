@@ -0,0 +1,348 @@
+//! 適用したコマンドの記録と再生。
+//!
+//! 記録ファイルは4バイトのマジックナンバー`RTV6`と、リトルエンディアンのu16の形式のバージョンから始まる。
+//! その後に、コマンドを適用した時点ごとにフレーム、適用した時点、コマンドの列をpostcardで書いたものが続く。
+
+use std::collections::VecDeque;
+use std::fmt::{self, Debug, Display};
+use std::hash::Hash;
+use std::io::{self, Read, Write};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::commands::AppliedAt;
+use crate::pacing::FramePacing;
+use crate::runtime::{Runtime, RuntimeIsDone};
+use crate::update_error::UpdateError;
+use crate::world::World;
+
+/// 記録ファイルの先頭に書くマジックナンバー。
+const MAGIC: [u8; 4] = *b"RTV6";
+
+/// 記録ファイルの形式のバージョン。
+pub const REPLAY_FORMAT_VERSION: u16 = 1;
+
+// 1つの時点で適用したコマンドの列。
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Batch<T, C> {
+    frame: u64,
+    at: AppliedAt<T>,
+    commands: Vec<C>,
+}
+
+// 書き込むときはコマンドを複製しないように参照で書く。
+// postcardではVec<C>と&[C]は同じ形式になる。
+#[derive(Serialize)]
+struct BatchRef<'a, T, C> {
+    frame: u64,
+    at: &'a AppliedAt<T>,
+    commands: &'a [C],
+}
+
+// 記録したコマンドの書き込み先。
+pub(crate) trait CommandSink<T, C> {
+    fn write_batch(&mut self, frame: u64, at: &AppliedAt<T>, commands: &[C]) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+struct PostcardSink<Wr> {
+    writer: Wr,
+}
+impl<T: Serialize, C: Serialize, Wr: Write> CommandSink<T, C> for PostcardSink<Wr> {
+    fn write_batch(&mut self, frame: u64, at: &AppliedAt<T>, commands: &[C]) -> io::Result<()> {
+        let batch = BatchRef {
+            frame,
+            at,
+            commands,
+        };
+        let bytes = postcard::to_stdvec(&batch)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        self.writer.write_all(&bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Runtimeが記録や再生をしている状態。
+pub(crate) enum CommandLog<T, C> {
+    Off,
+    Recording {
+        sink: Box<dyn CommandSink<T, C> + Send>,
+        // 書き込みに失敗したら、それ以降は記録しない
+        error: Option<io::Error>,
+    },
+    Replaying(VecDeque<Batch<T, C>>),
+}
+impl<T: PartialEq, C> CommandLog<T, C> {
+    /// 記録を始める。ヘッダーを書いてから記録する状態にする。
+    pub(crate) fn start_recording<Wr>(&mut self, mut writer: Wr) -> io::Result<()>
+    where
+        T: Serialize,
+        C: Serialize,
+        Wr: Write + Send + 'static,
+    {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&REPLAY_FORMAT_VERSION.to_le_bytes())?;
+        *self = CommandLog::Recording {
+            sink: Box::new(PostcardSink { writer }),
+            error: None,
+        };
+        Ok(())
+    }
+
+    /// 記録を終える。書き込みに失敗していればそのエラーを返す。
+    pub(crate) fn stop_recording(&mut self) -> io::Result<()> {
+        match std::mem::replace(self, CommandLog::Off) {
+            CommandLog::Recording {
+                mut sink,
+                error: None,
+            } => sink.flush(),
+            CommandLog::Recording {
+                error: Some(error), ..
+            } => Err(error),
+            other => {
+                *self = other;
+                Ok(())
+            }
+        }
+    }
+
+    /// ある時点で適用するコマンドを受け取って、実際に適用するコマンドを返す。
+    ///
+    /// 記録中はコマンドを記録してそのまま返す。
+    /// 再生中は受け取ったコマンドを捨てて、記録されているその時点のコマンドを返す。
+    /// 記録されているコマンドがもう適用できない場合は、残りの記録を捨ててエラーを返す。
+    pub(crate) fn apply(
+        &mut self,
+        frame: u64,
+        at: AppliedAt<T>,
+        commands: Vec<C>,
+    ) -> Result<Vec<C>, ReplayDesync<T>> {
+        match self {
+            CommandLog::Off => Ok(commands),
+            CommandLog::Recording { sink, error } => {
+                if error.is_none() && !commands.is_empty() {
+                    if let Err(e) = sink.write_batch(frame, &at, &commands) {
+                        *error = Some(e);
+                    }
+                }
+                Ok(commands)
+            }
+            CommandLog::Replaying(batches) => {
+                let Some(batch) = batches.front() else {
+                    return Ok(vec![]);
+                };
+                if batch.frame == frame && batch.at == at {
+                    return Ok(batches.pop_front().unwrap().commands);
+                }
+                // 過ぎたフレームのコマンドと、フレームの終わりまでに適用されなかったコマンドは
+                // もう適用できない。PhaseのActivateや実行順が記録したときと違う
+                let missed =
+                    batch.frame < frame || (batch.frame == frame && at == AppliedAt::FrameEnd);
+                if !missed {
+                    return Ok(vec![]);
+                }
+                let batch = batches.pop_front().unwrap();
+                batches.clear();
+                Err(ReplayDesync {
+                    frame,
+                    recorded_frame: batch.frame,
+                    recorded_at: batch.at,
+                })
+            }
+        }
+    }
+
+    /// 再生していて、まだ適用していないコマンドが残っているかどうか。
+    pub(crate) fn is_replay_pending(&self) -> bool {
+        matches!(self, CommandLog::Replaying(batches) if !batches.is_empty())
+    }
+}
+
+/// 記録したコマンドを、記録したときと同じ時点で適用できなかったことを表すエラー。
+///
+/// 再生するRuntimeのPhaseのActivateや実行順が、記録したときと違う場合に起きる。
+/// エラーになった時点で残りの記録は捨てられる。
+#[derive(Debug)]
+pub struct ReplayDesync<T> {
+    frame: u64,
+    recorded_frame: u64,
+    recorded_at: AppliedAt<T>,
+}
+impl<T> ReplayDesync<T> {
+    /// 再生がずれたことに気づいたフレームを返す関数。
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// 適用できなかったコマンドを記録したフレームを返す関数。
+    pub fn recorded_frame(&self) -> u64 {
+        self.recorded_frame
+    }
+
+    /// 適用できなかったコマンドを記録したときに、コマンドを適用した後のPhaseを返す関数。
+    /// フレームの開始時や終わりに適用したコマンドの場合はNoneを返す。
+    pub fn recorded_phase(&self) -> Option<&T> {
+        self.recorded_at.phase()
+    }
+}
+impl<T: Debug> Display for ReplayDesync<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "replay desynced at frame {}: commands recorded at frame {} ",
+            self.frame, self.recorded_frame
+        )?;
+        match &self.recorded_at {
+            AppliedAt::FrameStart => write!(f, "at the start of the frame")?,
+            AppliedAt::AfterPhase(phase) => write!(f, "after {:?}", phase)?,
+            AppliedAt::FrameEnd => write!(f, "at the end of the frame")?,
+        }
+        write!(f, " could not be applied")
+    }
+}
+
+/// 記録したコマンドを読み込めなかったときのエラー。
+#[derive(Debug)]
+pub enum ReplayError {
+    /// 読み込みに失敗した。
+    Io(io::Error),
+    /// ファイルの先頭がマジックナンバーではない。
+    InvalidHeader,
+    /// 対応していない形式のバージョン。
+    UnsupportedVersion(u16),
+    /// コマンドの列を読み込めなかった。
+    Decode(postcard::Error),
+}
+impl Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io(error) => write!(f, "failed to read replay: {}", error),
+            ReplayError::InvalidHeader => write!(f, "not a replay file"),
+            ReplayError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported replay format version {} (expected {})",
+                version, REPLAY_FORMAT_VERSION
+            ),
+            ReplayError::Decode(error) => write!(f, "failed to decode replay: {}", error),
+        }
+    }
+}
+impl std::error::Error for ReplayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReplayError::Io(error) => Some(error),
+            ReplayError::Decode(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+impl From<io::Error> for ReplayError {
+    fn from(error: io::Error) -> Self {
+        ReplayError::Io(error)
+    }
+}
+
+fn read_batches<T, C>(mut reader: impl Read) -> Result<VecDeque<Batch<T, C>>, ReplayError>
+where
+    T: DeserializeOwned,
+    C: DeserializeOwned,
+{
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+
+    if bytes.len() < MAGIC.len() + 2 || bytes[..MAGIC.len()] != MAGIC {
+        return Err(ReplayError::InvalidHeader);
+    }
+    let version = u16::from_le_bytes([bytes[MAGIC.len()], bytes[MAGIC.len() + 1]]);
+    if version != REPLAY_FORMAT_VERSION {
+        return Err(ReplayError::UnsupportedVersion(version));
+    }
+
+    let mut rest = &bytes[MAGIC.len() + 2..];
+    let mut batches = VecDeque::new();
+    while !rest.is_empty() {
+        let (batch, tail) = postcard::take_from_bytes(rest).map_err(ReplayError::Decode)?;
+        batches.push_back(batch);
+        rest = tail;
+    }
+    Ok(batches)
+}
+
+/// [`Runtime::record_commands`]で記録したコマンドを再生するランタイム。
+///
+/// 記録したコマンドを、記録したときと同じフレームの同じ時点で[`World::process_command`]に渡す。
+/// 再生中にシステムが送信したコマンドは適用されずに捨てられる。
+/// そのため、入力を読んでコマンドを送るシステムは登録しなくてよい。
+///
+/// [`Runtime::record_commands`]: crate::Runtime::record_commands
+pub struct ReplayRuntime<T: Eq + Hash + Clone + Debug, W: World> {
+    runtime: Runtime<T, W>,
+}
+impl<T, W> ReplayRuntime<T, W>
+where
+    T: Eq + Hash + Clone + Debug + DeserializeOwned + 'static,
+    W: World,
+    W::Command: DeserializeOwned,
+{
+    /// readerから記録を読み込んで、runtimeで再生するReplayRuntimeを作る関数。
+    /// runtimeは記録したときと同じ初期状態のWorldで作る。
+    pub fn new<R: Read>(runtime: Runtime<T, W>, reader: R) -> Result<Self, ReplayError> {
+        let batches = read_batches(reader)?;
+        *runtime.command_log().lock().unwrap() = CommandLog::Replaying(batches);
+        Ok(Self { runtime })
+    }
+}
+impl<T: Eq + Hash + Clone + Debug + 'static, W: World> ReplayRuntime<T, W> {
+    /// 再生に使っているRuntimeを返す関数。
+    /// システムの登録やPhaseのActivateに使う。
+    pub fn runtime(&self) -> &Runtime<T, W> {
+        &self.runtime
+    }
+
+    /// 再生に使っているRuntimeを返す関数。
+    pub fn runtime_mut(&mut self) -> &mut Runtime<T, W> {
+        &mut self.runtime
+    }
+
+    /// 記録したコマンドをすべて適用し終えたかどうかを返す関数。
+    pub fn is_finished(&self) -> bool {
+        !self
+            .runtime
+            .command_log()
+            .lock()
+            .unwrap()
+            .is_replay_pending()
+    }
+
+    /// 1フレーム分再生する関数。
+    ///
+    /// 記録したコマンドが残っている間は、タスクがすべて終わっていてもフレームを進める。
+    pub fn update(&mut self) -> Result<RuntimeIsDone, UpdateError<T, W::Error>> {
+        self.runtime.update()
+    }
+
+    /// 記録したコマンドをすべて適用し終えて、タスクがすべて終了するまで再生する関数。
+    ///
+    /// 記録したコマンドを記録したときと同じ時点で適用できなかった場合は、
+    /// [`UpdateError::ReplayDesync`]を返して止まる。
+    pub fn run(&mut self) -> Result<(), UpdateError<T, W::Error>> {
+        loop {
+            if let RuntimeIsDone::Done = self.update()? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// 記録したコマンドをすべて適用し終えて、タスクがすべて終了するまで、
+    /// pacingに従ってフレームを進めながら再生する関数。
+    ///
+    /// ## panic
+    /// FPSやupdate_hz、max_catchupに0を指定した場合、panicする。
+    pub fn run_with(&mut self, pacing: FramePacing) -> Result<(), UpdateError<T, W::Error>> {
+        self.runtime.run_with(pacing)
+    }
+}
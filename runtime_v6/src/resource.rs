@@ -0,0 +1,54 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// ランタイムに登録されたリソースへの参照。
+///
+/// リソースはWorldと違ってコマンドで変更されないので、いつでも参照できる。
+/// 変更が必要なリソースは`Mutex`などで包んで登録する。
+pub struct Res<R: ?Sized + 'static> {
+    resource: Arc<R>,
+}
+impl<R: ?Sized + 'static> Deref for Res<R> {
+    type Target = R;
+
+    fn deref(&self) -> &Self::Target {
+        &self.resource
+    }
+}
+impl<R: ?Sized + 'static> Clone for Res<R> {
+    fn clone(&self) -> Self {
+        Self {
+            resource: Arc::clone(&self.resource),
+        }
+    }
+}
+
+/// ランタイムに登録されたリソース。
+pub(crate) struct Resources {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+impl Resources {
+    pub(crate) fn new() -> Self {
+        Self {
+            map: HashMap::new(),
+        }
+    }
+
+    /// リソースを登録する。
+    /// 既に登録されている場合は置き換えるが、取り出し済みのResは古い値を指したままになる。
+    pub(crate) fn insert<R: Send + Sync + 'static>(&mut self, resource: R) {
+        self.map
+            .insert(TypeId::of::<R>(), Box::new(Arc::new(resource)));
+    }
+
+    pub(crate) fn get<R: Send + Sync + 'static>(&self) -> Option<Res<R>> {
+        self.map
+            .get(&TypeId::of::<R>())
+            .and_then(|r| r.downcast_ref::<Arc<R>>())
+            .map(|resource| Res {
+                resource: Arc::clone(resource),
+            })
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::ops::Range;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// シードから同じ乱数列を再現できる乱数生成器。
+///
+/// [`SeededRng::fork`]で元の乱数列とは独立した乱数生成器を作れる。
+/// [`Runtime::add_system`]で登録したシステムは引数として受け取ると、
+/// ランタイムの乱数生成器からforkしたものを受け取る。
+/// システムごとに別の乱数列になるので、タスクがpollされる順序に結果が左右されない。
+///
+/// `rand_core`フィーチャーを有効にすると`rand_core::RngCore`を実装する。
+///
+/// [`Runtime::add_system`]: crate::Runtime::add_system
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeededRng {
+    // xoshiro256**の状態
+    state: [u64; 4],
+}
+impl SeededRng {
+    /// シードから乱数生成器を作成する。
+    pub fn new(seed: u64) -> Self {
+        // 状態がすべて0にならないようにSplitMix64で広げる
+        let mut seed = seed;
+        let mut state = [0; 4];
+        for s in state.iter_mut() {
+            *s = split_mix64(&mut seed);
+        }
+        Self { state }
+    }
+
+    /// 実行ごとに異なるシードで乱数生成器を作成する。
+    pub fn from_entropy() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(nanos);
+        Self::new(hasher.finish())
+    }
+
+    /// この乱数生成器から独立した乱数生成器を作る関数。
+    /// forkするとこの乱数生成器の状態も進む。
+    pub fn fork(&mut self) -> Self {
+        Self::new(self.next_u64())
+    }
+
+    /// 64ビットの乱数を返す関数。
+    pub fn next_u64(&mut self) -> u64 {
+        let result = self.state[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = self.state[1] << 17;
+
+        self.state[2] ^= self.state[0];
+        self.state[3] ^= self.state[1];
+        self.state[1] ^= self.state[2];
+        self.state[0] ^= self.state[3];
+        self.state[2] ^= t;
+        self.state[3] = self.state[3].rotate_left(45);
+
+        result
+    }
+
+    /// 32ビットの乱数を返す関数。
+    pub fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    /// rangeの範囲の乱数を返す関数。
+    ///
+    /// ## panic
+    /// rangeが空の場合、panicする。
+    pub fn gen_range(&mut self, range: Range<u64>) -> u64 {
+        assert!(range.start < range.end, "range must not be empty");
+        let span = range.end - range.start;
+        // 偏りが出ないように、spanの倍数に収まらない値は捨てる
+        let zone = u64::MAX - (u64::MAX - span + 1) % span;
+        loop {
+            let value = self.next_u64();
+            if value <= zone {
+                return range.start + value % span;
+            }
+        }
+    }
+}
+
+fn split_mix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(feature = "rand_core")]
+impl rand_core::RngCore for SeededRng {
+    fn next_u32(&mut self) -> u32 {
+        SeededRng::next_u32(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        SeededRng::next_u64(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = SeededRng::next_u64(self).to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use crate::commands::{Commands, FlushPoint};
+use crate::runtime::{Runtime, RuntimeIsDone};
+use crate::snapshot::{RuntimeSnapshot, Snapshot};
+use crate::update_error::UpdateError;
+
+/// 予測したまま進めてよいフレームの数のデフォルト。
+const DEFAULT_MAX_ROLLBACK: usize = 8;
+
+/// [`Transport`]で送受信する、あるフレームのプレイヤーの入力。
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputMessage<I> {
+    /// 入力を使うフレーム。
+    pub frame: u64,
+    /// プレイヤーの入力。
+    pub input: I,
+}
+
+/// [`RollbackSession`]が相手のセッションと入力をやり取りする経路。
+pub trait Transport<I> {
+    /// 相手に入力を送る関数。
+    fn send(&mut self, message: InputMessage<I>);
+    /// 相手から届いた入力を受け取る関数。
+    /// [`RollbackSession::advance`]と[`RollbackSession::poll_remote`]から1回ずつ呼ばれる。
+    fn receive(&mut self) -> Vec<InputMessage<I>>;
+}
+
+// 片方向の経路。届く時刻は受け取る側がreceiveを呼んだ回数で数える。
+struct Link<I> {
+    received: u64,
+    queue: VecDeque<(u64, InputMessage<I>)>,
+}
+
+/// 同じプロセスの中の2つのセッションをつなぐ[`Transport`]。
+///
+/// 送った入力は、相手が`latency`回[`Transport::receive`]を呼んだ後に届く。
+/// ネットワークを使わずに遅延のある対戦を試すのに使う。
+pub struct LoopbackTransport<I> {
+    latency: u64,
+    outgoing: Arc<Mutex<Link<I>>>,
+    incoming: Arc<Mutex<Link<I>>>,
+}
+impl<I> LoopbackTransport<I> {
+    /// 互いにつながった2つのLoopbackTransportを作る関数。
+    pub fn pair(latency: u64) -> (Self, Self) {
+        let link = || {
+            Arc::new(Mutex::new(Link {
+                received: 0,
+                queue: VecDeque::new(),
+            }))
+        };
+        let (a_to_b, b_to_a) = (link(), link());
+        (
+            Self {
+                latency,
+                outgoing: Arc::clone(&a_to_b),
+                incoming: Arc::clone(&b_to_a),
+            },
+            Self {
+                latency,
+                outgoing: b_to_a,
+                incoming: a_to_b,
+            },
+        )
+    }
+
+    /// これ以降に送る入力の遅延を設定する関数。
+    pub fn set_latency(&mut self, latency: u64) {
+        self.latency = latency;
+    }
+}
+impl<I> Transport<I> for LoopbackTransport<I> {
+    fn send(&mut self, message: InputMessage<I>) {
+        let mut link = self.outgoing.lock().unwrap();
+        let arrival = link.received + self.latency;
+        link.queue.push_back((arrival, message));
+    }
+
+    fn receive(&mut self) -> Vec<InputMessage<I>> {
+        let mut link = self.incoming.lock().unwrap();
+        let mut messages = vec![];
+        while let Some((arrival, _)) = link.queue.front() {
+            if *arrival > link.received {
+                break;
+            }
+            messages.push(link.queue.pop_front().unwrap().1);
+        }
+        link.received += 1;
+        messages
+    }
+}
+
+/// [`RollbackSession::advance`]の結果。
+#[derive(Debug)]
+pub enum RollbackStatus {
+    /// 1フレーム進めた。
+    Advanced(RuntimeIsDone),
+    /// 予測したまま進めたフレームが多すぎるので、相手の入力が届くまで進めなかった。
+    WaitingForRemote,
+}
+
+// プレイヤーの番号と入力を受け取って、コマンドを送る関数。
+type ApplyInput<W, I> = Box<dyn Fn(&Commands<W>, usize, &I) + Send>;
+
+// シミュレーションしたフレーム。
+struct FrameRecord<T: Eq + Hash + Clone + Debug, W: Snapshot, I> {
+    frame: u64,
+    // このフレームを実行する前の状態
+    snapshot: RuntimeSnapshot<T, W>,
+    local: I,
+    remote: I,
+    // 相手の入力が届いているかどうか
+    confirmed: bool,
+}
+
+/// 相手の入力を予測して進め、予測が外れたら巻き戻してやり直す2人用のセッション。
+///
+/// 毎フレーム[`RollbackSession::advance`]に自分の入力を渡すと、[`Transport`]で相手に送り、
+/// 相手の入力がまだ届いていなければ最後に届いた入力が続くと予測してフレームを進める。
+/// 後から届いた入力が予測と違った場合は、そのフレームの前に保存した[`RuntimeSnapshot`]に戻し、
+/// 現在のフレームまでシミュレーションし直す。
+///
+/// 両方のプレイヤーの入力はプレイヤーの番号の順にapply_inputでコマンドにされ、
+/// フレームの最初のPhaseの前に適用される。
+///
+/// やり直したときに同じ結果になるように、ランタイムは決定的モードで作り、
+/// システムはフレームをまたぐ状態をWorldに持たせる。
+/// 巻き戻したときに[`Runtime::restore`]で登録し直されるのは[`Runtime::add_system`]と
+/// [`Runtime::add_async_system`]のシステムだけである。
+pub struct RollbackSession<T, W, I, Tr>
+where
+    T: Eq + Hash + Clone + Debug,
+    W: Snapshot,
+{
+    runtime: Runtime<T, W>,
+    transport: Tr,
+    local_player: usize,
+    apply_input: ApplyInput<W, I>,
+    commands: Commands<W>,
+    max_rollback: usize,
+    // 相手の入力が確定していないフレーム。古い順に並んでいる
+    history: VecDeque<FrameRecord<T, W, I>>,
+    // シミュレーションする前に届いた相手の入力
+    pending: BTreeMap<u64, I>,
+    // 届いた中で最も新しいフレームの相手の入力
+    last_remote: Option<InputMessage<I>>,
+    rollbacks: u64,
+    resimulated_frames: u64,
+}
+impl<T, W, I, Tr> RollbackSession<T, W, I, Tr>
+where
+    T: Eq + Hash + Clone + Debug + 'static,
+    W: Snapshot,
+    I: Clone + PartialEq + Default,
+    Tr: Transport<I>,
+{
+    /// セッションを作成する関数。
+    /// local_playerは自分のプレイヤーの番号で、相手のセッションとは別の0か1を指定する。
+    ///
+    /// apply_inputはプレイヤーの番号と入力を受け取って、入力をWorldへのコマンドにして送る。
+    ///
+    /// ## panic
+    /// local_playerに0と1以外を指定した場合、panicする。
+    pub fn new<F>(
+        runtime: Runtime<T, W>,
+        transport: Tr,
+        local_player: usize,
+        apply_input: F,
+    ) -> Self
+    where
+        F: Fn(&Commands<W>, usize, &I) + Send + 'static,
+    {
+        assert!(local_player < 2, "local_player must be 0 or 1");
+        let commands = runtime.new_commands().flush_at(FlushPoint::NextFrame);
+        Self {
+            runtime,
+            transport,
+            local_player,
+            apply_input: Box::new(apply_input),
+            commands,
+            max_rollback: DEFAULT_MAX_ROLLBACK,
+            history: VecDeque::new(),
+            pending: BTreeMap::new(),
+            last_remote: None,
+            rollbacks: 0,
+            resimulated_frames: 0,
+        }
+    }
+
+    /// 相手の入力が届かないまま進めてよいフレームの数を設定する関数。デフォルトは8フレーム。
+    ///
+    /// ## panic
+    /// 0を指定した場合、panicする。
+    pub fn set_max_rollback(&mut self, frames: usize) {
+        assert!(frames > 0, "max_rollback must be greater than 0");
+        self.max_rollback = frames;
+    }
+
+    /// セッションが進めているRuntimeを返す関数。
+    pub fn runtime(&self) -> &Runtime<T, W> {
+        &self.runtime
+    }
+
+    /// 自分のプレイヤーの番号を返す関数。
+    pub fn local_player(&self) -> usize {
+        self.local_player
+    }
+
+    /// 巻き戻した回数を返す関数。
+    pub fn rollbacks(&self) -> u64 {
+        self.rollbacks
+    }
+
+    /// 巻き戻してシミュレーションし直したフレームの数を返す関数。
+    pub fn resimulated_frames(&self) -> u64 {
+        self.resimulated_frames
+    }
+
+    /// シミュレーションしたすべてのフレームで相手の入力が届いているかどうかを返す関数。
+    pub fn is_synchronized(&self) -> bool {
+        self.history.is_empty()
+    }
+
+    /// 自分の入力を送り、相手の入力を受け取ってから1フレーム進める関数。
+    ///
+    /// 予測が外れていた場合は、先に巻き戻してこのフレームの前までシミュレーションし直す。
+    /// 相手の入力が確定していないフレームが[`RollbackSession::set_max_rollback`]で
+    /// 設定した数だけ溜まっている場合は、進めずに[`RollbackStatus::WaitingForRemote`]を返す。
+    pub fn advance(&mut self, local_input: I) -> Result<RollbackStatus, UpdateError<T, W::Error>> {
+        self.poll_remote()?;
+        if self.history.len() >= self.max_rollback {
+            return Ok(RollbackStatus::WaitingForRemote);
+        }
+
+        let frame = self.runtime.frame_counter();
+        self.transport.send(InputMessage {
+            frame,
+            input: local_input.clone(),
+        });
+        let (remote, confirmed) = match self.pending.remove(&frame) {
+            Some(remote) => (remote, true),
+            None => (self.predict(), false),
+        };
+        let done = self.simulate(frame, local_input, remote, confirmed)?;
+        Ok(RollbackStatus::Advanced(done))
+    }
+
+    /// フレームを進めずに相手の入力を受け取り、予測が外れていれば巻き戻してシミュレーションし直す関数。
+    pub fn poll_remote(&mut self) -> Result<(), UpdateError<T, W::Error>> {
+        let mut rollback_to = None;
+        for message in self.transport.receive() {
+            if let Some(frame) = self.receive_remote(message) {
+                rollback_to = Some(rollback_to.map_or(frame, |f: u64| f.min(frame)));
+            }
+        }
+        if let Some(frame) = rollback_to {
+            self.rollback(frame)?;
+        }
+        self.drop_confirmed();
+        Ok(())
+    }
+
+    // 届いた入力を記録する。予測が外れていた場合はそのフレームを返す。
+    fn receive_remote(&mut self, message: InputMessage<I>) -> Option<u64> {
+        if self
+            .last_remote
+            .as_ref()
+            .is_none_or(|last| message.frame >= last.frame)
+        {
+            self.last_remote = Some(message.clone());
+        }
+
+        match self.history.iter_mut().find(|r| r.frame == message.frame) {
+            Some(record) => {
+                record.confirmed = true;
+                if record.remote != message.input {
+                    record.remote = message.input;
+                    return Some(message.frame);
+                }
+            }
+            None => {
+                if message.frame >= self.runtime.frame_counter() {
+                    self.pending.insert(message.frame, message.input);
+                }
+            }
+        }
+        None
+    }
+
+    // 相手の入力は最後に届いた入力が続くと予測する。
+    fn predict(&self) -> I {
+        self.last_remote
+            .as_ref()
+            .map(|last| last.input.clone())
+            .unwrap_or_default()
+    }
+
+    // frameの前に戻して、現在のフレームまでシミュレーションし直す。
+    fn rollback(&mut self, frame: u64) -> Result<(), UpdateError<T, W::Error>> {
+        let start = self
+            .history
+            .iter()
+            .position(|r| r.frame == frame)
+            .expect("rollback target must be in history");
+        let records = self.history.drain(start..).collect::<Vec<_>>();
+        self.runtime.restore(&records[0].snapshot);
+        self.rollbacks += 1;
+
+        for record in records {
+            // 確定していないフレームは新しく届いた入力で予測し直す
+            let remote = if record.confirmed {
+                record.remote
+            } else {
+                self.predict()
+            };
+            self.simulate(record.frame, record.local, remote, record.confirmed)?;
+            self.resimulated_frames += 1;
+        }
+        Ok(())
+    }
+
+    // 状態を保存してから、両方のプレイヤーの入力で1フレーム進める。
+    fn simulate(
+        &mut self,
+        frame: u64,
+        local: I,
+        remote: I,
+        confirmed: bool,
+    ) -> Result<RuntimeIsDone, UpdateError<T, W::Error>> {
+        let snapshot = self.runtime.snapshot();
+
+        // どちらのセッションでもプレイヤーの番号の順に適用する
+        let inputs = if self.local_player == 0 {
+            [&local, &remote]
+        } else {
+            [&remote, &local]
+        };
+        for (player, input) in inputs.iter().enumerate() {
+            (self.apply_input)(&self.commands, player, input);
+        }
+
+        self.history.push_back(FrameRecord {
+            frame,
+            snapshot,
+            local,
+            remote,
+            confirmed,
+        });
+        let result = self.runtime.update();
+        self.drop_confirmed();
+        result
+    }
+
+    // 巻き戻す必要のなくなった古いフレームを捨てる。
+    fn drop_confirmed(&mut self) {
+        while self.history.front().is_some_and(|r| r.confirmed) {
+            self.history.pop_front();
+        }
+    }
+}
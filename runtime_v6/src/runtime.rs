@@ -1,299 +1,1188 @@
-use std::collections::HashMap;
+use std::any::{type_name, Any};
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::future::Future;
 use std::hash::Hash;
-use std::pin::Pin;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::task::{Context, Poll, Waker};
-use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use futures::task::ArcWake;
-
-use crate::container::{Container, Read};
+use crate::abort_handle::{AbortHandle, TaskId};
+use crate::builder::RuntimeBuilder;
+use crate::clock::{Clock, Time};
+use crate::command_error::{CommandError, CommandErrorPolicy};
+use crate::commands::{AppliedAt, CommandRegistry, Commands, FlushPoint};
+use crate::component::{
+    self, AccessGuard, AccessTable, Components, SystemAccessError, SystemParams,
+};
+use crate::container::{Container, Inspecting, Read};
+use crate::executor::{Executor, ParkedTasks};
+use crate::join_handle::{joinable, JoinHandle};
+use crate::pacing::{FixedTimestep, FramePacing};
+use crate::phase_graph::{PhaseConstraint, PhaseGraph, PhaseGraphError};
+#[cfg(feature = "profiler")]
+use crate::profiler::{PhaseStats, Profiler, SpanKind};
+#[cfg(feature = "replay")]
+use crate::replay::CommandLog;
+use crate::resource::{Res, Resources};
+use crate::rng::SeededRng;
+use crate::snapshot::{RuntimeSnapshot, Snapshot};
+use crate::system::AsyncSystem;
+use crate::task::{self, ChildKey, FrameContext, PanicHandler, PanicPayload, Task, WakeQueue};
+use crate::task_panic::{panic_message, TaskPanicPolicy, TaskPanicked};
+use crate::task_snapshot::{TaskSnapshot, TaskState};
+use crate::timer::TimerWheel;
+use crate::update_error::UpdateError;
 use crate::world::World;
 
-struct Task {
-    future: Pin<Box<dyn Future<Output = ()> + Send + 'static>>,
+/// 非同期タスクがすべて終了したかどうかのenum。
+#[derive(Debug)]
+pub enum RuntimeIsDone {
+    Done,
+    NotDone,
 }
-impl Task {
-    fn new(f: impl Future<Output = ()> + Send + 'static) -> Self {
-        Self {
-            future: Box::pin(f),
-        }
-    }
 
-    fn poll(&mut self, mut ctx: Context) -> Poll<()> {
-        match Future::poll(self.future.as_mut(), &mut ctx) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(()) => Poll::Ready(()),
-        }
+// Phaseごとのタスク。
+#[derive(Default)]
+struct PhaseTasks {
+    // このPhaseが次に実行されるときにpollするタスク
+    runnable: Vec<Task>,
+    // wakeされるまでpollしないタスク。
+    // Phaseの実行中は実行器と共有するので、Phaseごとに作り直さない
+    parked: ParkedTasks,
+}
+impl PhaseTasks {
+    fn is_empty(&self) -> bool {
+        self.runnable.is_empty() && self.parked.lock().unwrap().is_empty()
     }
 }
 
-#[derive(Clone)]
-struct WakeFlag {
-    waked: Arc<Mutex<bool>>,
+// Runtimeが扱う、拒否されたコマンドのエラー。
+type WorldCommandError<T, W> = CommandError<T, <W as World>::Error>;
+
+// Runtime::updateが返すエラー。
+type RuntimeUpdateError<T, W> = UpdateError<T, <W as World>::Error>;
+
+// システムを同じPhaseに登録し直す関数。
+// panicしたときや、RuntimeSnapshotから復元したときに呼ばれる。
+pub(crate) type Restart<T, W> = Arc<dyn Fn(&Runtime<T, W>) + Send + Sync>;
+
+/// ゲームループ用の非同期ランタイム。
+pub struct Runtime<T: Eq + Hash + Clone + Debug, W: World> {
+    frame_counter: Arc<AtomicU64>,
+    clock: Arc<Mutex<Box<dyn Clock>>>,
+    time: Arc<Mutex<Time>>,
+    timers: Arc<Mutex<TimerWheel>>,
+    world: Arc<Container<W>>,
+    components: Arc<Mutex<Components>>,
+    component_access: Arc<Mutex<AccessTable<T>>>,
+    resources: Arc<Mutex<Resources>>,
+    commands: Arc<CommandRegistry<W::Command>>,
+    command_error_policy: Arc<Mutex<CommandErrorPolicy<T, W::Error>>>,
+    command_errors: Arc<Mutex<Vec<WorldCommandError<T, W>>>>,
+    task_panic_policy: Arc<Mutex<TaskPanicPolicy>>,
+    task_panics: Arc<Mutex<Vec<TaskPanicked<T>>>>,
+    // FailやAbortのときに、このフレームで最初に起きたエラー
+    failure: Arc<Mutex<Option<RuntimeUpdateError<T, W>>>>,
+    tasks: Arc<Mutex<HashMap<T, PhaseTasks>>>,
+    // 次に追加するタスクのTask::order
+    next_order: Arc<AtomicU64>,
+    // Phaseの実行中にタスクから起動されたタスク。Phaseの後に起動したタスクの順に追加する
+    spawned: Arc<Mutex<Vec<(ChildKey, T, Task)>>>,
+    wake_queue: WakeQueue,
+    activated_phase: Arc<Mutex<PhaseGraph<T>>>,
+    fixed_phases: Arc<Mutex<HashSet<T>>>,
+    paused_phases: Arc<Mutex<HashSet<T>>>,
+    deactivated_phases: Arc<Mutex<Vec<T>>>,
+    sync_points: Arc<Mutex<HashSet<T>>>,
+    abort_requests: Arc<Mutex<HashSet<TaskId>>>,
+    cancelled_phases: Arc<Mutex<Vec<T>>>,
+    executor: Arc<Mutex<Executor>>,
+    rng: Arc<Mutex<SeededRng>>,
+    // Phaseのタスクを登録順にpollするかどうか
+    deterministic: bool,
+    #[cfg(feature = "profiler")]
+    profiler: Arc<Profiler>,
+    #[cfg(feature = "replay")]
+    command_log: Arc<Mutex<CommandLog<T, W::Command>>>,
 }
-impl WakeFlag {
-    fn new() -> Self {
+impl<T: Eq + Hash + Clone + Debug + 'static, W: World> Runtime<T, W> {
+    /// 新しくRuntimeを作成して返す。
+    /// ワーカースレッドの数などを指定する場合は[`RuntimeBuilder`]を使う。
+    ///
+    /// [`RuntimeBuilder`]: crate::RuntimeBuilder
+    pub fn new(world: W) -> Self {
+        RuntimeBuilder::new().build(world)
+    }
+
+    pub(crate) fn with_executor(
+        world: W,
+        executor: Executor,
+        clock: Box<dyn Clock>,
+        rng: SeededRng,
+        deterministic: bool,
+    ) -> Self {
+        let world = Arc::new(Container::new(world));
+        let commands = Arc::new(CommandRegistry::new(world.id()));
+
         Self {
-            waked: Arc::new(Mutex::new(false)),
+            frame_counter: Arc::new(AtomicU64::new(0)),
+            clock: Arc::new(Mutex::new(clock)),
+            time: Arc::new(Mutex::new(Time::new())),
+            timers: Arc::new(Mutex::new(TimerWheel::new())),
+            world,
+            components: Arc::new(Mutex::new(Components::new())),
+            component_access: Arc::new(Mutex::new(AccessTable::new())),
+            resources: Arc::new(Mutex::new(Resources::new())),
+            commands,
+            command_error_policy: Arc::new(Mutex::new(CommandErrorPolicy::Collect)),
+            command_errors: Arc::new(Mutex::new(vec![])),
+            task_panic_policy: Arc::new(Mutex::new(TaskPanicPolicy::Ignore)),
+            task_panics: Arc::new(Mutex::new(vec![])),
+            failure: Arc::new(Mutex::new(None)),
+            tasks: Arc::new(Mutex::new(HashMap::new())),
+            next_order: Arc::new(AtomicU64::new(0)),
+            spawned: Arc::new(Mutex::new(vec![])),
+            wake_queue: Arc::new(Mutex::new(vec![])),
+            activated_phase: Arc::new(Mutex::new(PhaseGraph::new())),
+            fixed_phases: Arc::new(Mutex::new(HashSet::new())),
+            paused_phases: Arc::new(Mutex::new(HashSet::new())),
+            deactivated_phases: Arc::new(Mutex::new(vec![])),
+            sync_points: Arc::new(Mutex::new(HashSet::new())),
+            abort_requests: Arc::new(Mutex::new(HashSet::new())),
+            cancelled_phases: Arc::new(Mutex::new(vec![])),
+            executor: Arc::new(Mutex::new(executor)),
+            rng: Arc::new(Mutex::new(rng)),
+            deterministic,
+            #[cfg(feature = "profiler")]
+            profiler: Arc::new(Profiler::new()),
+            #[cfg(feature = "replay")]
+            command_log: Arc::new(Mutex::new(CommandLog::Off)),
         }
     }
 
-    fn wake(&self) {
-        *self.waked.lock().unwrap() = true;
+    /// 非同期のタスクを登録する関数。
+    /// 非同期関数は[`Read<World>`]と[`Commands<World>`]、[`Runtime`]を受け取る。
+    /// 必要な引数だけを受け取る場合は[`Runtime::add_system`]を使う。
+    ///
+    /// 返り値の[`JoinHandle`]を`.await`すると非同期関数の結果を受け取れる。
+    ///
+    /// 非同期関数を作る関数は覚えておき、[`TaskPanicPolicy::Restart`]でpanicしたときや
+    /// [`Runtime::restore`]で復元したときに、もう一度呼び出して登録し直す。
+    pub fn add_async_system<F, Fut>(&self, phase: T, f: F) -> JoinHandle<Fut::Output>
+    where
+        T: Send,
+        F: Fn(Read<W>, Commands<W>, Self) -> Fut + Send + Sync + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        self.spawn_async_system(phase, Arc::new(f))
     }
 
-    fn is_waked(&self) -> bool {
-        *self.waked.lock().unwrap()
+    // 登録し直すときも同じ型の関数で呼び出せるように、Arcに入れた関数を受け取る。
+    fn spawn_async_system<F, Fut>(&self, phase: T, f: Arc<F>) -> JoinHandle<Fut::Output>
+    where
+        T: Send,
+        F: Fn(Read<W>, Commands<W>, Self) -> Fut + Send + Sync + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        let future = f(self.read_world(), self.new_commands(), self.clone());
+        // 別のスレッドから呼ばれても良いようにMutexに入れておく
+        let registered = Mutex::new(phase.clone());
+        let restart: Restart<T, W> = Arc::new(move |runtime| {
+            let phase = registered.lock().unwrap().clone();
+            runtime.spawn_async_system(phase, Arc::clone(&f));
+        });
+        self.spawn_task(
+            phase,
+            TaskId::next(),
+            Some(type_name::<F>().to_string()),
+            Some(Box::new(restart)),
+            future,
+        )
     }
-}
 
-#[derive(Clone)]
-struct WakeFlagWaker {
-    flag: WakeFlag,
-}
-impl WakeFlagWaker {
-    fn waker(flag: WakeFlag) -> Waker {
-        futures::task::waker(Arc::new(Self { flag }))
+    /// 非同期のシステムを登録する関数。
+    ///
+    /// システムは[`Res<R>`]と[`Read<World>`]、[`Commands<World>`]、[`Runtime`]、[`SeededRng`]のうち
+    /// 必要なものを好きな順に受け取る関数として書く。
+    /// 例えば`async fn system(stdout: Res<Mutex<Stdout>>, world: Read<GameWorld>)`のように書く。
+    ///
+    /// 登録されていないリソースを受け取ろうとした場合はエラーを返す。
+    ///
+    /// [`TaskPanicPolicy::Restart`]の場合、panicしたシステムはcloneして登録し直される。
+    ///
+    /// [`Res<R>`]: crate::Res
+    pub fn add_system<Params, S>(
+        &self,
+        phase: T,
+        system: S,
+    ) -> Result<JoinHandle<S::Output>, SystemAccessError<T>>
+    where
+        T: Send,
+        Params: 'static,
+        S: AsyncSystem<T, W, Params> + Clone + Send + 'static,
+    {
+        let future = system.clone().call(self)?;
+        // 別のスレッドから呼ばれても良いようにMutexに入れておく
+        let registered = Mutex::new((phase.clone(), system));
+        let restart: Restart<T, W> = Arc::new(move |runtime| {
+            let (phase, system) = registered.lock().unwrap().clone();
+            // 一度取り出せた引数は登録し直すときにも取り出せる
+            let _ = runtime.add_system(phase, system);
+        });
+        Ok(self.spawn_task(
+            phase,
+            TaskId::next(),
+            Some(type_name::<S>().to_string()),
+            Some(Box::new(restart)),
+            future,
+        ))
     }
-}
-impl ArcWake for WakeFlagWaker {
-    fn wake_by_ref(arc_self: &Arc<Self>) {
-        arc_self.flag.wake();
+
+    /// リソースを登録する関数。
+    /// 同じ型のリソースが登録されている場合は値を置き換えるが、
+    /// 既にシステムに渡された[`Res`]は古い値を指したままになる。
+    ///
+    /// 登録したリソースは[`Runtime::add_system`]で登録したシステムが[`Res`]として受け取れる。
+    ///
+    /// [`Res`]: crate::Res
+    pub fn insert_resource<R: Send + Sync + 'static>(&self, resource: R) {
+        self.resources.lock().unwrap().insert(resource);
     }
-}
 
-/// 非同期タスクがすべて終了したかどうかのenum。
-pub enum RuntimeIsDone {
-    Done,
-    NotDone,
-}
+    pub(crate) fn resource<R: Send + Sync + 'static>(&self) -> Option<Res<R>> {
+        self.resources.lock().unwrap().get::<R>()
+    }
 
-fn process_tasks(mut tasks: Vec<Task>) -> Vec<Task> {
-    let mut wait_tasks = vec![];
-
-    'current_frame: loop {
-        let task = tasks.pop();
-
-        match task {
-            // tasksが空だった場合は次のphaseへ
-            None => break 'current_frame,
-            Some(mut task) => {
-                let flag = WakeFlag::new();
-                let waker = WakeFlagWaker::waker(flag.clone());
-
-                match task.poll(Context::from_waker(&waker)) {
-                    Poll::Ready(()) => (),
-                    Poll::Pending => {
-                        // タスクがwake済みだったらtasksにpush
-                        // そうでなかったらwait_tasksにpushする
-                        if flag.is_waked() {
-                            tasks.push(task);
-                        } else {
-                            wait_tasks.push(task);
-                        }
-                    }
-                }
+    pub(crate) fn read_world(&self) -> Read<W> {
+        self.world.read()
+    }
+
+    // フレームの間にWorldを参照する。返り値が存在する間はフレームを進められない。
+    pub(crate) fn inspect_world(&self) -> Inspecting<'_, W> {
+        self.world.inspect()
+    }
+
+    /// タイマーホイールに登録されているタイマーの数を返す関数。
+    #[cfg(test)]
+    pub(crate) fn timer_count(&self) -> usize {
+        self.timers.lock().unwrap().len()
+    }
+
+    pub(crate) fn new_commands(&self) -> Commands<W> {
+        Commands::new(&self.commands)
+    }
+
+    /// ランタイムの乱数生成器からforkした乱数生成器を返す関数。
+    ///
+    /// [`Runtime::add_system`]で登録したシステムは引数として[`SeededRng`]を受け取れる。
+    /// それ以外のタスクで乱数を使う場合はこの関数で作ったものを渡す。
+    ///
+    /// 決定的モードでタスクのpoll中に呼び出した場合は、そのタスクの乱数生成器からforkする。
+    /// そのため、ワーカースレッドでpollされる順序に結果が左右されない。
+    pub fn rng(&self) -> SeededRng {
+        task::fork_task_rng(self.world.id()).unwrap_or_else(|| self.rng.lock().unwrap().fork())
+    }
+
+    /// タスクを起動する関数。
+    /// 同一Phaseのタスクの実行順序は不定。
+    ///
+    /// 返り値の[`JoinHandle`]を`.await`するとタスクの結果を受け取れる。
+    pub fn spawn<Fut>(&self, phase: T, f: Fut) -> JoinHandle<Fut::Output>
+    where
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        self.spawn_task(phase, TaskId::next(), None, None, f)
+    }
+
+    /// 名前を付けてタスクを起動する関数。
+    /// 名前は[`Runtime::tasks_snapshot`]や[`TaskPanicked`]で参照できる。
+    ///
+    /// 返り値の[`JoinHandle`]を`.await`するとタスクの結果を受け取れる。
+    ///
+    /// [`TaskPanicked`]: crate::TaskPanicked
+    pub fn spawn_named<Fut>(
+        &self,
+        phase: T,
+        name: impl Into<String>,
+        f: Fut,
+    ) -> JoinHandle<Fut::Output>
+    where
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        self.spawn_task(phase, TaskId::next(), Some(name.into()), None, f)
+    }
+
+    /// 生きているタスクの一覧をタスクのIDの順に返す関数。
+    ///
+    /// Phaseの実行中に呼び出した場合、実行中のPhaseのタスクは含まれない。
+    pub fn tasks_snapshot(&self) -> Vec<TaskSnapshot<T>> {
+        let tasks = self.tasks.lock().unwrap();
+        let mut snapshot = vec![];
+        for (phase, phase_tasks) in tasks.iter() {
+            snapshot.extend(
+                phase_tasks
+                    .runnable
+                    .iter()
+                    .map(|task| TaskSnapshot::new(task, phase.clone(), TaskState::Runnable)),
+            );
+            snapshot.extend(
+                phase_tasks
+                    .parked
+                    .lock()
+                    .unwrap()
+                    .values()
+                    .map(|task| TaskSnapshot::new(task, phase.clone(), TaskState::Parked)),
+            );
+        }
+        snapshot.sort_by_key(TaskSnapshot::id);
+        snapshot
+    }
+
+    /// コンポーネントを登録する関数。
+    /// 同じ型のコンポーネントが登録されている場合は値を置き換える。
+    ///
+    /// 登録したコンポーネントには[`Runtime::add_component_system`]で登録したシステムからアクセスできる。
+    ///
+    /// ## panic
+    /// タスクのpoll中に呼び出した場合、panicする。
+    pub fn insert_component<C: Send + Sync + 'static>(&self, component: C) {
+        self.components
+            .lock()
+            .unwrap()
+            .insert(component, &self.world);
+    }
+
+    /// コンポーネントにアクセスするシステムを登録する関数。
+    ///
+    /// システムは引数の型でアクセスするコンポーネントを宣言する。
+    /// 例えば`|(player, mut enemies): (Read<Player>, Write<Enemies>)| async move { .. }`のように書く。
+    /// [`Write::with_mut`]でコンポーネントを直接書き換えられるので、コマンドを経由する必要はない。
+    /// 引数のアクセスはこのシステムのタスクの中でだけ使え、他のタスクに渡して使うとpanicする。
+    ///
+    /// 同じPhaseのシステム同士のアクセスは衝突しないので、タスクは並列に実行される。
+    /// 宣言したアクセスはタスクが終了するまで有効で、同じPhaseで衝突するアクセスを宣言した場合や
+    /// 登録されていないコンポーネントにアクセスしようとした場合はエラーを返す。
+    ///
+    /// [`Write::with_mut`]: crate::Write::with_mut
+    pub fn add_component_system<P, F, Fut>(
+        &self,
+        phase: T,
+        f: F,
+    ) -> Result<JoinHandle<Fut::Output>, SystemAccessError<T>>
+    where
+        T: Send + 'static,
+        P: SystemParams,
+        F: FnOnce(P) -> Fut,
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        let id = TaskId::next();
+        let params = component::fetch::<P>(&self.components.lock().unwrap(), id)
+            .map_err(SystemAccessError::MissingComponent)?;
+
+        self.component_access
+            .lock()
+            .unwrap()
+            .declare(&phase, id, component::accesses::<P>())?;
+        let guard = AccessGuard::new(Arc::clone(&self.component_access), id);
+
+        let f = f(params);
+        Ok(self.spawn_task(
+            phase,
+            id,
+            Some(type_name::<F>().to_string()),
+            None,
+            async move {
+                // タスクが終了するかdropされるまでアクセスの宣言を保持する
+                let _guard = guard;
+                f.await
+            },
+        ))
+    }
+
+    // restartには型を消したRestart<T, W>を渡す。
+    fn spawn_task<Fut>(
+        &self,
+        phase: T,
+        id: TaskId,
+        name: Option<String>,
+        restart: Option<Box<dyn Any + Send>>,
+        f: Fut,
+    ) -> JoinHandle<Fut::Output>
+    where
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        let (task, handle, report_panic) =
+            joinable(f, AbortHandle::new(id, Arc::clone(&self.abort_requests)));
+        let on_panic = PanicHandler {
+            report: report_panic,
+            restart,
+        };
+        let mut task = Task::new(
+            id,
+            name,
+            self.frame_counter(),
+            task,
+            on_panic,
+            Arc::clone(&self.wake_queue),
+            self.deterministic.then(|| self.rng()),
+        );
+        match task::next_child(self.world.id()) {
+            // poll中に起動されたタスクは、ワーカースレッドでは起動される順番が決まらないので
+            // Phaseの後に起動したタスクの順に並べてから追加する
+            Some(key) => self.spawned.lock().unwrap().push((key, phase, task)),
+            None => {
+                task.order = self.next_order.fetch_add(1, Ordering::Relaxed);
+                let mut tasks = self.tasks.lock().unwrap();
+                tasks.entry(phase).or_default().runnable.push(task);
             }
         }
+        handle
     }
 
-    wait_tasks
-}
+    // Phaseの実行中にタスクから起動されたタスクを、起動したタスクの順に追加する。
+    fn add_spawned_tasks(&self) {
+        let mut spawned = std::mem::take(&mut *self.spawned.lock().unwrap());
+        spawned.sort_by_key(|(key, _, _)| *key);
+        let mut tasks = self.tasks.lock().unwrap();
+        for (_, phase, mut task) in spawned {
+            task.order = self.next_order.fetch_add(1, Ordering::Relaxed);
+            tasks.entry(phase).or_default().runnable.push(task);
+        }
+    }
 
-/// ゲームループ用の非同期ランタイム。
-pub struct Runtime<T: Eq + Hash + Clone + Debug, W: World> {
-    frame_counter: u64,
-    world: Arc<Container<W>>,
-    world_command_receiver: Arc<Mutex<Receiver<W::Command>>>,
-    world_command_sender: Sender<W::Command>,
-    tasks: Arc<Mutex<HashMap<T, Vec<Task>>>>,
-    wait_tasks: Arc<Mutex<HashMap<T, Vec<Task>>>>,
-    activated_phase: Arc<Mutex<HashMap<u16, T>>>,
-    threads: Arc<Mutex<Vec<Option<JoinHandle<()>>>>>,
-    receivers: Arc<Mutex<[Receiver<Vec<Task>>; 2]>>,
-    senders: [Sender<Vec<Task>>; 2],
-    thread_stop_flag: Arc<AtomicBool>,
-}
-impl<T: Eq + Hash + Clone + Debug, W: World> Runtime<T, W> {
-    /// 新しくRuntimeを作成して返す。
-    pub fn new(world: W) -> Self {
-        let thread_stop_flag = Arc::new(AtomicBool::new(false));
-
-        let (thread_sender1, main_receiver1) = channel();
-        let (main_sender1, thread_receiver1) = channel();
-        let stop_flag_thread1 = Arc::clone(&thread_stop_flag);
-        let thread1 = thread::spawn(move || loop {
-            match thread_receiver1.recv_timeout(Duration::from_millis(16)) {
-                Ok(tasks) => {
-                    let wait_tasks = process_tasks(tasks);
-                    thread_sender1.send(wait_tasks).unwrap();
-                }
-                Err(_) => (),
+    /// 毎フレーム呼び出すべき関数。
+    /// 各Phaseのタスクを順に実行していく。
+    ///
+    /// 各Phaseでpollされるのは、next_frame()で次フレームを予約したタスクと
+    /// wakeされたタスクだけである。
+    ///
+    /// [`CommandErrorPolicy::Fail`]でコマンドが拒否された場合や、
+    /// [`TaskPanicPolicy::Abort`]でタスクがpanicした場合は、フレームの終わりに最初のエラーを返す。
+    pub fn update(&mut self) -> Result<RuntimeIsDone, RuntimeUpdateError<T, W>> {
+        self.update_frame(None)
+    }
+
+    /// 全てのタスクが終了するまで、pacingに従ってフレームを進める関数。
+    ///
+    /// [`Runtime::update`]がエラーを返すフレームでは、フレームの終わりにエラーを返す。
+    ///
+    /// ## panic
+    /// FPSやupdate_hz、max_catchupに0を指定した場合、panicする。
+    pub fn run_with(&mut self, pacing: FramePacing) -> Result<(), RuntimeUpdateError<T, W>> {
+        let mut fixed_timestep = match pacing {
+            FramePacing::FixedTimestep {
+                update_hz,
+                max_catchup,
+            } => Some(FixedTimestep::new(update_hz, max_catchup)),
+            _ => None,
+        };
+        let frame_duration = match pacing {
+            FramePacing::Fixed(fps) => {
+                assert!(fps > 0, "fps must be greater than 0");
+                Some(Duration::from_secs(1) / fps)
             }
+            _ => None,
+        };
+
+        'update_loop: loop {
+            let frame_start = Instant::now();
 
-            if stop_flag_thread1.load(Ordering::Relaxed) {
-                break;
+            match self.update_frame(fixed_timestep.as_mut())? {
+                RuntimeIsDone::Done => break 'update_loop,
+                RuntimeIsDone::NotDone => (),
             }
+
+            // 次のフレームを始めるまでにsleepする時間
+            let target = match (&fixed_timestep, frame_duration) {
+                (Some(fixed_timestep), _) => fixed_timestep.until_next_step(),
+                (None, Some(frame_duration)) => frame_duration,
+                (None, None) => continue,
+            };
+            let duration = frame_start.elapsed();
+            if duration < target {
+                thread::sleep(target - duration);
+            }
+        }
+
+        Ok(())
+    }
+
+    // 1フレーム分タスクを実行する。
+    // fixed_timestepを渡した場合、固定Phaseは経過時間に応じた回数だけ実行される。
+    fn update_frame(
+        &mut self,
+        fixed_timestep: Option<&mut FixedTimestep>,
+    ) -> Result<RuntimeIsDone, RuntimeUpdateError<T, W>> {
+        #[cfg(feature = "profiler")]
+        let _span = self
+            .profiler
+            .span(SpanKind::Frame, self.frame_counter(), || {
+                "frame".to_string()
+            });
+        #[cfg(feature = "tracing")]
+        let _entered = tracing::info_span!("update", frame = self.frame_counter()).entered();
+
+        // 前のフレームでDeactivateされたPhaseのタスクをキャンセルする
+        self.cancel_deactivated_phases();
+
+        // 前のフレームで次のフレームの開始時に適用するよう送信されたコマンドを処理する
+        self.apply_commands(AppliedAt::FrameStart, |point| {
+            matches!(point, FlushPoint::NextFrame)
         });
 
-        let (thread_sender2, main_receiver2) = channel();
-        let (main_sender2, thread_receiver2) = channel();
-        let stop_flag_thread2 = Arc::clone(&thread_stop_flag);
-        let thread2 = thread::spawn(move || loop {
-            match thread_receiver2.recv_timeout(Duration::from_millis(16)) {
-                Ok(tasks) => {
-                    let wait_tasks = process_tasks(tasks);
-                    thread_sender2.send(wait_tasks).unwrap();
-                }
-                Err(_) => (),
+        // ActivateされているPhaseを実行順に並べたもの
+        // フレームの途中でPhaseが変更されても次のフレームまで反映されないように複製しておく
+        let phases = self.activated_phase.lock().unwrap().sorted().to_vec();
+
+        // 時計を進めて、このフレームのゲーム時間を決める
+        let time = {
+            let delta = self.clock.lock().unwrap().tick();
+            let mut time = self.time.lock().unwrap();
+            time.advance(self.frame_counter(), delta);
+            *time
+        };
+
+        // タイマーを進めて、期限の来たタイマーを待っているタスクをwakeする
+        let frame = FrameContext {
+            world: self.world.id(),
+            frame_counter: self.frame_counter(),
+            time,
+            timers: Arc::clone(&self.timers),
+            #[cfg(feature = "profiler")]
+            profiler: Arc::clone(&self.profiler),
+        };
+        let expired = self
+            .timers
+            .lock()
+            .unwrap()
+            .advance(frame.frame_counter, time.elapsed());
+        for waker in expired {
+            waker.wake();
+        }
+
+        // 固定Phaseを実行する回数と、固定Phaseから見えるフレームの情報
+        let (fixed_steps, fixed_frame) = match fixed_timestep {
+            Some(fixed_timestep) => {
+                let steps = fixed_timestep.advance(time.delta_time());
+                let fixed_frame = FrameContext {
+                    time: time.with_fixed_step(fixed_timestep.step()),
+                    ..frame.clone()
+                };
+                (steps, fixed_frame)
+            }
+            None => (1, frame.clone()),
+        };
+        let fixed_phases = self.fixed_phases.lock().unwrap().clone();
+        let paused_phases = self.paused_phases.lock().unwrap().clone();
+        let sync_points = self.sync_points.lock().unwrap().clone();
+
+        // このフレームで実行されたPhase
+        let mut executed = HashSet::new();
+
+        // Phaseにについてループする
+        for phase in phases.iter() {
+            // 一時停止中のPhaseのタスクはpollせずに残しておく
+            if paused_phases.contains(phase) {
+                continue;
             }
 
-            if stop_flag_thread2.load(Ordering::Relaxed) {
-                break;
+            // 同期点が追加されていなければすべてのPhaseの後でコマンドを適用する
+            let sync = sync_points.is_empty() || sync_points.contains(phase);
+            if fixed_phases.contains(phase) {
+                for _ in 0..fixed_steps {
+                    self.run_phase(phase, &fixed_frame, sync);
+                    executed.insert(phase.clone());
+                }
+            } else {
+                self.run_phase(phase, &frame, sync);
+                executed.insert(phase.clone());
             }
+        }
+
+        // 次の同期点を待っているコマンドと、このフレームで実行されないPhaseの後に
+        // 適用するコマンドをフレームの終わりに処理する
+        self.apply_commands(AppliedAt::FrameEnd, |point| match point {
+            FlushPoint::NextSyncPoint | FlushPoint::EndOfFrame => true,
+            FlushPoint::AfterPhase(p) => !executed.iter().any(|phase| p.is(phase)),
+            FlushPoint::NextFrame => false,
         });
 
-        let world = Arc::new(Container::new(world));
-        let (world_command_sender, world_command_receiver) = channel();
-        let world_command_receiver = Arc::new(Mutex::new(world_command_receiver));
+        self.wake_parked_tasks();
+        self.drop_aborted_tasks();
 
-        Self {
-            frame_counter: 0,
-            world,
-            world_command_receiver,
-            world_command_sender,
-            tasks: Arc::new(Mutex::new(HashMap::new())),
-            wait_tasks: Arc::new(Mutex::new(HashMap::new())),
-            activated_phase: Arc::new(Mutex::new(HashMap::new())),
-            threads: Arc::new(Mutex::new(vec![Some(thread1), Some(thread2)])),
-            receivers: Arc::new(Mutex::new([main_receiver1, main_receiver2])),
-            senders: [main_sender1, main_sender2],
-            thread_stop_flag,
+        // すべてのActivateされているPhaseのタスクが空の場合、全てのタスクの実行が終わっている。
+        let done_flag = {
+            let tasks = self.tasks.lock().unwrap();
+            phases
+                .iter()
+                .all(|phase| tasks.get(phase).is_none_or(PhaseTasks::is_empty))
+        };
+        // 再生中は記録したコマンドが残っている間は終わらない
+        #[cfg(feature = "replay")]
+        let done_flag = done_flag && !self.command_log.lock().unwrap().is_replay_pending();
+
+        // 次のフレームに移る前にフレームカウンターを更新する
+        if !done_flag {
+            self.frame_counter.fetch_add(1, Ordering::Relaxed);
         }
-    }
 
-    /// 非同期のタスクを登録する関数。
-    /// 非同期関数は[`Read<World>`]と[`Sender<World::Command>`]、[`Runtime`]を受け取る。
-    pub fn add_async_system<F, Fut>(&self, phase: T, f: F)
-    where
-        F: FnOnce(Read<W>, Sender<W::Command>, Self) -> Fut,
-        Fut: Future<Output = ()> + Send + 'static,
-    {
-        let mut wait_tasks = self.wait_tasks.lock().unwrap();
-        let wts = wait_tasks.entry(phase).or_insert(vec![]);
-        wts.push(Task::new(f(
-            unsafe { self.world.read() },
-            self.world_command_sender.clone(),
-            self.clone(),
-        )));
+        // このフレームで起きたエラーがあればフレームを終えてから返す
+        if let Some(error) = self.failure.lock().unwrap().take() {
+            return Err(error);
+        }
+
+        if done_flag {
+            Ok(RuntimeIsDone::Done)
+        } else {
+            Ok(RuntimeIsDone::NotDone)
+        }
     }
 
-    /// 毎フレーム呼び出すべき関数。
-    /// 各Phaseのタスクを順に実行していく。
-    pub fn update(&mut self) -> RuntimeIsDone {
-        // ActivateされているPhaseをソートする
-        let activated_phase = self.activated_phase.lock().unwrap();
-        let mut phases = activated_phase.iter().collect::<Vec<_>>();
-        phases.sort_by_key(|(&order, _phase)| order);
-        let phases = phases.into_iter().map(|(_order, phase)| phase);
+    // 1つのPhaseのタスクを実行して、このPhaseの後に適用するコマンドを処理する。
+    // syncがtrueの場合はPhaseの後が同期点になる。
+    fn run_phase(&self, phase: &T, frame: &FrameContext, sync: bool) {
+        #[cfg(feature = "profiler")]
+        let _span = self
+            .profiler
+            .span(SpanKind::Phase, frame.frame_counter, || {
+                format!("{:?}", phase)
+            });
+        #[cfg(feature = "tracing")]
+        let _entered = tracing::info_span!("phase", phase = ?phase).entered();
 
-        // wait_tasksを空のtasks_queueと交換する
-        std::mem::swap(&mut self.wait_tasks, &mut self.tasks);
+        // Phaseの境界でwakeされたタスクを実行可能に戻し、
+        // 中断要求のあったタスクを破棄する
+        self.wake_parked_tasks();
+        self.drop_aborted_tasks();
 
-        // Phaseにについてループする
-        for phase in phases {
-            let (tasks1, tasks2) = {
-                let mut tasks = self.tasks.lock().unwrap();
-                let tasks = tasks.entry(phase.clone()).or_insert(vec![]);
+        let (mut runnable, parked) = {
+            let mut tasks = self.tasks.lock().unwrap();
+            let phase_tasks = tasks.entry(phase.clone()).or_default();
+            (
+                std::mem::take(&mut phase_tasks.runnable),
+                Arc::clone(&phase_tasks.parked),
+            )
+        };
+        // 決定的モードではタスクが追加された順にpollする
+        if self.deterministic {
+            runnable.sort_by_key(|task| task.order);
+        }
+
+        // タスクを実行器に渡して、すべてpollされるのを待つ
+        // pollしている間だけタスクからWorldを参照できる
+        let processed = {
+            let _polling = self.world.poll();
+            self.executor
+                .lock()
+                .unwrap()
+                .run(runnable, &parked, frame.clone(), &self.wake_queue)
+        };
+
+        // 帰ってきたタスクを戻す
+        {
+            let mut tasks = self.tasks.lock().unwrap();
+            let phase_tasks = tasks.entry(phase.clone()).or_default();
+            phase_tasks.runnable.extend(processed.next_frame);
+        }
+        self.add_spawned_tasks();
 
-                // tasksを二分割する
-                let tasks1 = tasks.split_off(tasks.len() / 2);
-                let tasks2 = tasks.drain(0..).collect();
-                (tasks1, tasks2)
+        // panicしたタスクは戻さずに、タスクが追加された順に報告する
+        let mut panicked = processed.panicked;
+        panicked.sort_by_key(|(task, _)| task.order);
+        for (task, payload) in panicked {
+            self.handle_panicked_task(phase, frame.frame_counter, task, payload);
+        }
+
+        self.apply_commands(AppliedAt::AfterPhase(phase.clone()), |point| match point {
+            FlushPoint::NextSyncPoint => sync,
+            FlushPoint::AfterPhase(p) => p.is(phase),
+            FlushPoint::EndOfFrame | FlushPoint::NextFrame => false,
+        });
+    }
+
+    // 適用するタイミングがfilterに合うコマンドを、システムが登録された順に直列で実行する。
+    // 実行中にタスクの持つReadを参照するとpanicする。
+    // 記録中は適用する前にコマンドを記録し、再生中は記録したコマンドに置き換える。
+    fn apply_commands(&self, at: AppliedAt<T>, filter: impl FnMut(&FlushPoint) -> bool) {
+        let phase = at.phase().cloned();
+        let errors: Vec<_> = {
+            let commands = self.commands.drain(filter);
+            #[cfg(feature = "replay")]
+            let commands = {
+                let applied =
+                    self.command_log
+                        .lock()
+                        .unwrap()
+                        .apply(self.frame_counter(), at, commands);
+                applied.unwrap_or_else(|desync| {
+                    let mut failure = self.failure.lock().unwrap();
+                    if failure.is_none() {
+                        *failure = Some(UpdateError::ReplayDesync(desync));
+                    }
+                    vec![]
+                })
             };
+            #[cfg(feature = "profiler")]
+            let _span = (!commands.is_empty()).then(|| {
+                self.profiler.span(
+                    SpanKind::CommandFlush,
+                    self.frame_counter(),
+                    || match &phase {
+                        Some(phase) => format!("commands after {:?}", phase),
+                        None => "commands".to_string(),
+                    },
+                )
+            });
+            #[cfg(feature = "tracing")]
+            let _entered = (!commands.is_empty()).then(|| {
+                tracing::debug_span!("process_commands", count = commands.len(), phase = ?phase)
+                    .entered()
+            });
 
-            // 分割したtasksを各スレッドに送る
-            self.senders[0].send(tasks1).unwrap();
-            self.senders[1].send(tasks2).unwrap();
-
-            // スレッドからの応答を待つ
-            let receivers = self.receivers.lock().unwrap();
-            let wait_tasks1 = receivers[0].recv().unwrap();
-            let wait_tasks2 = receivers[1].recv().unwrap();
-
-            // 各スレッドから帰ってきたwait_tasksを追加する。
-            let wait_tasks = wait_tasks1.into_iter().chain(wait_tasks2.into_iter());
-            let mut wts = self.wait_tasks.lock().unwrap();
-            let wts_entry = wts.entry(phase.clone()).or_insert(vec![]);
-            for t in wait_tasks {
-                wts_entry.push(t);
-            }
+            let mut world = self.world.apply();
+            commands
+                .into_iter()
+                .filter_map(|cmd| world.process_command(cmd).err())
+                .collect()
+        };
+        if errors.is_empty() {
+            return;
+        }
 
-            // このphaseで送信されたコマンドを直列で実行する
-            {
-                let mut world = unsafe { self.world.write() };
-                for cmd in self.world_command_receiver.lock().unwrap().try_iter() {
-                    world.process_command(cmd);
+        // Worldへのアクセスを終えてから、拒否されたコマンドのエラーをポリシーに従って扱う
+        let frame_counter = self.frame_counter();
+        let mut policy = self.command_error_policy.lock().unwrap();
+        for error in errors {
+            let error = CommandError::new(frame_counter, phase.clone(), error);
+            match &mut *policy {
+                CommandErrorPolicy::Collect => self.command_errors.lock().unwrap().push(error),
+                CommandErrorPolicy::Callback(callback) => callback(error),
+                CommandErrorPolicy::Fail => {
+                    let mut failure = self.failure.lock().unwrap();
+                    if failure.is_none() {
+                        *failure = Some(UpdateError::CommandRejected(error));
+                    } else {
+                        self.command_errors.lock().unwrap().push(error);
+                    }
                 }
             }
         }
+    }
 
-        {
-            // すべてのPhaseのwait_tasksが空の場合、全てのタスクの実行が終わっている。
-            let mut done_flag = true;
-            let wait_tasks = self.wait_tasks.lock().unwrap();
-            for (_p, tasks) in wait_tasks.iter() {
-                if !tasks.is_empty() {
-                    done_flag = false;
+    /// 拒否されたコマンドのエラーの扱い方を設定する関数。
+    /// デフォルトは[`CommandErrorPolicy::Collect`]。
+    ///
+    /// コールバックの中からこの関数を呼ぶとデッドロックする。
+    pub fn set_command_error_policy(&self, policy: CommandErrorPolicy<T, W::Error>) {
+        *self.command_error_policy.lock().unwrap() = policy;
+    }
+
+    /// 溜められている拒否されたコマンドのエラーを、拒否された順に取り出す関数。
+    pub fn command_errors(&self) -> Vec<CommandError<T, W::Error>> {
+        std::mem::take(&mut *self.command_errors.lock().unwrap())
+    }
+
+    // panicしたタスクを破棄して、ポリシーに従ってイベントを報告する。
+    fn handle_panicked_task(&self, phase: &T, frame: u64, task: Task, payload: PanicPayload) {
+        let panicked = TaskPanicked::new(task.id, task.name.clone(), phase.clone(), frame, payload);
+        let message = panic_message(panicked.payload());
+        let restart = task.finish_panicked(Box::new(message));
+
+        let policy = *self.task_panic_policy.lock().unwrap();
+        match policy {
+            TaskPanicPolicy::Abort => {
+                let mut failure = self.failure.lock().unwrap();
+                if failure.is_none() {
+                    *failure = Some(UpdateError::TaskPanicked(panicked));
+                    return;
                 }
             }
+            TaskPanicPolicy::Restart => {
+                if let Some(Ok(restart)) = restart.map(|r| r.downcast::<Restart<T, W>>()) {
+                    restart(self);
+                }
+            }
+            TaskPanicPolicy::Ignore => (),
+        }
+        self.task_panics.lock().unwrap().push(panicked);
+    }
+
+    /// タスクがpanicしたときの扱い方を設定する関数。
+    /// デフォルトは[`TaskPanicPolicy::Ignore`]。
+    pub fn set_task_panic_policy(&self, policy: TaskPanicPolicy) {
+        *self.task_panic_policy.lock().unwrap() = policy;
+    }
+
+    /// 溜められているタスクのpanicのイベントを、panicした順に取り出す関数。
+    pub fn task_panics(&self) -> Vec<TaskPanicked<T>> {
+        std::mem::take(&mut *self.task_panics.lock().unwrap())
+    }
 
-            if done_flag {
-                self.thread_stop_flag.store(true, Ordering::Relaxed);
-                let mut threads = self.threads.lock().unwrap();
-                threads[0].take().unwrap().join().unwrap();
-                threads[1].take().unwrap().join().unwrap();
+    /// ランタイムの状態を保存する関数。
+    /// [`Runtime::update`]の間、つまりフレームの境界で呼び出す。
+    ///
+    /// Worldの状態の他に、フレームカウンターとゲーム時間、乱数生成器、PhaseのActivateの状態を保存する。
+    /// 中断しているタスクは保存できないので、代わりに生きている[`Runtime::add_system`]と
+    /// [`Runtime::add_async_system`]のシステムを覚えておき、[`Runtime::restore`]で登録し直す。
+    ///
+    /// ## panic
+    /// タスクのpoll中やコマンドの適用中に呼び出した場合、panicする。
+    pub fn snapshot(&self) -> RuntimeSnapshot<T, W>
+    where
+        W: Snapshot,
+    {
+        let world = self.world.apply().snapshot();
 
-                return RuntimeIsDone::Done;
+        // Deactivateされたり中断を要求されたりしたタスクは次のフレームで破棄されるので覚えない
+        let deactivated = self.deactivated_phases.lock().unwrap().clone();
+        let abort_requests = self.abort_requests.lock().unwrap().clone();
+        let mut systems = {
+            let restart_of = |task: &Task| {
+                if abort_requests.contains(&task.id) {
+                    return None;
+                }
+                let restart = task.on_panic.restart.as_ref()?;
+                let restart = restart.downcast_ref::<Restart<T, W>>()?;
+                Some((task.order, Arc::clone(restart)))
+            };
+            let tasks = self.tasks.lock().unwrap();
+            let mut systems = vec![];
+            for (_, phase_tasks) in tasks
+                .iter()
+                .filter(|(phase, _)| !deactivated.contains(phase))
+            {
+                systems.extend(phase_tasks.runnable.iter().filter_map(restart_of));
+                systems.extend(
+                    phase_tasks
+                        .parked
+                        .lock()
+                        .unwrap()
+                        .values()
+                        .filter_map(restart_of),
+                );
             }
+            systems
+        };
+        systems.sort_by_key(|(order, _)| *order);
+
+        RuntimeSnapshot {
+            frame_counter: self.frame_counter(),
+            time: self.time(),
+            world,
+            rng: self.rng.lock().unwrap().clone(),
+            activated_phase: self.activated_phase.lock().unwrap().clone(),
+            fixed_phases: self.fixed_phases.lock().unwrap().clone(),
+            paused_phases: self.paused_phases.lock().unwrap().clone(),
+            sync_points: self.sync_points.lock().unwrap().clone(),
+            systems: systems.into_iter().map(|(_, restart)| restart).collect(),
         }
+    }
 
-        // 次のフレームに移る前にフレームカウンターを更新する
-        self.frame_counter += 1;
+    /// [`Runtime::snapshot`]で保存した状態に戻す関数。
+    /// [`Runtime::update`]の間、つまりフレームの境界で呼び出す。
+    ///
+    /// すべてのタスクとまだ適用していないコマンドを破棄してからWorldなどの状態を戻し、
+    /// 保存したときに生きていた[`Runtime::add_system`]と[`Runtime::add_async_system`]のシステムを
+    /// 登録された順に登録し直す。[`Runtime::add_async_system`]のシステムは、
+    /// 非同期関数を作る関数を戻したWorldでもう一度呼び出して始め直す。
+    /// システムは戻したWorldと乱数生成器から始め直すので、同じ入力なら同じように進む。
+    /// [`Runtime::spawn`]などで起動したタスクは登録し直されない。
+    /// 破棄したタスクの[`JoinHandle`]にはキャンセルが報告される。
+    ///
+    /// ## panic
+    /// タスクのpoll中やコマンドの適用中に呼び出した場合、panicする。
+    pub fn restore(&self, snapshot: &RuntimeSnapshot<T, W>)
+    where
+        W: Snapshot,
+    {
+        self.world.apply().restore(&snapshot.world);
 
-        RuntimeIsDone::NotDone
+        // タスクはロックを外してから破棄する
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        drop(tasks);
+        self.commands.drain(|_| true);
+        self.wake_queue.lock().unwrap().clear();
+        self.abort_requests.lock().unwrap().clear();
+        self.deactivated_phases.lock().unwrap().clear();
+        self.cancelled_phases.lock().unwrap().clear();
+
+        self.frame_counter
+            .store(snapshot.frame_counter, Ordering::Relaxed);
+        *self.time.lock().unwrap() = snapshot.time;
+        self.timers
+            .lock()
+            .unwrap()
+            .reset(snapshot.time.frame_counter(), snapshot.time.elapsed());
+        *self.rng.lock().unwrap() = snapshot.rng.clone();
+        *self.activated_phase.lock().unwrap() = snapshot.activated_phase.clone();
+        *self.fixed_phases.lock().unwrap() = snapshot.fixed_phases.clone();
+        *self.paused_phases.lock().unwrap() = snapshot.paused_phases.clone();
+        *self.sync_points.lock().unwrap() = snapshot.sync_points.clone();
+
+        for restart in snapshot.systems.iter() {
+            restart(self);
+        }
+    }
+
+    /// フレームの実時間を計測するプロファイラを返す関数。
+    #[cfg(feature = "profiler")]
+    pub fn profiler(&self) -> &Profiler {
+        &self.profiler
+    }
+
+    /// Phaseの直近の実行時間の統計を返す関数。
+    /// 一度も実行されていないPhaseの場合はNoneを返す。
+    #[cfg(feature = "profiler")]
+    pub fn phase_stats(&self, phase: &T) -> Option<PhaseStats> {
+        self.profiler.phase_stats(&format!("{:?}", phase))
+    }
+
+    /// これ以降に適用するコマンドを、フレームと適用した時点と共にwriterへ記録する関数。
+    /// 拒否されたコマンドも記録する。記録したコマンドは[`ReplayRuntime`]で再生できる。
+    ///
+    /// 書き込みに失敗するとそれ以降は記録せず、[`Runtime::stop_recording`]でエラーを返す。
+    ///
+    /// [`ReplayRuntime`]: crate::ReplayRuntime
+    #[cfg(feature = "replay")]
+    pub fn record_commands<Wr>(&self, writer: Wr) -> std::io::Result<()>
+    where
+        T: serde::Serialize,
+        W::Command: serde::Serialize,
+        Wr: std::io::Write + Send + 'static,
+    {
+        self.command_log.lock().unwrap().start_recording(writer)
+    }
+
+    /// コマンドの記録を終える関数。
+    /// 記録の途中で書き込みに失敗していた場合はそのエラーを返す。
+    #[cfg(feature = "replay")]
+    pub fn stop_recording(&self) -> std::io::Result<()> {
+        self.command_log.lock().unwrap().stop_recording()
+    }
+
+    #[cfg(feature = "replay")]
+    pub(crate) fn command_log(&self) -> &Mutex<CommandLog<T, W::Command>> {
+        &self.command_log
     }
 
     /// 現在のフレームカウントを返す関数。
     /// 0スタートでカウントされている。
     pub fn frame_counter(&self) -> u64 {
-        self.frame_counter
+        self.frame_counter.load(Ordering::Relaxed)
+    }
+
+    /// 現在のフレームのゲーム時間を返す関数。
+    pub fn time(&self) -> Time {
+        *self.time.lock().unwrap()
+    }
+
+    /// 前のフレームからの経過時間を返す関数。
+    /// time_scaleがかけられている。
+    pub fn delta_time(&self) -> Duration {
+        self.time().delta_time()
+    }
+
+    /// ランタイムの開始からの経過時間を返す関数。
+    /// time_scaleがかけられている。
+    pub fn elapsed(&self) -> Duration {
+        self.time().elapsed()
+    }
+
+    /// 時間の進む速さの倍率を返す関数。
+    pub fn time_scale(&self) -> f64 {
+        self.time().time_scale()
+    }
+
+    /// 時間の進む速さの倍率を設定する関数。
+    /// 次のフレームのデルタタイムから反映される。
+    ///
+    /// ## panic
+    /// 負の値や有限でない値を指定した場合、panicする。
+    pub fn set_time_scale(&self, time_scale: f64) {
+        self.time.lock().unwrap().set_time_scale(time_scale);
+    }
+
+    /// Phaseに登録されているすべてのタスクをキャンセルする関数。
+    ///
+    /// タスクは次のPhaseの境界でdropされ、JoinHandleにはキャンセルが報告される。
+    pub fn cancel_phase(&self, phase: T) {
+        self.cancelled_phases.lock().unwrap().push(phase);
+    }
+
+    // Deactivateされたまま次のフレームを迎えたPhaseのタスクをキャンセルする。
+    fn cancel_deactivated_phases(&self) {
+        let deactivated = std::mem::take(&mut *self.deactivated_phases.lock().unwrap());
+        if deactivated.is_empty() {
+            return;
+        }
+
+        let activated_phase = self.activated_phase.lock().unwrap();
+        let mut cancelled_phases = self.cancelled_phases.lock().unwrap();
+        for phase in deactivated {
+            // 再びActivateされたPhaseのタスクは残す
+            if !activated_phase.contains(&phase) {
+                cancelled_phases.push(phase);
+            }
+        }
+    }
+
+    // wakeされたタスクをparkedからrunnableに移す。
+    fn wake_parked_tasks(&self) {
+        let woken = std::mem::take(&mut *self.wake_queue.lock().unwrap());
+        if woken.is_empty() {
+            return;
+        }
+
+        let mut tasks = self.tasks.lock().unwrap();
+        for id in woken {
+            // poll中にwakeされてそのまま再pollされたタスクなど、
+            // parkedに見つからないIDは無視してよい
+            for phase_tasks in tasks.values_mut() {
+                let task = phase_tasks.parked.lock().unwrap().remove(&id);
+                if let Some(task) = task {
+                    phase_tasks.runnable.push(task);
+                    break;
+                }
+            }
+        }
+    }
+
+    // 中断要求のあったタスクとキャンセルされたPhaseのタスクを破棄する。
+    // タスクのデストラクタはこの関数を呼び出したスレッドで実行される。
+    fn drop_aborted_tasks(&self) {
+        let cancelled_phases = std::mem::take(&mut *self.cancelled_phases.lock().unwrap());
+        let abort_requests = std::mem::take(&mut *self.abort_requests.lock().unwrap());
+        if cancelled_phases.is_empty() && abort_requests.is_empty() {
+            return;
+        }
+
+        let mut dropped = vec![];
+        {
+            let mut tasks = self.tasks.lock().unwrap();
+            for (phase, phase_tasks) in tasks.iter_mut() {
+                if cancelled_phases.contains(phase) {
+                    dropped.push(std::mem::take(phase_tasks));
+                } else {
+                    let (aborted, alive): (Vec<_>, Vec<_>) =
+                        std::mem::take(&mut phase_tasks.runnable)
+                            .into_iter()
+                            .partition(|t| abort_requests.contains(&t.id));
+                    phase_tasks.runnable = alive;
+                    let aborted = PhaseTasks {
+                        runnable: aborted,
+                        parked: Default::default(),
+                    };
+                    {
+                        let mut parked = phase_tasks.parked.lock().unwrap();
+                        let mut aborted_parked = aborted.parked.lock().unwrap();
+                        for id in abort_requests.iter() {
+                            if let Some(task) = parked.remove(id) {
+                                aborted_parked.insert(*id, task);
+                            }
+                        }
+                    }
+                    dropped.push(aborted);
+                }
+            }
+        }
+
+        // ロックを解放してからdropする。
+        // デストラクタの中からランタイムにタスクを追加しても良いようにするため。
+        drop(dropped);
+    }
+
+    /// Phaseを固定Phaseとして登録する関数。
+    ///
+    /// [`FramePacing::FixedTimestep`]で実行する場合、固定Phaseは経過したゲーム時間に応じて
+    /// 1フレームに0回以上実行される。固定Phaseの中から見えるデルタタイムは固定タイムステップになる。
+    /// それ以外の方法でフレームを進める場合は通常のPhaseと同じく1フレームに1回実行される。
+    pub fn set_fixed_phase(&self, phase: T) {
+        self.fixed_phases.lock().unwrap().insert(phase);
     }
 
     /// 実行するPhaseを登録する関数。
-    /// Phaseの実行順序をorderで指定する。
+    /// Phaseの実行順序は[`before`]と[`after`]による制約で指定する。
     ///
+    /// 制約のないPhase同士はActivateされた順に実行される。
+    /// ActivateされていないPhaseへの制約は、そのPhaseがActivateされるまで無視される。
     /// ActivateされたPhaseは次のフレームから実行されるようになる。
     ///
-    /// ## panic
-    /// orderに他のPhaseと重複した値を指定した場合、panicする。
-    pub fn activate_phase(&mut self, phase: T, order: u16) {
-        let mut activated_phase = self.activated_phase.lock().unwrap();
-
-        if let Some(p) = activated_phase.get(&order) {
-            panic!(format!(
-                "Another PHASE has already been registered in this order: {:?}",
-                p
-            ));
-        }
+    /// Phaseが既にActivateされている場合や制約が循環している場合はエラーを返し、何も変更しない。
+    ///
+    /// [`before`]: crate::before
+    /// [`after`]: crate::after
+    pub fn activate_phase(
+        &self,
+        phase: T,
+        constraints: impl IntoIterator<Item = PhaseConstraint<T>>,
+    ) -> Result<(), PhaseGraphError<T>> {
+        self.activated_phase
+            .lock()
+            .unwrap()
+            .insert(phase, constraints.into_iter().collect())
+    }
 
-        activated_phase.insert(order, phase);
+    /// Phaseの後にコマンドを適用する同期点を追加する関数。
+    /// 変更は次のフレームから反映される。
+    ///
+    /// [`FlushPoint::NextSyncPoint`]で送信されたコマンドは、同期点かフレームの終わりまで適用されない。
+    /// 同期点が1つも追加されていない場合は、すべてのPhaseの後が同期点になる。
+    pub fn add_sync_point(&self, phase: T) {
+        self.sync_points.lock().unwrap().insert(phase);
+    }
+
+    /// Phaseの後の同期点を取り除く関数。
+    /// 変更は次のフレームから反映される。
+    pub fn remove_sync_point(&self, phase: &T) {
+        self.sync_points.lock().unwrap().remove(phase);
+    }
+
+    /// Phaseの登録を解除する関数。
+    ///
+    /// DeactivateされたPhaseは次のフレームから実行されなくなり、
+    /// 次のフレームの開始時までに再びActivateされなければPhaseのタスクはキャンセルされる。
+    /// タスクを残したまま実行を止める場合は[`Runtime::pause_phase`]を使う。
+    pub fn deactivate_phase(&self, phase: T) {
+        self.activated_phase.lock().unwrap().remove(&phase);
+        self.paused_phases.lock().unwrap().remove(&phase);
+        self.deactivated_phases.lock().unwrap().push(phase);
+    }
+
+    /// Phaseを一時停止する関数。
+    ///
+    /// 一時停止したPhaseは次のフレームから[`Runtime::resume_phase`]を呼ぶまで実行されない。
+    /// Phaseのタスクはpollされずに残り、一時停止中にwakeされたタスクは再開後にpollされる。
+    pub fn pause_phase(&self, phase: T) {
+        self.paused_phases.lock().unwrap().insert(phase);
+    }
+
+    /// 一時停止したPhaseを再開する関数。
+    /// Phaseは次のフレームから実行される。
+    pub fn resume_phase(&self, phase: &T) {
+        self.paused_phases.lock().unwrap().remove(phase);
+    }
+
+    /// ActivateされているPhaseの実行順序の制約を置き換える関数。
+    /// 変更は次のフレームから反映される。
+    ///
+    /// PhaseがActivateされていない場合や制約が循環している場合はエラーを返し、何も変更しない。
+    pub fn set_phase_order(
+        &self,
+        phase: T,
+        constraints: impl IntoIterator<Item = PhaseConstraint<T>>,
+    ) -> Result<(), PhaseGraphError<T>> {
+        self.activated_phase
+            .lock()
+            .unwrap()
+            .set_constraints(phase, constraints.into_iter().collect())
     }
 }
 // deriveマクロではWorldに過剰なCloneが要求されてしまうので手動で実装する。
@@ -301,17 +1190,38 @@ impl<T: Eq + Hash + Clone + Debug, W: World> Runtime<T, W> {
 impl<T: Eq + Hash + Clone + Debug, W: World> Clone for Runtime<T, W> {
     fn clone(&self) -> Self {
         Self {
-            frame_counter: self.frame_counter,
+            frame_counter: Arc::clone(&self.frame_counter),
+            clock: Arc::clone(&self.clock),
+            time: Arc::clone(&self.time),
+            timers: Arc::clone(&self.timers),
             world: Arc::clone(&self.world),
-            world_command_receiver: Arc::clone(&self.world_command_receiver),
-            world_command_sender: self.world_command_sender.clone(),
+            components: Arc::clone(&self.components),
+            component_access: Arc::clone(&self.component_access),
+            resources: Arc::clone(&self.resources),
+            commands: Arc::clone(&self.commands),
+            command_error_policy: Arc::clone(&self.command_error_policy),
+            command_errors: Arc::clone(&self.command_errors),
+            task_panic_policy: Arc::clone(&self.task_panic_policy),
+            task_panics: Arc::clone(&self.task_panics),
+            failure: Arc::clone(&self.failure),
             tasks: Arc::clone(&self.tasks),
-            wait_tasks: Arc::clone(&self.wait_tasks),
+            next_order: Arc::clone(&self.next_order),
+            spawned: Arc::clone(&self.spawned),
+            wake_queue: Arc::clone(&self.wake_queue),
             activated_phase: Arc::clone(&self.activated_phase),
-            threads: Arc::clone(&self.threads),
-            receivers: Arc::clone(&self.receivers),
-            senders: self.senders.clone(),
-            thread_stop_flag: Arc::clone(&self.thread_stop_flag),
+            fixed_phases: Arc::clone(&self.fixed_phases),
+            paused_phases: Arc::clone(&self.paused_phases),
+            deactivated_phases: Arc::clone(&self.deactivated_phases),
+            sync_points: Arc::clone(&self.sync_points),
+            abort_requests: Arc::clone(&self.abort_requests),
+            cancelled_phases: Arc::clone(&self.cancelled_phases),
+            executor: Arc::clone(&self.executor),
+            rng: Arc::clone(&self.rng),
+            deterministic: self.deterministic,
+            #[cfg(feature = "profiler")]
+            profiler: Arc::clone(&self.profiler),
+            #[cfg(feature = "replay")]
+            command_log: Arc::clone(&self.command_log),
         }
     }
 }
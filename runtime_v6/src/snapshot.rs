@@ -0,0 +1,83 @@
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::hash::Hash;
+
+use crate::clock::Time;
+use crate::phase_graph::PhaseGraph;
+use crate::rng::SeededRng;
+use crate::runtime::Restart;
+use crate::world::World;
+
+/// [`Runtime::snapshot`]で状態を保存できるWorld。
+///
+/// [`Runtime::snapshot`]: crate::Runtime::snapshot
+pub trait Snapshot: World {
+    /// 保存したWorldの状態。
+    type State: Clone + Send + Sync + 'static;
+    /// 現在の状態を返す関数。
+    fn snapshot(&self) -> Self::State;
+    /// 保存した状態に戻す関数。
+    fn restore(&mut self, state: &Self::State);
+}
+
+/// [`Runtime::snapshot`]で保存したランタイムの状態。
+///
+/// Worldの状態とフレームカウンター、ゲーム時間、乱数生成器、PhaseのActivateの状態を持つ。
+/// 中断しているタスクは保存できないので、保存したときに生きていた
+/// [`Runtime::add_system`]と[`Runtime::add_async_system`]のシステムを[`Runtime::restore`]で登録し直す。
+///
+/// [`Runtime::snapshot`]: crate::Runtime::snapshot
+/// [`Runtime::add_system`]: crate::Runtime::add_system
+/// [`Runtime::add_async_system`]: crate::Runtime::add_async_system
+/// [`Runtime::restore`]: crate::Runtime::restore
+pub struct RuntimeSnapshot<T: Eq + Hash + Clone + Debug, W: Snapshot> {
+    pub(crate) frame_counter: u64,
+    pub(crate) time: Time,
+    pub(crate) world: W::State,
+    pub(crate) rng: SeededRng,
+    pub(crate) activated_phase: PhaseGraph<T>,
+    pub(crate) fixed_phases: HashSet<T>,
+    pub(crate) paused_phases: HashSet<T>,
+    pub(crate) sync_points: HashSet<T>,
+    // 登録し直すシステム。登録された順に並んでいる
+    pub(crate) systems: Vec<Restart<T, W>>,
+}
+impl<T: Eq + Hash + Clone + Debug, W: Snapshot> RuntimeSnapshot<T, W> {
+    /// 保存したときのフレームカウントを返す関数。
+    /// 復元した後の最初の[`Runtime::update`]でこのフレームが実行される。
+    ///
+    /// [`Runtime::update`]: crate::Runtime::update
+    pub fn frame_counter(&self) -> u64 {
+        self.frame_counter
+    }
+
+    /// 保存したときのゲーム時間を返す関数。
+    pub fn time(&self) -> Time {
+        self.time
+    }
+
+    /// 保存したWorldの状態を返す関数。
+    pub fn world(&self) -> &W::State {
+        &self.world
+    }
+
+    /// 復元したときに登録し直すシステムの数を返す関数。
+    pub fn system_count(&self) -> usize {
+        self.systems.len()
+    }
+}
+impl<T: Eq + Hash + Clone + Debug, W: Snapshot> Clone for RuntimeSnapshot<T, W> {
+    fn clone(&self) -> Self {
+        Self {
+            frame_counter: self.frame_counter,
+            time: self.time,
+            world: self.world.clone(),
+            rng: self.rng.clone(),
+            activated_phase: self.activated_phase.clone(),
+            fixed_phases: self.fixed_phases.clone(),
+            paused_phases: self.paused_phases.clone(),
+            sync_points: self.sync_points.clone(),
+            systems: self.systems.clone(),
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::any::type_name;
+use std::fmt::Debug;
+use std::future::Future;
+use std::hash::Hash;
+
+use crate::commands::Commands;
+use crate::component::SystemAccessError;
+use crate::container::Read;
+use crate::resource::Res;
+use crate::rng::SeededRng;
+use crate::runtime::Runtime;
+use crate::world::World;
+
+pub(crate) mod private {
+    use super::*;
+
+    pub trait Extract<T: Eq + Hash + Clone + Debug + 'static, W: World>: Sized {
+        // ランタイムから引数を取り出す。
+        fn extract(runtime: &Runtime<T, W>) -> Result<Self, SystemAccessError<T>>;
+    }
+}
+
+/// 非同期システムの引数として受け取れる値。
+///
+/// [`Read<W>`]と[`Commands<W>`]、[`Runtime`]、[`Res`]、[`SeededRng`]に実装されている。
+pub trait SystemParam<T: Eq + Hash + Clone + Debug + 'static, W: World>:
+    private::Extract<T, W> + Send + 'static
+{
+}
+impl<T, W, P> SystemParam<T, W> for P
+where
+    T: Eq + Hash + Clone + Debug + 'static,
+    W: World,
+    P: private::Extract<T, W> + Send + 'static,
+{
+}
+
+impl<T: Eq + Hash + Clone + Debug + 'static, W: World> private::Extract<T, W> for Read<W> {
+    fn extract(runtime: &Runtime<T, W>) -> Result<Self, SystemAccessError<T>> {
+        Ok(runtime.read_world())
+    }
+}
+
+impl<T: Eq + Hash + Clone + Debug + 'static, W: World> private::Extract<T, W> for Commands<W> {
+    fn extract(runtime: &Runtime<T, W>) -> Result<Self, SystemAccessError<T>> {
+        Ok(runtime.new_commands())
+    }
+}
+
+impl<T: Eq + Hash + Clone + Debug + 'static, W: World> private::Extract<T, W> for Runtime<T, W> {
+    fn extract(runtime: &Runtime<T, W>) -> Result<Self, SystemAccessError<T>> {
+        Ok(runtime.clone())
+    }
+}
+
+impl<T: Eq + Hash + Clone + Debug + 'static, W: World> private::Extract<T, W> for SeededRng {
+    fn extract(runtime: &Runtime<T, W>) -> Result<Self, SystemAccessError<T>> {
+        Ok(runtime.rng())
+    }
+}
+
+impl<T, W, R> private::Extract<T, W> for Res<R>
+where
+    T: Eq + Hash + Clone + Debug + 'static,
+    W: World,
+    R: Send + Sync + 'static,
+{
+    fn extract(runtime: &Runtime<T, W>) -> Result<Self, SystemAccessError<T>> {
+        runtime
+            .resource::<R>()
+            .ok_or_else(|| SystemAccessError::MissingResource(type_name::<R>()))
+    }
+}
+
+/// [`Runtime::add_system`]で登録できる非同期システム。
+///
+/// [`SystemParam`]を0個から6個受け取り、Futureを返す関数に実装されている。
+/// Paramsは引数の型を並べたタプルで、推論されるので指定する必要はない。
+pub trait AsyncSystem<T: Eq + Hash + Clone + Debug + 'static, W: World, Params> {
+    /// システムの結果。
+    type Output: Send + 'static;
+    /// システムを呼び出して返るFuture。
+    type Future: Future<Output = Self::Output> + Send + 'static;
+
+    /// ランタイムから引数を取り出してシステムを呼び出す関数。
+    fn call(self, runtime: &Runtime<T, W>) -> Result<Self::Future, SystemAccessError<T>>;
+}
+
+macro_rules! impl_async_system {
+    ($($p:ident),*) => {
+        impl<T, W, F, Fut, $($p),*> AsyncSystem<T, W, ($($p,)*)> for F
+        where
+            T: Eq + Hash + Clone + Debug + 'static,
+            W: World,
+            F: FnOnce($($p),*) -> Fut,
+            Fut: Future + Send + 'static,
+            Fut::Output: Send + 'static,
+            $($p: SystemParam<T, W>,)*
+        {
+            type Output = Fut::Output;
+            type Future = Fut;
+
+            #[allow(unused_variables)]
+            fn call(self, runtime: &Runtime<T, W>) -> Result<Fut, SystemAccessError<T>> {
+                Ok(self($(<$p as private::Extract<T, W>>::extract(runtime)?),*))
+            }
+        }
+    };
+}
+impl_async_system!();
+impl_async_system!(P1);
+impl_async_system!(P1, P2);
+impl_async_system!(P1, P2, P3);
+impl_async_system!(P1, P2, P3, P4);
+impl_async_system!(P1, P2, P3, P4, P5);
+impl_async_system!(P1, P2, P3, P4, P5, P6);
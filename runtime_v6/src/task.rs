@@ -0,0 +1,318 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::task::ArcWake;
+
+use crate::abort_handle::TaskId;
+use crate::clock::Time;
+#[cfg(feature = "profiler")]
+use crate::profiler::Profiler;
+use crate::rng::SeededRng;
+use crate::timer::TimerWheel;
+
+/// タスクをpollしている間にタスクから参照できるフレームの情報。
+#[derive(Clone)]
+pub(crate) struct FrameContext {
+    /// pollしているランタイムのWorldの識別子。
+    pub(crate) world: usize,
+    pub(crate) frame_counter: u64,
+    pub(crate) time: Time,
+    pub(crate) timers: Arc<Mutex<TimerWheel>>,
+    #[cfg(feature = "profiler")]
+    pub(crate) profiler: Arc<Profiler>,
+}
+
+// pollしているタスク。
+struct CurrentTask {
+    id: TaskId,
+    order: u64,
+    // このタスクがpoll中に作ったタスクやCommandsの数
+    children: u64,
+    rng: Option<SeededRng>,
+}
+
+struct PollState {
+    frame: FrameContext,
+    task: Option<CurrentTask>,
+    // ポーリング中のタスクがnext_frame()で次フレームの実行を予約したかどうか
+    next_frame_requested: bool,
+}
+
+thread_local! {
+    // ワーカースレッドでタスクをpollしている間だけSomeになる。
+    static CURRENT: RefCell<Option<PollState>> = const { RefCell::new(None) };
+}
+
+/// ポーリング中のタスクを次のフレームに実行するよう予約する。
+/// ランタイムの外でpollされている場合はfalseを返す。
+pub(crate) fn request_next_frame() -> bool {
+    CURRENT.with(|current| match current.borrow_mut().as_mut() {
+        Some(state) => {
+            state.next_frame_requested = true;
+            true
+        }
+        None => false,
+    })
+}
+
+/// このスレッドでworldのランタイムのタスクをpollしていれば、そのタスクのIDを返す。
+pub(crate) fn current_task(world: usize) -> Option<TaskId> {
+    CURRENT.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .filter(|state| state.frame.world == world)
+            .and_then(|state| state.task.as_ref())
+            .map(|task| task.id)
+    })
+}
+
+/// タスクのpoll中に作られたタスクやCommandsを並べるためのキー。
+/// 作ったタスクの[`Task::order`]と、そのタスクの中で作られた順番の組になる。
+pub(crate) type ChildKey = (u64, u64);
+
+/// このスレッドでworldのランタイムのタスクをpollしていれば、
+/// そのタスクがこれから作るもののキーを返す。
+pub(crate) fn next_child(world: usize) -> Option<ChildKey> {
+    CURRENT.with(|current| {
+        let mut current = current.borrow_mut();
+        let task = current
+            .as_mut()
+            .filter(|state| state.frame.world == world)?
+            .task
+            .as_mut()?;
+        let key = (task.order, task.children);
+        task.children += 1;
+        Some(key)
+    })
+}
+
+/// このスレッドでworldのランタイムのタスクをpollしていて、
+/// そのタスクが乱数生成器を持っていれば、そこからforkした乱数生成器を返す。
+pub(crate) fn fork_task_rng(world: usize) -> Option<SeededRng> {
+    CURRENT.with(|current| {
+        let mut current = current.borrow_mut();
+        let task = current
+            .as_mut()
+            .filter(|state| state.frame.world == world)?
+            .task
+            .as_mut()?;
+        task.rng.as_mut().map(SeededRng::fork)
+    })
+}
+
+/// ポーリング中のフレームの情報を参照する。
+/// ランタイムの外でpollされている場合はNoneを返す。
+pub(crate) fn with_frame_context<R>(f: impl FnOnce(&FrameContext) -> R) -> Option<R> {
+    CURRENT.with(|current| current.borrow().as_ref().map(|state| f(&state.frame)))
+}
+
+// wakeされたタスクのIDを積んでおくキュー。
+// Phaseの境界でランタイムが取り出して、待機中のタスクを実行可能に戻す。
+pub(crate) type WakeQueue = Arc<Mutex<Vec<TaskId>>>;
+
+struct TaskWaker {
+    id: TaskId,
+    woken: AtomicBool,
+    wake_queue: WakeQueue,
+}
+impl ArcWake for TaskWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        // 既にwakeされている場合はキューに積まない
+        if !arc_self.woken.swap(true, Ordering::AcqRel) {
+            arc_self.wake_queue.lock().unwrap().push(arc_self.id);
+        }
+    }
+}
+
+/// panicのペイロード。
+pub(crate) type PanicPayload = Box<dyn Any + Send + 'static>;
+
+/// JoinHandleにpanicを報告する関数。
+pub(crate) type ReportPanic = Box<dyn FnOnce(PanicPayload) + Send>;
+
+/// タスクがpanicしたときの後始末。
+pub(crate) struct PanicHandler {
+    /// JoinHandleにpanicを報告する。
+    pub(crate) report: ReportPanic,
+    /// TaskPanicPolicy::Restartのときにタスクを作り直す。
+    /// ランタイムの型に依存するので型を消して保持する。
+    pub(crate) restart: Option<Box<dyn Any + Send>>,
+}
+
+/// タスクがどれだけ実行されたか。
+#[derive(Clone, Copy)]
+pub(crate) struct TaskStats {
+    /// タスクが起動されたフレーム。
+    pub(crate) spawn_frame: u64,
+    /// タスクがpollされた回数。
+    pub(crate) poll_count: u64,
+    /// タスクが最後にpollされたフレーム。
+    pub(crate) last_polled_frame: Option<u64>,
+}
+
+pub(crate) struct Task {
+    pub(crate) id: TaskId,
+    /// ランタイムにタスクが追加された順番。
+    /// poll中に起動されたタスクは、Phaseの後に起動したタスクの順番に並べてから決まる。
+    pub(crate) order: u64,
+    pub(crate) name: Option<String>,
+    pub(crate) stats: TaskStats,
+    /// タスクのpollごとのspanの親になるspan。
+    #[cfg(feature = "tracing")]
+    pub(crate) span: tracing::Span,
+    pub(crate) on_panic: PanicHandler,
+    future: Pin<Box<dyn Future<Output = ()> + Send + 'static>>,
+    waker: Arc<TaskWaker>,
+    children: u64,
+    // 決定的モードでは、poll中にforkする乱数生成器をタスクごとに持つ
+    rng: Option<SeededRng>,
+}
+impl Task {
+    pub(crate) fn new(
+        id: TaskId,
+        name: Option<String>,
+        spawn_frame: u64,
+        f: impl Future<Output = ()> + Send + 'static,
+        on_panic: PanicHandler,
+        wake_queue: WakeQueue,
+        rng: Option<SeededRng>,
+    ) -> Self {
+        // タスクは起動したタスクより長く生きることがあるので、親のないspanにする
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            parent: None,
+            "task",
+            id = %id,
+            name = tracing::field::Empty,
+            spawn_frame
+        );
+        #[cfg(feature = "tracing")]
+        if let Some(name) = &name {
+            span.record("name", name.as_str());
+        }
+
+        Self {
+            id,
+            order: 0,
+            name,
+            #[cfg(feature = "tracing")]
+            span,
+            stats: TaskStats {
+                spawn_frame,
+                poll_count: 0,
+                last_polled_frame: None,
+            },
+            on_panic,
+            future: Box::pin(f),
+            waker: Arc::new(TaskWaker {
+                id,
+                woken: AtomicBool::new(false),
+                wake_queue,
+            }),
+            children: 0,
+            rng,
+        }
+    }
+
+    // panicした場合はワーカースレッドに伝えずにペイロードを返す。
+    fn poll(&mut self) -> Result<Poll<()>, PanicPayload> {
+        let waker = futures::task::waker_ref(&self.waker);
+        let mut ctx = Context::from_waker(&waker);
+        let future = self.future.as_mut();
+        panic::catch_unwind(AssertUnwindSafe(|| Future::poll(future, &mut ctx)))
+    }
+
+    /// panicしたタスクのJoinHandleにpayloadを報告してから破棄する関数。
+    /// タスクを作り直すための関数を返す。
+    pub(crate) fn finish_panicked(self, payload: PanicPayload) -> Option<Box<dyn Any + Send>> {
+        let Task {
+            on_panic, future, ..
+        } = self;
+        // 先にFutureをdropするとJoinHandleにキャンセルが報告されてしまう
+        (on_panic.report)(payload);
+        drop(future);
+        on_panic.restart
+    }
+
+    fn take_woken(&self) -> bool {
+        self.waker.woken.swap(false, Ordering::AcqRel)
+    }
+}
+
+/// タスクを1回pollした結果。
+pub(crate) enum PollOutcome {
+    /// タスクが完了した。
+    Ready,
+    /// next_frame()で次のフレームの実行を予約した。
+    NextFrame(Task),
+    /// poll中にwakeされたので、このPhaseの中でもう一度pollする。
+    Woken(Task),
+    /// wakeされるまで実行しない。
+    Parked(Task),
+    /// poll中にpanicした。
+    Panicked(Task, PanicPayload),
+}
+
+/// このスレッドでタスクをpollする間のフレームの情報を設定する。
+/// [`exit`]を呼ぶまで有効。
+pub(crate) fn enter(frame: FrameContext) {
+    CURRENT.with(|current| {
+        *current.borrow_mut() = Some(PollState {
+            frame,
+            task: None,
+            next_frame_requested: false,
+        })
+    });
+}
+
+/// [`enter`]で設定したフレームの情報を破棄する。
+pub(crate) fn exit() {
+    CURRENT.with(|current| *current.borrow_mut() = None);
+}
+
+/// タスクを1回pollする。[`enter`]してから呼び出す。
+pub(crate) fn poll_task(mut task: Task) -> PollOutcome {
+    // poll前にwakeフラグを下ろしておき、poll中や後のwakeを検出する
+    task.take_woken();
+
+    CURRENT.with(|current| {
+        let mut current = current.borrow_mut();
+        let state = current.as_mut().expect("poll_task called outside of enter");
+        state.task = Some(CurrentTask {
+            id: task.id,
+            order: task.order,
+            children: task.children,
+            rng: task.rng.take(),
+        });
+    });
+    let poll = task.poll();
+    let (frame_counter, next_frame_requested) = CURRENT.with(|current| {
+        let mut current = current.borrow_mut();
+        let state = current.as_mut().expect("poll_task called outside of enter");
+        let current_task = state.task.take().expect("polled task was not recorded");
+        task.children = current_task.children;
+        task.rng = current_task.rng;
+        (
+            state.frame.frame_counter,
+            std::mem::replace(&mut state.next_frame_requested, false),
+        )
+    });
+    task.stats.poll_count += 1;
+    task.stats.last_polled_frame = Some(frame_counter);
+
+    match poll {
+        Err(payload) => PollOutcome::Panicked(task, payload),
+        Ok(Poll::Ready(())) => PollOutcome::Ready,
+        // 次フレームを予約したタスクはwakeされていても次のフレームまで待つ
+        Ok(Poll::Pending) if next_frame_requested => PollOutcome::NextFrame(task),
+        Ok(Poll::Pending) if task.take_woken() => PollOutcome::Woken(task),
+        Ok(Poll::Pending) => PollOutcome::Parked(task),
+    }
+}
@@ -0,0 +1,128 @@
+use std::any::Any;
+use std::fmt::{self, Debug};
+
+use crate::abort_handle::TaskId;
+
+/// タスクがpanicしたことを表すイベント。
+pub struct TaskPanicked<T> {
+    task_id: TaskId,
+    task_name: Option<String>,
+    phase: T,
+    frame: u64,
+    payload: Box<dyn Any + Send + 'static>,
+}
+impl<T> TaskPanicked<T> {
+    pub(crate) fn new(
+        task_id: TaskId,
+        task_name: Option<String>,
+        phase: T,
+        frame: u64,
+        payload: Box<dyn Any + Send + 'static>,
+    ) -> Self {
+        Self {
+            task_id,
+            task_name,
+            phase,
+            frame,
+            payload,
+        }
+    }
+
+    /// panicしたタスクのIDを返す関数。
+    pub fn task_id(&self) -> TaskId {
+        self.task_id
+    }
+
+    /// panicしたタスクの名前を返す関数。
+    /// 名前のないタスクの場合はNoneを返す。
+    pub fn task_name(&self) -> Option<&str> {
+        self.task_name.as_deref()
+    }
+
+    /// タスクが登録されていたPhaseを返す関数。
+    pub fn phase(&self) -> &T {
+        &self.phase
+    }
+
+    /// タスクがpanicしたフレームを返す関数。
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// panicのペイロードを返す関数。
+    pub fn payload(&self) -> &(dyn Any + Send + 'static) {
+        &*self.payload
+    }
+
+    /// panicのペイロードを取り出す関数。
+    pub fn into_payload(self) -> Box<dyn Any + Send + 'static> {
+        self.payload
+    }
+}
+impl<T: Debug> Debug for TaskPanicked<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskPanicked")
+            .field("task_id", &self.task_id)
+            .field("task_name", &self.task_name)
+            .field("phase", &self.phase)
+            .field("frame", &self.frame)
+            .field("payload", &panic_message(&*self.payload))
+            .finish()
+    }
+}
+impl<T: Debug> fmt::Display for TaskPanicked<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.task_name {
+            Some(name) => write!(f, "task {} (id {})", name, self.task_id)?,
+            None => write!(f, "task {}", self.task_id)?,
+        }
+        write!(
+            f,
+            " panicked in PHASE {:?} at frame {}: {}",
+            self.phase,
+            self.frame,
+            panic_message(&*self.payload)
+        )
+    }
+}
+impl<T: Debug> std::error::Error for TaskPanicked<T> {}
+
+/// タスクがpanicしたときにランタイムがどうするか。
+///
+/// どのポリシーでもpanicしたタスクは破棄され、ワーカースレッドは実行を続ける。
+/// タスクの[`JoinHandle`]にはpanicのメッセージを持つ[`JoinError::Panicked`]が報告される。
+///
+/// [`JoinHandle`]: crate::JoinHandle
+/// [`JoinError::Panicked`]: crate::JoinError::Panicked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskPanicPolicy {
+    /// フレームの終わりに[`Runtime::update`]が最初の[`TaskPanicked`]をエラーとして返す。
+    /// 2つ目以降のイベントは[`Runtime::task_panics`]で取り出せる。
+    ///
+    /// [`Runtime::update`]: crate::Runtime::update
+    /// [`Runtime::task_panics`]: crate::Runtime::task_panics
+    Abort,
+    /// [`Runtime::add_system`]と[`Runtime::add_async_system`]で登録したシステムを
+    /// 同じPhaseに登録し直し、次のフレームから実行する。
+    /// それ以外のタスクはIgnoreと同じく破棄するだけになる。
+    ///
+    /// [`Runtime::add_system`]: crate::Runtime::add_system
+    /// [`Runtime::add_async_system`]: crate::Runtime::add_async_system
+    Restart,
+    /// タスクを破棄して実行を続ける。デフォルト。
+    /// イベントは[`Runtime::task_panics`]で取り出せる。
+    ///
+    /// [`Runtime::task_panics`]: crate::Runtime::task_panics
+    Ignore,
+}
+
+/// panicのペイロードからメッセージを取り出す。
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
+}
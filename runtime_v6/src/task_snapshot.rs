@@ -0,0 +1,94 @@
+use std::fmt::{self, Debug};
+
+use crate::abort_handle::TaskId;
+use crate::task::Task;
+
+/// タスクが次にpollされる条件。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Phaseが次に実行されるときにpollされる。
+    Runnable,
+    /// wakeされるまでpollされない。
+    Parked,
+}
+
+/// [`Runtime::tasks_snapshot`]で取得する、生きているタスクの状態。
+///
+/// [`Runtime::tasks_snapshot`]: crate::Runtime::tasks_snapshot
+#[derive(Debug, Clone)]
+pub struct TaskSnapshot<T> {
+    id: TaskId,
+    name: Option<String>,
+    phase: T,
+    spawn_frame: u64,
+    poll_count: u64,
+    last_polled_frame: Option<u64>,
+    state: TaskState,
+}
+impl<T> TaskSnapshot<T> {
+    pub(crate) fn new(task: &Task, phase: T, state: TaskState) -> Self {
+        Self {
+            id: task.id,
+            name: task.name.clone(),
+            phase,
+            spawn_frame: task.stats.spawn_frame,
+            poll_count: task.stats.poll_count,
+            last_polled_frame: task.stats.last_polled_frame,
+            state,
+        }
+    }
+
+    /// タスクのIDを返す関数。
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// タスクの名前を返す関数。
+    /// 名前のないタスクの場合はNoneを返す。
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// タスクが登録されているPhaseを返す関数。
+    pub fn phase(&self) -> &T {
+        &self.phase
+    }
+
+    /// タスクが起動されたフレームを返す関数。
+    pub fn spawn_frame(&self) -> u64 {
+        self.spawn_frame
+    }
+
+    /// タスクがpollされた回数を返す関数。
+    pub fn poll_count(&self) -> u64 {
+        self.poll_count
+    }
+
+    /// タスクが最後にpollされたフレームを返す関数。
+    /// まだpollされていない場合はNoneを返す。
+    pub fn last_polled_frame(&self) -> Option<u64> {
+        self.last_polled_frame
+    }
+
+    /// タスクがparkされているか実行可能かを返す関数。
+    pub fn state(&self) -> TaskState {
+        self.state
+    }
+}
+impl<T: Debug> fmt::Display for TaskSnapshot<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "task {} (id {})", name, self.id)?,
+            None => write!(f, "task {}", self.id)?,
+        }
+        write!(
+            f,
+            " in PHASE {:?}: {:?}, spawned at frame {}, polled {} times",
+            self.phase, self.state, self.spawn_frame, self.poll_count
+        )?;
+        match self.last_polled_frame {
+            Some(frame) => write!(f, ", last at frame {}", frame),
+            None => Ok(()),
+        }
+    }
+}
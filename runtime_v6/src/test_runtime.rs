@@ -0,0 +1,187 @@
+use std::fmt::{self, Debug, Display};
+use std::hash::Hash;
+use std::ops::Deref;
+use std::time::Duration;
+
+use crate::builder::RuntimeBuilder;
+use crate::clock::FixedStepClock;
+use crate::commands::{Commands, FlushPoint};
+use crate::container::Inspecting;
+use crate::rng::SeededRng;
+use crate::runtime::{Runtime, RuntimeIsDone};
+use crate::update_error::UpdateError;
+use crate::world::World;
+
+/// [`TestRuntime::with_world`]で作るRuntimeの1フレームの時間。
+const TEST_FRAME_DURATION: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+/// [`TestRuntime::run_until`]が条件を満たす前に止まったときのエラー。
+#[derive(Debug)]
+pub enum RunUntilError<T, E> {
+    /// フレームの更新に失敗した。
+    Update(UpdateError<T, E>),
+    /// 指定したフレーム数を進めても条件を満たさなかった。
+    FrameLimit(u64),
+    /// 条件を満たす前にタスクがすべて終了した。値は進めたフレーム数。
+    Finished(u64),
+}
+impl<T: Debug, E: Display> Display for RunUntilError<T, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunUntilError::Update(error) => write!(f, "{}", error),
+            RunUntilError::FrameLimit(frames) => {
+                write!(f, "condition was not met within {} frames", frames)
+            }
+            RunUntilError::Finished(frames) => write!(
+                f,
+                "all tasks finished after {} frames before the condition was met",
+                frames
+            ),
+        }
+    }
+}
+impl<T: Debug + 'static, E: std::error::Error + 'static> std::error::Error for RunUntilError<T, E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RunUntilError::Update(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+impl<T, E> From<UpdateError<T, E>> for RunUntilError<T, E> {
+    fn from(error: UpdateError<T, E>) -> Self {
+        RunUntilError::Update(error)
+    }
+}
+
+/// [`TestRuntime::world`]が返す、フレームの間のWorldへの読み取り専用のアクセス。
+///
+/// 同時に複数参照できるが、存在する間はフレームを進められない。
+pub struct WorldRef<'a, W: 'static> {
+    world: Inspecting<'a, W>,
+}
+impl<W: 'static> Deref for WorldRef<'_, W> {
+    type Target = W;
+
+    fn deref(&self) -> &Self::Target {
+        &self.world
+    }
+}
+
+/// 端末やウィンドウを使わずにフレームを1つずつ進めて、Worldを確認するためのランタイム。
+///
+/// フレームの間に[`TestRuntime::world`]でWorldを参照し、
+/// [`TestRuntime::send`]でテストからコマンドを送れる。
+/// 送ったコマンドは次のフレームの最初のPhaseの前に適用される。
+pub struct TestRuntime<T: Eq + Hash + Clone + Debug, W: World> {
+    runtime: Runtime<T, W>,
+    commands: Commands<W>,
+    frames: u64,
+}
+impl<T: Eq + Hash + Clone + Debug + 'static, W: World> TestRuntime<T, W> {
+    /// runtimeを進めるTestRuntimeを作る関数。
+    pub fn new(runtime: Runtime<T, W>) -> Self {
+        let commands = runtime.new_commands().flush_at(FlushPoint::NextFrame);
+        Self {
+            runtime,
+            commands,
+            frames: 0,
+        }
+    }
+
+    /// worldから結果が再現できるRuntimeを作って、TestRuntimeを作る関数。
+    ///
+    /// Runtimeは決定的モードで、乱数のシードは0、ゲーム時間は1フレームごとに1/60秒進む。
+    pub fn with_world(world: W) -> Self {
+        let runtime = RuntimeBuilder::new()
+            .deterministic()
+            .rng(SeededRng::new(0))
+            .clock(FixedStepClock::new(TEST_FRAME_DURATION))
+            .build(world);
+        Self::new(runtime)
+    }
+
+    /// 進めているRuntimeを返す関数。
+    /// PhaseのActivateやシステムの登録に使う。
+    pub fn runtime(&self) -> &Runtime<T, W> {
+        &self.runtime
+    }
+
+    /// 進めているRuntimeを返す関数。
+    pub fn runtime_mut(&mut self) -> &mut Runtime<T, W> {
+        &mut self.runtime
+    }
+
+    /// TestRuntimeで進めたフレームの数を返す関数。
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// 現在のWorldを返す関数。
+    ///
+    /// ## panic
+    /// 返り値を[`Runtime::update`]の途中で参照した場合、panicする。
+    pub fn world(&self) -> WorldRef<'_, W> {
+        WorldRef {
+            world: self.runtime.inspect_world(),
+        }
+    }
+
+    /// 次のフレームの最初に適用するコマンドを送る関数。
+    pub fn send(&self, command: W::Command) {
+        self.commands.send(command);
+    }
+
+    /// テストからコマンドを送るための[`Commands`]を返す関数。
+    /// 送ったコマンドは次のフレームの最初に適用される。
+    pub fn commands(&self) -> Commands<W> {
+        self.commands.clone()
+    }
+
+    /// 1フレーム進める関数。
+    pub fn step(&mut self) -> Result<RuntimeIsDone, UpdateError<T, W::Error>> {
+        let done = self.runtime.update()?;
+        self.frames += 1;
+        Ok(done)
+    }
+
+    /// nフレーム進める関数。
+    /// 途中でタスクがすべて終了した場合は、そこで止めて[`RuntimeIsDone::Done`]を返す。
+    pub fn step_frames(&mut self, n: u64) -> Result<RuntimeIsDone, UpdateError<T, W::Error>> {
+        for _ in 0..n {
+            if let RuntimeIsDone::Done = self.step()? {
+                return Ok(RuntimeIsDone::Done);
+            }
+        }
+        Ok(RuntimeIsDone::NotDone)
+    }
+
+    /// フレームの間にWorldがconditionを満たすまでフレームを進める関数。
+    /// 進めたフレームの数を返す。最初から満たしている場合は0を返す。
+    ///
+    /// max_framesを進めても満たさなかった場合や、満たす前にタスクがすべて終了した場合はエラーを返す。
+    pub fn run_until<F>(
+        &mut self,
+        max_frames: u64,
+        mut condition: F,
+    ) -> Result<u64, RunUntilError<T, W::Error>>
+    where
+        F: FnMut(&W) -> bool,
+    {
+        for frames in 0..=max_frames {
+            if condition(&self.world()) {
+                return Ok(frames);
+            }
+            if frames == max_frames {
+                break;
+            }
+            if let RuntimeIsDone::Done = self.step()? {
+                if condition(&self.world()) {
+                    return Ok(frames + 1);
+                }
+                return Err(RunUntilError::Finished(frames + 1));
+            }
+        }
+        Err(RunUntilError::FrameLimit(max_frames))
+    }
+}
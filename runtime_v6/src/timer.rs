@@ -0,0 +1,332 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use crate::task::with_frame_context;
+
+// ホイールのスロット数。
+// これより先のフレームを待つタイマーはoverflowに置き、近づいてからホイールに移す。
+const WHEEL_SLOTS: u64 = 64;
+
+// ホイールとタイマーのFutureで共有するwaker。
+// Futureは再pollのたびに登録し直さずにwakerだけを差し替え、破棄されたときはNoneにする。
+type TimerWaker = Arc<Mutex<Option<Waker>>>;
+
+struct Entry<K> {
+    deadline: K,
+    seq: u64,
+    waker: TimerWaker,
+}
+impl<K: Ord> PartialEq for Entry<K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl<K: Ord> Eq for Entry<K> {}
+impl<K: Ord> PartialOrd for Entry<K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl<K: Ord> Ord for Entry<K> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (&self.deadline, self.seq).cmp(&(&other.deadline, other.seq))
+    }
+}
+
+/// フレーム単位と経過時間単位のタイマーを管理するタイマーホイール。
+///
+/// フレーム単位のタイマーは`WHEEL_SLOTS`個のスロットに振り分けられ、
+/// フレームを進めるときには該当するスロット1つだけを取り出す。
+/// 長時間のタイマーはヒープに置かれるので、待っている間のフレームごとのコストはかからない。
+pub(crate) struct TimerWheel {
+    current_frame: u64,
+    elapsed: Duration,
+    seq: u64,
+    slots: Vec<Vec<TimerWaker>>,
+    overflow: BinaryHeap<Reverse<Entry<u64>>>,
+    durations: BinaryHeap<Reverse<Entry<Duration>>>,
+}
+impl TimerWheel {
+    pub(crate) fn new() -> Self {
+        Self {
+            current_frame: 0,
+            elapsed: Duration::from_secs(0),
+            seq: 0,
+            slots: (0..WHEEL_SLOTS).map(|_| vec![]).collect(),
+            overflow: BinaryHeap::new(),
+            durations: BinaryHeap::new(),
+        }
+    }
+
+    /// タイマーをすべて破棄して、指定したフレームと経過時間まで進めた状態にする。
+    pub(crate) fn reset(&mut self, current_frame: u64, elapsed: Duration) {
+        *self = Self {
+            current_frame,
+            elapsed,
+            ..Self::new()
+        };
+    }
+
+    /// 登録されているタイマーの数を返す。
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.slots.iter().map(Vec::len).sum::<usize>() + self.overflow.len() + self.durations.len()
+    }
+
+    /// 指定したフレームの開始時にwakerをwakeするタイマーを登録する。
+    fn register_frame(&mut self, frame: u64, waker: TimerWaker) {
+        if frame <= self.current_frame {
+            wake(&waker);
+        } else if frame - self.current_frame <= WHEEL_SLOTS {
+            self.slots[(frame % WHEEL_SLOTS) as usize].push(waker);
+        } else {
+            self.seq += 1;
+            self.overflow.push(Reverse(Entry {
+                deadline: frame,
+                seq: self.seq,
+                waker,
+            }));
+        }
+    }
+
+    /// 経過時間がdeadlineを超えたフレームの開始時にwakerをwakeするタイマーを登録する。
+    fn register_elapsed(&mut self, deadline: Duration, waker: TimerWaker) {
+        if deadline <= self.elapsed {
+            wake(&waker);
+        } else {
+            self.seq += 1;
+            self.durations.push(Reverse(Entry {
+                deadline,
+                seq: self.seq,
+                waker,
+            }));
+        }
+    }
+
+    /// フレームと経過時間を進め、期限の来たタイマーのwakerを返す。
+    pub(crate) fn advance(&mut self, frame: u64, elapsed: Duration) -> Vec<Waker> {
+        let mut expired: Vec<TimerWaker> = vec![];
+
+        while self.current_frame < frame {
+            self.current_frame += 1;
+            let slot = (self.current_frame % WHEEL_SLOTS) as usize;
+            expired.append(&mut self.slots[slot]);
+
+            // ホイールの範囲に入ったタイマーをoverflowから移す
+            while let Some(Reverse(entry)) = self.overflow.peek() {
+                if entry.deadline - self.current_frame > WHEEL_SLOTS {
+                    break;
+                }
+                let Reverse(entry) = self.overflow.pop().unwrap();
+                if entry.deadline <= self.current_frame {
+                    expired.push(entry.waker);
+                } else {
+                    self.slots[(entry.deadline % WHEEL_SLOTS) as usize].push(entry.waker);
+                }
+            }
+        }
+
+        self.elapsed = elapsed;
+        while let Some(Reverse(entry)) = self.durations.peek() {
+            if entry.deadline > self.elapsed {
+                break;
+            }
+            let Reverse(entry) = self.durations.pop().unwrap();
+            expired.push(entry.waker);
+        }
+
+        // 破棄されたFutureのタイマーはwakerがNoneになっている
+        expired
+            .iter()
+            .filter_map(|waker| waker.lock().unwrap().take())
+            .collect()
+    }
+}
+
+fn wake(waker: &TimerWaker) {
+    if let Some(waker) = waker.lock().unwrap().take() {
+        waker.wake();
+    }
+}
+
+/// タイマーのFutureが登録したwaker。
+#[derive(Default)]
+struct Registration {
+    waker: TimerWaker,
+}
+impl Registration {
+    /// ホイールに登録されていればwakerを差し替えてtrueを返す。
+    /// 期限が来たり、ホイールがリセットされたりして登録が外れている場合はfalseを返す。
+    fn update(&self, waker: &Waker) -> bool {
+        // ホイールが持っていなければ参照はこのFutureだけになる
+        if Arc::strong_count(&self.waker) == 1 {
+            return false;
+        }
+        let mut registered = self.waker.lock().unwrap();
+        match &*registered {
+            Some(registered) if registered.will_wake(waker) => {}
+            _ => *registered = Some(waker.clone()),
+        }
+        true
+    }
+
+    /// ホイールに渡すwakerを作る。
+    fn register(&mut self, waker: &Waker) -> TimerWaker {
+        self.waker = Arc::new(Mutex::new(Some(waker.clone())));
+        Arc::clone(&self.waker)
+    }
+}
+impl Drop for Registration {
+    fn drop(&mut self) {
+        // 期限が来てもwakeしないように、ホイールに残っているwakerを外す
+        self.waker.lock().unwrap().take();
+    }
+}
+
+/// 指定したフレームになるまで待機するFuture。
+pub struct WaitFrames {
+    frames: u64,
+    target: Option<u64>,
+    registration: Registration,
+}
+impl Future for WaitFrames {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let frames = self.frames;
+        let target = *self
+            .target
+            .get_or_insert_with(|| current_frame_counter() + frames);
+
+        with_frame_context(|ctx| {
+            if ctx.frame_counter >= target {
+                Poll::Ready(())
+            } else {
+                // 登録済みならwakerを差し替えるだけにして、ホイールにタイマーを積み増さない
+                if !self.registration.update(cx.waker()) {
+                    let waker = self.registration.register(cx.waker());
+                    ctx.timers.lock().unwrap().register_frame(target, waker);
+                }
+                Poll::Pending
+            }
+        })
+        .expect(OUTSIDE_RUNTIME)
+    }
+}
+
+/// 指定した時間が経過するまで待機するFuture。
+pub struct WaitFor {
+    duration: Duration,
+    deadline: Option<Duration>,
+    registration: Registration,
+}
+impl Future for WaitFor {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let duration = self.duration;
+        let deadline = *self
+            .deadline
+            .get_or_insert_with(|| current_elapsed() + duration);
+
+        with_frame_context(|ctx| {
+            if ctx.time.elapsed() >= deadline {
+                Poll::Ready(())
+            } else {
+                if !self.registration.update(cx.waker()) {
+                    let waker = self.registration.register(cx.waker());
+                    ctx.timers.lock().unwrap().register_elapsed(deadline, waker);
+                }
+                Poll::Pending
+            }
+        })
+        .expect(OUTSIDE_RUNTIME)
+    }
+}
+
+/// [`timeout_frames`]で待機するフレーム数を超えたことを表すエラー。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed(());
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "deadline has elapsed")
+    }
+}
+impl std::error::Error for Elapsed {}
+
+/// 指定したフレーム数以内にFutureが完了しなければエラーを返すFuture。
+pub struct Timeout<F> {
+    future: Pin<Box<F>>,
+    delay: WaitFrames,
+}
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = self.future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut self.delay).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed(()))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+const OUTSIDE_RUNTIME: &str = "timer futures must be polled inside the runtime";
+
+fn current_frame_counter() -> u64 {
+    with_frame_context(|ctx| ctx.frame_counter).expect(OUTSIDE_RUNTIME)
+}
+
+fn current_elapsed() -> Duration {
+    with_frame_context(|ctx| ctx.time.elapsed()).expect(OUTSIDE_RUNTIME)
+}
+
+/// nフレーム待機するFutureを返す関数。
+/// `wait_frames(1)`は`next_frame()`と同じタイミングで完了する。
+///
+/// 待機中のタスクはpollされないので、長い待機でもフレームごとのコストはかからない。
+pub fn wait_frames(n: u64) -> WaitFrames {
+    WaitFrames {
+        frames: n,
+        target: None,
+        registration: Registration::default(),
+    }
+}
+
+/// フレームカウンターが指定した値になるまで待機するFutureを返す関数。
+pub fn wait_until_frame(frame_counter: u64) -> WaitFrames {
+    WaitFrames {
+        frames: 0,
+        target: Some(frame_counter),
+        registration: Registration::default(),
+    }
+}
+
+/// 指定した時間が経過するまで待機するFutureを返す関数。
+/// 経過時間はランタイムの[`Clock`]によるゲーム時間で、time_scaleの影響を受ける。
+/// 経過時間はフレームの開始時に更新されるので、完了はフレーム単位になる。
+///
+/// [`Clock`]: crate::Clock
+pub fn wait_for(duration: Duration) -> WaitFor {
+    WaitFor {
+        duration,
+        deadline: None,
+        registration: Registration::default(),
+    }
+}
+
+/// nフレーム以内にFutureが完了しなければ[`Elapsed`]を返すFutureを返す関数。
+pub fn timeout_frames<F: Future>(n: u64, future: F) -> Timeout<F> {
+    Timeout {
+        future: Box::pin(future),
+        delay: wait_frames(n),
+    }
+}
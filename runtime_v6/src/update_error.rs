@@ -0,0 +1,44 @@
+use std::fmt::{self, Debug, Display};
+
+use crate::command_error::CommandError;
+#[cfg(feature = "replay")]
+use crate::replay::ReplayDesync;
+use crate::task_panic::TaskPanicked;
+
+/// [`Runtime::update`]が返すエラー。
+///
+/// [`Runtime::update`]: crate::Runtime::update
+#[derive(Debug)]
+pub enum UpdateError<T, E> {
+    /// [`CommandErrorPolicy::Fail`]でコマンドが拒否された。
+    ///
+    /// [`CommandErrorPolicy::Fail`]: crate::CommandErrorPolicy::Fail
+    CommandRejected(CommandError<T, E>),
+    /// [`TaskPanicPolicy::Abort`]でタスクがpanicした。
+    ///
+    /// [`TaskPanicPolicy::Abort`]: crate::TaskPanicPolicy::Abort
+    TaskPanicked(TaskPanicked<T>),
+    /// 再生中に、記録したコマンドを記録したときと同じ時点で適用できなかった。
+    #[cfg(feature = "replay")]
+    ReplayDesync(ReplayDesync<T>),
+}
+impl<T: Debug, E: Display> Display for UpdateError<T, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpdateError::CommandRejected(error) => write!(f, "{}", error),
+            UpdateError::TaskPanicked(panicked) => write!(f, "{}", panicked),
+            #[cfg(feature = "replay")]
+            UpdateError::ReplayDesync(desync) => write!(f, "{}", desync),
+        }
+    }
+}
+impl<T: Debug, E: std::error::Error + 'static> std::error::Error for UpdateError<T, E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            UpdateError::CommandRejected(error) => error.source(),
+            UpdateError::TaskPanicked(_) => None,
+            #[cfg(feature = "replay")]
+            UpdateError::ReplayDesync(_) => None,
+        }
+    }
+}
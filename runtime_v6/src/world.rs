@@ -1,4 +1,10 @@
-pub trait World: 'static {
+use std::fmt::Debug;
+
+pub trait World: Send + Sync + 'static {
     type Command;
-    fn process_command(&mut self, cmd: Self::Command);
+    /// コマンドを拒否したときのエラー。
+    type Error: Debug + Send + 'static;
+    /// コマンドをWorldに適用する関数。
+    /// 適用できないコマンドはWorldを変更せずにエラーを返す。
+    fn process_command(&mut self, cmd: Self::Command) -> Result<(), Self::Error>;
 }
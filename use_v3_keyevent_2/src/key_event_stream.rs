@@ -1,8 +1,8 @@
 use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Receiver, TryRecvError};
-use std::sync::Arc;
-use std::task::{Context, Poll};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
 use std::thread;
 
 use crossterm::event::{read, Event, KeyEvent};
@@ -10,6 +10,7 @@ use futures::Stream;
 
 pub struct KeyEventStream {
     receiver: Receiver<KeyEvent>,
+    waker: Arc<Mutex<Option<Waker>>>,
     stop_flag: Arc<AtomicBool>,
 }
 impl KeyEventStream {
@@ -17,10 +18,18 @@ impl KeyEventStream {
         let (sender, receiver) = channel();
         let stop_flag = Arc::new(AtomicBool::new(false));
         let stop_flag_2 = stop_flag.clone();
+        let waker = Arc::new(Mutex::new(None::<Waker>));
+        let waker_2 = waker.clone();
         thread::spawn(move || loop {
             let evt = read();
             match evt {
-                Ok(Event::Key(evt)) => sender.send(evt).unwrap(),
+                Ok(Event::Key(evt)) => {
+                    sender.send(evt).unwrap();
+                    // ランタイムはwakeされるまでタスクをpollしないので起こしてやる
+                    if let Some(waker) = waker_2.lock().unwrap().take() {
+                        waker.wake();
+                    }
+                }
                 Ok(_) => (),
                 Err(err) => {
                     println!("{:?}", err);
@@ -33,6 +42,7 @@ impl KeyEventStream {
         });
         Self {
             receiver,
+            waker,
             stop_flag,
         }
     }
@@ -45,7 +55,9 @@ impl Drop for KeyEventStream {
 impl Stream for KeyEventStream {
     type Item = KeyEvent;
 
-    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // 受信を試す前にwakerを登録しておき、その間に届いたイベントを取りこぼさないようにする
+        *self.waker.lock().unwrap() = Some(cx.waker().clone());
         match self.receiver.try_recv() {
             Ok(evt) => Poll::Ready(Some(evt)),
             Err(TryRecvError::Empty) => Poll::Pending,
@@ -53,7 +53,7 @@ fn main() {
         let frame_start = Instant::now();
         let frame_duration = Duration::from_millis(83);
 
-        match runtime.update() {
+        match runtime.update().unwrap() {
             RuntimeIsDone::Done => break 'update_loop,
             RuntimeIsDone::NotDone => (),
         }
@@ -111,7 +111,7 @@ fn main() {
         let frame_start = Instant::now();
         let frame_duration = Duration::new(1, 0);
 
-        match runtime.update() {
+        match runtime.update().unwrap() {
             RuntimeIsDone::Done => break 'update_loop,
             RuntimeIsDone::NotDone => (),
         }
@@ -1,72 +1,69 @@
-#![allow(unused_must_use)]
-
-use std::sync::mpsc::Sender;
-
-use rand::prelude::*;
-
-use runtime_v6::{next_frame, Read, Runtime};
+use runtime_v6::{next_frame, Commands, Read, Runtime, SeededRng};
 
 use crate::world::{Direction, EnemyCommand, GameCommand, GameWorld, HEIGHT, WIDTH};
 use crate::Phase;
 
 pub async fn enemy_system(
     world: Read<GameWorld>,
-    sender: Sender<GameCommand>,
-    _runtime: Runtime<Phase, GameWorld>,
+    commands: Commands<GameWorld>,
+    runtime: Runtime<Phase, GameWorld>,
 ) {
-    let mut i = 0;
-
+    // 巻き戻したときに同じ動きになるように、フレームをまたぐ状態は持たず
+    // フレームカウントとWorldのシードから動きを決める
     'update_loop: loop {
-        if i % 8 == 0 {
-            let mut rng = rand::thread_rng();
-
-            for (index, e) in world.enemies.iter().enumerate() {
-                if e.dead {
-                    continue;
-                }
+        let frame = runtime.frame_counter();
+        if frame.is_multiple_of(8) {
+            world.with(|world| {
+                let mut rng = SeededRng::new(world.seed.wrapping_add(frame));
+                for (index, e) in world.enemies.iter().enumerate() {
+                    if e.dead {
+                        continue;
+                    }
 
-                let dir = rng.gen_range(0..4);
-                match dir {
-                    0 => {
-                        if e.x > 0 {
-                            sender.send(GameCommand::Enemy(EnemyCommand::Move(
-                                index,
-                                Direction::Left,
-                            )));
+                    let dir = rng.gen_range(0..4);
+                    match dir {
+                        0 => {
+                            if e.x > 0 {
+                                commands.send(GameCommand::Enemy(EnemyCommand::Move(
+                                    index,
+                                    Direction::Left,
+                                )));
+                            }
                         }
-                    }
-                    1 => {
-                        if e.x < WIDTH - 1 {
-                            sender.send(GameCommand::Enemy(EnemyCommand::Move(
-                                index,
-                                Direction::Right,
-                            )));
+                        1 => {
+                            if e.x < WIDTH - 1 {
+                                commands.send(GameCommand::Enemy(EnemyCommand::Move(
+                                    index,
+                                    Direction::Right,
+                                )));
+                            }
                         }
-                    }
-                    2 => {
-                        if e.y > 0 {
-                            sender
-                                .send(GameCommand::Enemy(EnemyCommand::Move(index, Direction::Up)));
+                        2 => {
+                            if e.y > 0 {
+                                commands.send(GameCommand::Enemy(EnemyCommand::Move(
+                                    index,
+                                    Direction::Up,
+                                )));
+                            }
                         }
-                    }
-                    3 => {
-                        if e.y < HEIGHT - 1 {
-                            sender.send(GameCommand::Enemy(EnemyCommand::Move(
-                                index,
-                                Direction::Down,
-                            )));
+                        3 => {
+                            if e.y < HEIGHT - 1 {
+                                commands.send(GameCommand::Enemy(EnemyCommand::Move(
+                                    index,
+                                    Direction::Down,
+                                )));
+                            }
                         }
+                        _ => unreachable!(),
                     }
-                    _ => unreachable!(),
                 }
-            }
+            });
         }
 
-        if world.should_stop_game {
+        if world.with(|world| world.should_stop_game) {
             break 'update_loop;
         }
 
-        i += 1;
         next_frame().await;
     }
 }
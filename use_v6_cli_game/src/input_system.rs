@@ -1,68 +1,78 @@
-#![allow(unused_must_use)]
-
-use std::sync::mpsc::Sender;
+use std::sync::Arc;
 
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 
-use runtime_v6::{next_frame, Read, Runtime};
+use runtime_v6::{next_frame, Commands, Read, Res};
 
 use crate::key_events::KeyEvents;
+use crate::quick_save::{QuickSave, QuickSaveRequest};
 use crate::world::{GameCommand, GameWorld, InputCommand};
-use crate::Phase;
 
 pub async fn input_system(
     world: Read<GameWorld>,
-    sender: Sender<GameCommand>,
-    _runtime: Runtime<Phase, GameWorld>,
+    commands: Commands<GameWorld>,
+    quick_save: Res<Arc<QuickSave>>,
 ) {
     let mut key_events = KeyEvents::new();
 
     'update_loop: loop {
-        sender.send(GameCommand::Input(InputCommand::Reset));
+        commands.send(GameCommand::Input(0, InputCommand::Reset));
         for evt in key_events.get_events() {
             match evt {
                 KeyEvent {
                     code: KeyCode::Char('c'),
                     modifiers: KeyModifiers::CONTROL,
                 } => {
-                    sender.send(GameCommand::ShouldStopGame);
+                    commands.send(GameCommand::ShouldStopGame);
                     break 'update_loop;
                 }
+                KeyEvent {
+                    code: KeyCode::F(5),
+                    modifiers: KeyModifiers::NONE,
+                } => {
+                    quick_save.request(QuickSaveRequest::Save);
+                }
+                KeyEvent {
+                    code: KeyCode::F(9),
+                    modifiers: KeyModifiers::NONE,
+                } => {
+                    quick_save.request(QuickSaveRequest::Load);
+                }
                 KeyEvent {
                     code: KeyCode::Char('z'),
                     modifiers: KeyModifiers::NONE,
                 } => {
-                    sender.send(GameCommand::Input(InputCommand::Z));
+                    commands.send(GameCommand::Input(0, InputCommand::Z));
                 }
                 KeyEvent {
                     code: KeyCode::Left,
                     modifiers: KeyModifiers::NONE,
                 } => {
-                    sender.send(GameCommand::Input(InputCommand::Left));
+                    commands.send(GameCommand::Input(0, InputCommand::Left));
                 }
                 KeyEvent {
                     code: KeyCode::Right,
                     modifiers: KeyModifiers::NONE,
                 } => {
-                    sender.send(GameCommand::Input(InputCommand::Right));
+                    commands.send(GameCommand::Input(0, InputCommand::Right));
                 }
                 KeyEvent {
                     code: KeyCode::Up,
                     modifiers: KeyModifiers::NONE,
                 } => {
-                    sender.send(GameCommand::Input(InputCommand::Up));
+                    commands.send(GameCommand::Input(0, InputCommand::Up));
                 }
                 KeyEvent {
                     code: KeyCode::Down,
                     modifiers: KeyModifiers::NONE,
                 } => {
-                    sender.send(GameCommand::Input(InputCommand::Down));
+                    commands.send(GameCommand::Input(0, InputCommand::Down));
                 }
                 _ => (),
             }
         }
 
-        if world.should_stop_game {
+        if world.with(|world| world.should_stop_game) {
             break 'update_loop;
         }
 
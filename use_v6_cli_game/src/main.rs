@@ -1,15 +1,23 @@
-use std::thread::sleep;
+use std::fs::File;
+use std::io::{stdout, BufReader, BufWriter};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::{Duration, Instant};
 
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
 
-use runtime_v6::{Runtime, RuntimeIsDone};
+use runtime_v6::{
+    after, CommandErrorPolicy, FramePacing, ReplayRuntime, Runtime, RuntimeBuilder, RuntimeIsDone,
+    SeededRng, UpdateError,
+};
+use serde::{Deserialize, Serialize};
 
 mod enemy_system;
 mod input_system;
 mod key_events;
 mod late_update_system;
 mod player_system;
+mod quick_save;
 mod render_system;
 mod world;
 
@@ -17,10 +25,11 @@ use enemy_system::enemy_system;
 use input_system::input_system;
 use late_update_system::late_update_system;
 use player_system::player_system;
+use quick_save::QuickSave;
 use render_system::render_system;
-use world::GameWorld;
+use world::{GameError, GameWorld};
 
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
 pub enum Phase {
     Input,
     Update,
@@ -29,38 +38,371 @@ pub enum Phase {
 }
 
 fn main() {
-    let world = GameWorld::new();
+    // GAME_SEEDを指定すると決定的モードで実行し、同じ入力で同じゲームを再現できる
+    let seed = std::env::var("GAME_SEED")
+        .ok()
+        .and_then(|seed| seed.parse::<u64>().ok());
+    let mut rng = match seed {
+        Some(seed) => SeededRng::new(seed),
+        None => SeededRng::from_entropy(),
+    };
 
-    let mut runtime = Runtime::new(world);
+    let world = GameWorld::new(&mut rng);
 
-    runtime.activate_phase(Phase::Input, 0);
-    runtime.activate_phase(Phase::Update, 10);
-    runtime.activate_phase(Phase::LateUpdate, 20);
-    runtime.activate_phase(Phase::Render, 30);
+    let mut builder = RuntimeBuilder::new().rng(rng);
+    if seed.is_some() {
+        builder = builder.deterministic();
+    }
+    let mut runtime = builder.build(world);
+
+    activate_phases(&runtime);
+
+    // 描画先の標準出力はrender_systemがリソースとして受け取る
+    runtime.insert_resource(Mutex::new(stdout()));
+    // F5でクイックセーブ、F9でクイックロードする
+    let quick_save = Arc::new(QuickSave::new());
+    runtime.insert_resource(Arc::clone(&quick_save));
+
+    // GAME_REPLAYを指定すると、記録したコマンドを再生する
+    // 再生するときはキー入力を読まず、記録したときと同じGAME_SEEDを指定する
+    let replay = std::env::var_os("GAME_REPLAY");
+    if replay.is_none() {
+        runtime.add_system(Phase::Input, input_system).unwrap();
+    }
+    add_game_systems(&runtime);
+    runtime.add_system(Phase::Render, render_system).unwrap();
+
+    // 拒否されたコマンドはシステムの不具合なのでゲームを止める
+    runtime.set_command_error_policy(CommandErrorPolicy::Fail);
 
-    runtime.add_async_system(Phase::Input, input_system);
-    runtime.add_async_system(Phase::Update, player_system);
-    runtime.add_async_system(Phase::Update, enemy_system);
-    runtime.add_async_system(Phase::LateUpdate, late_update_system);
-    runtime.add_async_system(Phase::Render, render_system);
+    // GAME_RECORDを指定すると、適用したコマンドをファイルに記録する
+    // クイックロードするとフレームが戻って記録と合わなくなるので、記録中はクイックセーブを使えない
+    let record = std::env::var_os("GAME_RECORD");
+    if let Some(path) = &record {
+        let file = File::create(path).expect("Create record file");
+        runtime.record_commands(BufWriter::new(file)).unwrap();
+    }
+    // 再生するときは記録したコマンドを読み込んでおく
+    let mut replay = replay.map(|path| {
+        let file = File::open(path).expect("Open replay file");
+        ReplayRuntime::new(runtime.clone(), BufReader::new(file)).unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        })
+    });
 
     enable_raw_mode().unwrap();
 
-    'update_loop: loop {
-        let frame_start = Instant::now();
-        let frame_duration = Duration::from_millis(83);
+    // 約83msごとにフレームを進める
+    let result = match &mut replay {
+        Some(replay) => replay.run_with(FramePacing::Fixed(12)),
+        None if record.is_some() => runtime.run_with(FramePacing::Fixed(12)),
+        None => run_with_quick_save(&mut runtime, &quick_save),
+    };
+
+    disable_raw_mode().unwrap();
+
+    if let Err(error) = runtime.stop_recording() {
+        eprintln!("failed to record commands: {}", error);
+    }
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
+}
+
+// ゲームのPhaseをInput、Update、LateUpdate、Renderの順にActivateする。
+fn activate_phases(runtime: &Runtime<Phase, GameWorld>) {
+    runtime.activate_phase(Phase::Input, []).unwrap();
+    runtime
+        .activate_phase(Phase::Update, [after(Phase::Input)])
+        .unwrap();
+    runtime
+        .activate_phase(Phase::LateUpdate, [after(Phase::Update)])
+        .unwrap();
+    runtime
+        .activate_phase(Phase::Render, [after(Phase::LateUpdate)])
+        .unwrap();
+}
+
+// 入力と描画以外の、ゲームを進めるシステムを登録する。
+// 端末を使わずに動かすときはこれだけを登録する。
+fn add_game_systems(runtime: &Runtime<Phase, GameWorld>) {
+    runtime.add_system(Phase::Update, player_system).unwrap();
+    runtime.add_system(Phase::Update, enemy_system).unwrap();
+    runtime
+        .add_system(Phase::LateUpdate, late_update_system)
+        .unwrap();
+}
 
-        match runtime.update() {
-            RuntimeIsDone::Done => break 'update_loop,
-            RuntimeIsDone::NotDone => (),
+// 12FPSでフレームを進めながら、フレームの間にクイックセーブの要求を処理する。
+fn run_with_quick_save(
+    runtime: &mut Runtime<Phase, GameWorld>,
+    quick_save: &QuickSave,
+) -> Result<(), UpdateError<Phase, GameError>> {
+    let frame_duration = Duration::from_secs(1) / 12;
+    loop {
+        let frame_start = Instant::now();
+        if let RuntimeIsDone::Done = runtime.update()? {
+            return Ok(());
         }
+        quick_save.process(runtime);
 
-        let now = Instant::now();
-        let duration = now.duration_since(frame_start);
+        let duration = frame_start.elapsed();
         if duration < frame_duration {
-            sleep(frame_duration - duration);
+            thread::sleep(frame_duration - duration);
         }
     }
+}
 
-    disable_raw_mode().unwrap();
+#[cfg(test)]
+mod tests {
+    use runtime_v6::{
+        Commands, LoopbackTransport, RollbackSession, RollbackStatus, RuntimeBuilder, SeededRng,
+        TestRuntime,
+    };
+
+    use super::*;
+    use crate::world::{Enemy, GameCommand, GameState, InputCommand};
+
+    // 指定した位置に敵がいるWorld。1人目のプレイヤーは(2, 2)で右を向いている。
+    fn world_with_enemies(players: u16, enemies: &[(u16, u16)]) -> GameWorld {
+        let mut world = GameWorld::with_players(&mut SeededRng::new(0), players);
+        world.enemies = enemies
+            .iter()
+            .map(|&(x, y)| Enemy { dead: false, x, y })
+            .collect();
+        world
+    }
+
+    // 描画と入力のシステムを登録せずに、端末なしで進めるランタイム。
+    fn test_runtime(world: GameWorld) -> TestRuntime<Phase, GameWorld> {
+        let runtime = TestRuntime::with_world(world);
+        activate_phases(runtime.runtime());
+        runtime
+            .runtime()
+            .set_command_error_policy(CommandErrorPolicy::Fail);
+        runtime
+    }
+
+    fn send_input(runtime: &TestRuntime<Phase, GameWorld>, player: usize, input: InputCommand) {
+        runtime.send(GameCommand::Input(player, InputCommand::Reset));
+        runtime.send(GameCommand::Input(player, input));
+    }
+
+    #[test]
+    fn player_moves_with_input() {
+        let mut runtime = test_runtime(world_with_enemies(1, &[(10, 10)]));
+        runtime
+            .runtime()
+            .add_system(Phase::Update, player_system)
+            .unwrap();
+
+        send_input(&runtime, 0, InputCommand::Right);
+        runtime.step().unwrap();
+        assert_eq!(
+            (runtime.world().players[0].x, runtime.world().players[0].y),
+            (3, 2)
+        );
+
+        send_input(&runtime, 0, InputCommand::Down);
+        runtime.step_frames(2).unwrap();
+        assert_eq!(
+            (runtime.world().players[0].x, runtime.world().players[0].y),
+            (3, 4)
+        );
+
+        // 入力がなければ動かない
+        runtime.send(GameCommand::Input(0, InputCommand::Reset));
+        runtime.step_frames(3).unwrap();
+        assert_eq!(
+            (runtime.world().players[0].x, runtime.world().players[0].y),
+            (3, 4)
+        );
+    }
+
+    #[test]
+    fn attack_kills_adjacent_enemy() {
+        let mut runtime = test_runtime(world_with_enemies(1, &[(3, 2), (10, 10)]));
+        runtime
+            .runtime()
+            .add_system(Phase::Update, player_system)
+            .unwrap();
+        runtime
+            .runtime()
+            .add_system(Phase::LateUpdate, late_update_system)
+            .unwrap();
+
+        send_input(&runtime, 0, InputCommand::Z);
+        runtime.run_until(5, |world| world.enemies[0].dead).unwrap();
+        let world = runtime.world();
+        assert!(!world.enemies[1].dead);
+        assert!(!world.players[0].dead);
+        assert!(matches!(world.state, GameState::InGame));
+    }
+
+    #[test]
+    fn game_clear_when_all_enemies_are_killed() {
+        let mut runtime = test_runtime(world_with_enemies(1, &[(3, 2)]));
+        runtime
+            .runtime()
+            .add_system(Phase::Update, player_system)
+            .unwrap();
+        runtime
+            .runtime()
+            .add_system(Phase::LateUpdate, late_update_system)
+            .unwrap();
+
+        send_input(&runtime, 0, InputCommand::Z);
+        runtime
+            .run_until(5, |world| matches!(world.state, GameState::GameClear))
+            .unwrap();
+    }
+
+    #[test]
+    fn game_over_when_enemy_touches_player() {
+        let mut runtime = test_runtime(world_with_enemies(1, &[(3, 2), (10, 10)]));
+        runtime
+            .runtime()
+            .add_system(Phase::Update, player_system)
+            .unwrap();
+        runtime
+            .runtime()
+            .add_system(Phase::LateUpdate, late_update_system)
+            .unwrap();
+
+        // 敵のいるマスに進む
+        send_input(&runtime, 0, InputCommand::Right);
+        runtime
+            .run_until(5, |world| matches!(world.state, GameState::GameOver))
+            .unwrap();
+        assert!(runtime.world().players[0].dead);
+    }
+
+    #[test]
+    fn game_continues_while_a_player_is_alive() {
+        let mut runtime = test_runtime(world_with_enemies(2, &[(3, 2), (10, 10)]));
+        runtime
+            .runtime()
+            .add_system(Phase::Update, player_system)
+            .unwrap();
+        runtime
+            .runtime()
+            .add_system(Phase::LateUpdate, late_update_system)
+            .unwrap();
+
+        send_input(&runtime, 0, InputCommand::Right);
+        runtime.run_until(5, |world| world.players[0].dead).unwrap();
+        runtime.step_frames(3).unwrap();
+        let world = runtime.world();
+        assert!(!world.players[1].dead);
+        assert!(matches!(world.state, GameState::InGame));
+    }
+
+    #[test]
+    fn enemies_move_only_every_8_frames() {
+        let mut runtime = test_runtime(world_with_enemies(1, &[(10, 5), (15, 10)]));
+        runtime
+            .runtime()
+            .add_system(Phase::Update, enemy_system)
+            .unwrap();
+        let positions = |runtime: &TestRuntime<Phase, GameWorld>| {
+            runtime
+                .world()
+                .enemies
+                .iter()
+                .map(|e| (e.x, e.y))
+                .collect::<Vec<_>>()
+        };
+
+        runtime.step().unwrap();
+        let moved = positions(&runtime);
+        assert_ne!(moved, vec![(10, 5), (15, 10)]);
+        runtime.step_frames(7).unwrap();
+        assert_eq!(positions(&runtime), moved);
+    }
+
+    type Session =
+        RollbackSession<Phase, GameWorld, Vec<InputCommand>, LoopbackTransport<Vec<InputCommand>>>;
+
+    fn session(player: usize, transport: LoopbackTransport<Vec<InputCommand>>) -> Session {
+        let mut rng = SeededRng::new(42);
+        let world = GameWorld::with_players(&mut rng, 2);
+        let runtime = RuntimeBuilder::new().deterministic().rng(rng).build(world);
+        activate_phases(&runtime);
+        add_game_systems(&runtime);
+        runtime.set_command_error_policy(CommandErrorPolicy::Fail);
+
+        RollbackSession::new(
+            runtime,
+            transport,
+            player,
+            |commands: &Commands<GameWorld>, player, input: &Vec<InputCommand>| {
+                commands.send(GameCommand::Input(player, InputCommand::Reset));
+                for input in input {
+                    commands.send(GameCommand::Input(player, input.clone()));
+                }
+            },
+        )
+    }
+
+    // プレイヤーごとの、フレームごとの入力。
+    fn scripted_input(player: usize, frame: u64) -> Vec<InputCommand> {
+        let input = match (player, frame / 4 % 4) {
+            (0, 0) | (1, 2) => InputCommand::Right,
+            (0, 1) | (1, 3) => InputCommand::Down,
+            (0, 2) | (1, 0) => InputCommand::Z,
+            _ => InputCommand::Up,
+        };
+        vec![input]
+    }
+
+    #[test]
+    fn two_player_rollback_sessions_converge() {
+        let (a, b) = LoopbackTransport::pair(3);
+        let mut sessions = [session(0, a), session(1, b)];
+
+        for frame in 0..40 {
+            for session in sessions.iter_mut() {
+                let input = scripted_input(session.local_player(), frame);
+                match session.advance(input).unwrap() {
+                    RollbackStatus::Advanced(_) => (),
+                    RollbackStatus::WaitingForRemote => {
+                        panic!("session stalled at frame {}", frame)
+                    }
+                }
+            }
+        }
+        // 遅れて届く入力をすべて受け取る
+        while !sessions.iter().all(|s| s.is_synchronized()) {
+            for session in sessions.iter_mut() {
+                session.poll_remote().unwrap();
+            }
+        }
+
+        assert!(sessions.iter().all(|s| s.rollbacks() > 0));
+        let [a, b] = sessions.map(|s| s.runtime().snapshot().world().clone());
+        assert_eq!(
+            a.players
+                .iter()
+                .map(|p| (p.x, p.y, p.dead))
+                .collect::<Vec<_>>(),
+            b.players
+                .iter()
+                .map(|p| (p.x, p.y, p.dead))
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(
+            a.enemies
+                .iter()
+                .map(|e| (e.x, e.y, e.dead))
+                .collect::<Vec<_>>(),
+            b.enemies
+                .iter()
+                .map(|e| (e.x, e.y, e.dead))
+                .collect::<Vec<_>>(),
+        );
+        // 2人目のプレイヤーも入力どおりに動いている
+        assert_ne!((b.players[1].x, b.players[1].y), (2, 4));
+    }
 }
@@ -1,47 +1,43 @@
-#![allow(unused_must_use)]
-
-use std::sync::mpsc::Sender;
-
-use runtime_v6::{next_frame, Read, Runtime};
+use runtime_v6::{next_frame, Commands, Read};
 
 use crate::world::{Direction, GameCommand, GameWorld, PlayerCommand, HEIGHT, WIDTH};
-use crate::Phase;
 
-pub async fn player_system(
-    world: Read<GameWorld>,
-    sender: Sender<GameCommand>,
-    _runtime: Runtime<Phase, GameWorld>,
-) {
+pub async fn player_system(world: Read<GameWorld>, commands: Commands<GameWorld>) {
     'update_loop: loop {
-        if world.input.left {
-            if world.player.x > 0 {
-                sender.send(GameCommand::Player(PlayerCommand::Move(Direction::Left)));
-            }
-            sender.send(GameCommand::Player(PlayerCommand::SetDir(Direction::Left)));
-        } else if world.input.right {
-            if world.player.x < WIDTH - 1 {
-                sender.send(GameCommand::Player(PlayerCommand::Move(Direction::Right)));
-            }
-            sender.send(GameCommand::Player(PlayerCommand::SetDir(Direction::Right)));
-        } else if world.input.up {
-            if world.player.y > 0 {
-                sender.send(GameCommand::Player(PlayerCommand::Move(Direction::Up)));
-            }
-            sender.send(GameCommand::Player(PlayerCommand::SetDir(Direction::Up)));
-        } else if world.input.down {
-            if world.player.y < HEIGHT - 1 {
-                sender.send(GameCommand::Player(PlayerCommand::Move(Direction::Down)));
-            }
-            sender.send(GameCommand::Player(PlayerCommand::SetDir(Direction::Down)));
-        }
+        world.with(|world| {
+            for (index, (player, input)) in world.players.iter().zip(&world.inputs).enumerate() {
+                if player.dead {
+                    continue;
+                }
+                let send = |cmd| commands.send(GameCommand::Player(index, cmd));
 
-        if world.input.z {
-            sender.send(GameCommand::Player(PlayerCommand::SetAttacked(true)));
-        } else {
-            sender.send(GameCommand::Player(PlayerCommand::SetAttacked(false)));
-        }
+                if input.left {
+                    if player.x > 0 {
+                        send(PlayerCommand::Move(Direction::Left));
+                    }
+                    send(PlayerCommand::SetDir(Direction::Left));
+                } else if input.right {
+                    if player.x < WIDTH - 1 {
+                        send(PlayerCommand::Move(Direction::Right));
+                    }
+                    send(PlayerCommand::SetDir(Direction::Right));
+                } else if input.up {
+                    if player.y > 0 {
+                        send(PlayerCommand::Move(Direction::Up));
+                    }
+                    send(PlayerCommand::SetDir(Direction::Up));
+                } else if input.down {
+                    if player.y < HEIGHT - 1 {
+                        send(PlayerCommand::Move(Direction::Down));
+                    }
+                    send(PlayerCommand::SetDir(Direction::Down));
+                }
+
+                send(PlayerCommand::SetAttacked(input.z));
+            }
+        });
 
-        if world.should_stop_game {
+        if world.with(|world| world.should_stop_game) {
             break 'update_loop;
         }
 
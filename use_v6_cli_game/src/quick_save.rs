@@ -0,0 +1,50 @@
+use std::sync::Mutex;
+
+use runtime_v6::{Runtime, RuntimeSnapshot};
+
+use crate::world::GameWorld;
+use crate::Phase;
+
+/// クイックセーブとクイックロードの要求。
+pub enum QuickSaveRequest {
+    Save,
+    Load,
+}
+
+/// input_systemから受け取った要求を、フレームの間に処理するためのリソース。
+///
+/// タスクのpoll中はランタイムの状態を保存できないので、要求だけを預かっておく。
+pub struct QuickSave {
+    request: Mutex<Option<QuickSaveRequest>>,
+    saved: Mutex<Option<RuntimeSnapshot<Phase, GameWorld>>>,
+}
+impl QuickSave {
+    pub fn new() -> Self {
+        Self {
+            request: Mutex::new(None),
+            saved: Mutex::new(None),
+        }
+    }
+
+    pub fn request(&self, request: QuickSaveRequest) {
+        *self.request.lock().unwrap() = Some(request);
+    }
+
+    /// 要求があれば処理する関数。フレームの間に呼び出す。
+    pub fn process(&self, runtime: &Runtime<Phase, GameWorld>) {
+        let request = self.request.lock().unwrap().take();
+        match request {
+            Some(QuickSaveRequest::Save) => {
+                *self.saved.lock().unwrap() = Some(runtime.snapshot());
+            }
+            Some(QuickSaveRequest::Load) => {
+                // ロックを外してから戻す
+                let saved = self.saved.lock().unwrap().clone();
+                if let Some(saved) = saved {
+                    runtime.restore(&saved);
+                }
+            }
+            None => (),
+        }
+    }
+}
@@ -1,9 +1,5 @@
-#![allow(unused_must_use)]
-
-use std::io::stdout;
-use std::io::Write;
-use std::sync::mpsc::Sender;
-use std::sync::{Arc, Mutex};
+use std::io::{Stdout, Write};
+use std::sync::Mutex;
 
 use crossterm::{
     cursor::MoveTo,
@@ -13,10 +9,9 @@ use crossterm::{
 };
 use futures::{future::FutureExt, pin_mut, select};
 
-use runtime_v6::{next_frame, Read, Runtime};
+use runtime_v6::{next_frame, wait_frames, Commands, Read, Res};
 
 use crate::world::{Direction, GameCommand, GameState, GameWorld, HEIGHT, WIDTH};
-use crate::Phase;
 
 const GAMEOVER0: &str = r"   _____          __  __ ______ ______      ________ _____   ";
 const GAMEOVER1: &str = r"  / ____|   /\   |  \/  |  ____/ __ \ \    / /  ____|  __ \  ";
@@ -32,7 +27,7 @@ const CLEAR3: &str = r"          | |    | |    |  __|   / /\ \ |  _  /
 const CLEAR4: &str = r"          | |____| |____| |____ / ____ \| | \ \              ";
 const CLEAR5: &str = r"           \_____|______|______/_/    \_\_|  \_\             ";
 
-async fn game_over(sender: Sender<GameCommand>, w: Arc<Mutex<impl Write>>) {
+async fn game_over(commands: Commands<GameWorld>, w: Res<Mutex<Stdout>>) {
     let size = terminal::size().unwrap();
     let offset_x = if size.0 / 2 < WIDTH + 2 {
         println!("Terminal space is too small!");
@@ -106,14 +101,12 @@ async fn game_over(sender: Sender<GameCommand>, w: Arc<Mutex<impl Write>>) {
         next_frame().await;
     }
 
-    for _ in 0..15 {
-        next_frame().await;
-    }
+    wait_frames(15).await;
 
-    sender.send(GameCommand::ShouldStopGame);
+    commands.send(GameCommand::ShouldStopGame);
 }
 
-async fn game_clear(sender: Sender<GameCommand>, w: Arc<Mutex<impl Write>>) {
+async fn game_clear(commands: Commands<GameWorld>, w: Res<Mutex<Stdout>>) {
     let size = terminal::size().unwrap();
     let offset_x = if size.0 / 2 < WIDTH + 2 {
         println!("Terminal space is too small!");
@@ -187,16 +180,14 @@ async fn game_clear(sender: Sender<GameCommand>, w: Arc<Mutex<impl Write>>) {
         next_frame().await;
     }
 
-    for _ in 0..15 {
-        next_frame().await;
-    }
+    wait_frames(15).await;
 
-    sender.send(GameCommand::ShouldStopGame);
+    commands.send(GameCommand::ShouldStopGame);
 }
 
 async fn game_close(world: Read<GameWorld>) {
     'update_loop: loop {
-        if world.should_stop_game {
+        if world.with(|world| world.should_stop_game) {
             break 'update_loop;
         }
 
@@ -204,9 +195,9 @@ async fn game_close(world: Read<GameWorld>) {
     }
 }
 
-async fn render(world: Read<GameWorld>, sender: Sender<GameCommand>, w: Arc<Mutex<impl Write>>) {
+async fn render(world: Read<GameWorld>, commands: Commands<GameWorld>, w: Res<Mutex<Stdout>>) {
     loop {
-        let state = {
+        let state = world.with(|world| {
             let mut w = w.lock().expect("Get write");
 
             queue!(w, Clear(ClearType::All)).unwrap();
@@ -273,15 +264,24 @@ async fn render(world: Read<GameWorld>, sender: Sender<GameCommand>, w: Arc<Mute
             }
 
             // Player
-            if !world.player.dead {
-                let x = offset_x + world.player.x * 2 + 2 + 2;
-                let y = offset_y + world.player.y + 2 + 1;
+            // 2人目のプレイヤーは色を変えて描画する
+            for (index, player) in world.players.iter().enumerate() {
+                if player.dead {
+                    continue;
+                }
+                let x = offset_x + player.x * 2 + 2 + 2;
+                let y = offset_y + player.y + 2 + 1;
+                let color = if index == 0 {
+                    Color::DarkYellow
+                } else {
+                    Color::DarkMagenta
+                };
                 queue!(w, MoveTo(x, y)).unwrap();
-                queue!(w, SetForegroundColor(Color::DarkYellow), Print('人')).unwrap();
+                queue!(w, SetForegroundColor(color), Print('人')).unwrap();
 
                 queue!(w, SetForegroundColor(Color::White)).unwrap();
-                if world.player.attacked {
-                    match world.player.dir {
+                if player.attacked {
+                    match player.dir {
                         Direction::Left => queue!(w, MoveTo(x - 2, y), Print('刀')).unwrap(),
                         Direction::Right => queue!(w, MoveTo(x + 2, y), Print('刀')).unwrap(),
                         Direction::Up => queue!(w, MoveTo(x, y - 1), Print('刀')).unwrap(),
@@ -294,16 +294,16 @@ async fn render(world: Read<GameWorld>, sender: Sender<GameCommand>, w: Arc<Mute
             w.flush().unwrap();
 
             world.state
-        };
+        });
 
         match state {
             GameState::InGame => next_frame().await,
             GameState::GameClear => {
-                game_clear(sender, Arc::clone(&w)).await;
+                game_clear(commands, w.clone()).await;
                 break;
             }
             GameState::GameOver => {
-                game_over(sender, Arc::clone(&w)).await;
+                game_over(commands, w.clone()).await;
                 break;
             }
         }
@@ -312,17 +312,15 @@ async fn render(world: Read<GameWorld>, sender: Sender<GameCommand>, w: Arc<Mute
 
 pub async fn render_system(
     world: Read<GameWorld>,
-    sender: Sender<GameCommand>,
-    _runtime: Runtime<Phase, GameWorld>,
+    commands: Commands<GameWorld>,
+    w: Res<Mutex<Stdout>>,
 ) {
-    let w = Arc::new(Mutex::new(stdout()));
-
     {
         let mut w = w.lock().expect("Get write");
         execute!(w, EnterAlternateScreen).unwrap();
     }
 
-    let render = render(world, sender.clone(), Arc::clone(&w)).fuse();
+    let render = render(world.clone(), commands.clone(), w.clone()).fuse();
     let close = game_close(world).fuse();
     pin_mut!(render);
     pin_mut!(close);
@@ -1,10 +1,14 @@
+use std::fmt;
+
 use rand::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use runtime_v6::World;
+use runtime_v6::{Snapshot, World};
 
 pub const WIDTH: u16 = 30;
 pub const HEIGHT: u16 = 20;
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum InputCommand {
     Reset,
     Up,
@@ -13,6 +17,7 @@ pub enum InputCommand {
     Right,
     Z,
 }
+#[derive(Serialize, Deserialize)]
 pub enum PlayerCommand {
     Move(Direction),
     SetDir(Direction),
@@ -20,24 +25,53 @@ pub enum PlayerCommand {
     Dead,
 }
 
+#[derive(Serialize, Deserialize)]
 pub enum EnemyCommand {
     Move(usize, Direction),
     Kill(usize),
 }
 
+#[derive(Serialize, Deserialize)]
 pub enum WorldStateCommand {
     SetGameOver,
     SetGameClear,
 }
 
+#[derive(Serialize, Deserialize)]
 pub enum GameCommand {
-    Input(InputCommand),
-    Player(PlayerCommand),
+    /// プレイヤーの番号と、そのプレイヤーの入力。
+    Input(usize, InputCommand),
+    /// プレイヤーの番号と、そのプレイヤーへのコマンド。
+    Player(usize, PlayerCommand),
     Enemy(EnemyCommand),
     WorldState(WorldStateCommand),
     ShouldStopGame,
 }
 
+/// GameWorldが拒否したコマンドのエラー。
+#[derive(Debug)]
+pub enum GameError {
+    /// 存在しない敵を指定した。
+    EnemyNotFound(usize),
+    /// 存在しないプレイヤーを指定した。
+    PlayerNotFound(usize),
+    /// 画面の外に移動しようとした。
+    OutOfBounds { x: u16, y: u16, dir: Direction },
+}
+impl fmt::Display for GameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GameError::EnemyNotFound(index) => write!(f, "enemy {} does not exist", index),
+            GameError::PlayerNotFound(index) => write!(f, "player {} does not exist", index),
+            GameError::OutOfBounds { x, y, dir } => {
+                write!(f, "cannot move {:?} from ({}, {})", dir, x, y)
+            }
+        }
+    }
+}
+impl std::error::Error for GameError {}
+
+#[derive(Clone)]
 pub struct Input {
     pub z: bool,
     pub left: bool,
@@ -65,7 +99,7 @@ impl Input {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum Direction {
     Left,
     Right,
@@ -73,6 +107,7 @@ pub enum Direction {
     Down,
 }
 
+#[derive(Clone)]
 pub struct Player {
     pub dead: bool,
     pub x: u16,
@@ -92,6 +127,7 @@ impl Player {
     }
 }
 
+#[derive(Clone)]
 pub struct Enemy {
     pub dead: bool,
     pub x: u16,
@@ -110,17 +146,26 @@ pub enum GameState {
     GameClear,
 }
 
+#[derive(Clone)]
 pub struct GameWorld {
     pub state: GameState,
     pub should_stop_game: bool,
-    pub input: Input,
-    pub player: Player,
+    /// プレイヤーごとの入力。
+    pub inputs: Vec<Input>,
+    pub players: Vec<Player>,
     pub enemies: Vec<Enemy>,
+    /// 敵の動きを決める乱数のシード。
+    pub seed: u64,
 }
 impl GameWorld {
-    pub fn new() -> Self {
-        let mut rng = rand::thread_rng();
+    /// 1人用のWorldを作成する。
+    pub fn new(rng: &mut impl Rng) -> Self {
+        Self::with_players(rng, 1)
+    }
 
+    /// 指定した人数のプレイヤーがいるWorldを作成する。
+    /// プレイヤーは左上に縦に並ぶ。
+    pub fn with_players(rng: &mut impl Rng, players: u16) -> Self {
         let mut enemies: Vec<Enemy> = vec![];
         for _ in 0..5 {
             let (x, y) = 'label: loop {
@@ -145,43 +190,75 @@ impl GameWorld {
         Self {
             state: GameState::InGame,
             should_stop_game: false,
-            input: Input::new(),
-            player: Player::new(2, 2),
+            inputs: (0..players).map(|_| Input::new()).collect(),
+            players: (0..players).map(|i| Player::new(2, 2 + i * 2)).collect(),
             enemies,
+            seed: rng.next_u64(),
         }
     }
 }
+// 画面の中でdirの方向に1マス移動した座標を返す。
+fn moved(x: u16, y: u16, dir: Direction) -> Result<(u16, u16), GameError> {
+    let moved = match dir {
+        Direction::Left => x.checked_sub(1).map(|x| (x, y)),
+        Direction::Right => Some((x + 1, y)).filter(|&(x, _)| x < WIDTH),
+        Direction::Up => y.checked_sub(1).map(|y| (x, y)),
+        Direction::Down => Some((x, y + 1)).filter(|&(_, y)| y < HEIGHT),
+    };
+    moved.ok_or(GameError::OutOfBounds { x, y, dir })
+}
+
 impl World for GameWorld {
     type Command = GameCommand;
-    fn process_command(&mut self, cmd: Self::Command) {
+    type Error = GameError;
+    fn process_command(&mut self, cmd: Self::Command) -> Result<(), Self::Error> {
         match cmd {
-            GameCommand::Input(input) => match input {
-                InputCommand::Reset => self.input.reset(),
-                InputCommand::Left => self.input.left = true,
-                InputCommand::Right => self.input.right = true,
-                InputCommand::Up => self.input.up = true,
-                InputCommand::Down => self.input.down = true,
-                InputCommand::Z => self.input.z = true,
-            },
-            GameCommand::Player(cmd) => match cmd {
-                PlayerCommand::Move(dir) => match dir {
-                    Direction::Left => self.player.x -= 1,
-                    Direction::Right => self.player.x += 1,
-                    Direction::Up => self.player.y -= 1,
-                    Direction::Down => self.player.y += 1,
-                },
-                PlayerCommand::SetAttacked(flag) => self.player.attacked = flag,
-                PlayerCommand::SetDir(dir) => self.player.dir = dir,
-                PlayerCommand::Dead => self.player.dead = true,
-            },
+            GameCommand::Input(index, input) => {
+                let state = self
+                    .inputs
+                    .get_mut(index)
+                    .ok_or(GameError::PlayerNotFound(index))?;
+                match input {
+                    InputCommand::Reset => state.reset(),
+                    InputCommand::Left => state.left = true,
+                    InputCommand::Right => state.right = true,
+                    InputCommand::Up => state.up = true,
+                    InputCommand::Down => state.down = true,
+                    InputCommand::Z => state.z = true,
+                }
+            }
+            GameCommand::Player(index, cmd) => {
+                let player = self
+                    .players
+                    .get_mut(index)
+                    .ok_or(GameError::PlayerNotFound(index))?;
+                match cmd {
+                    PlayerCommand::Move(dir) => {
+                        let (x, y) = moved(player.x, player.y, dir)?;
+                        player.x = x;
+                        player.y = y;
+                    }
+                    PlayerCommand::SetAttacked(flag) => player.attacked = flag,
+                    PlayerCommand::SetDir(dir) => player.dir = dir,
+                    PlayerCommand::Dead => player.dead = true,
+                }
+            }
             GameCommand::Enemy(cmd) => match cmd {
-                EnemyCommand::Move(index, dir) => match dir {
-                    Direction::Left => self.enemies[index].x -= 1,
-                    Direction::Right => self.enemies[index].x += 1,
-                    Direction::Up => self.enemies[index].y -= 1,
-                    Direction::Down => self.enemies[index].y += 1,
-                },
-                EnemyCommand::Kill(index) => self.enemies[index].dead = true,
+                EnemyCommand::Move(index, dir) => {
+                    let enemy = self
+                        .enemies
+                        .get_mut(index)
+                        .ok_or(GameError::EnemyNotFound(index))?;
+                    let (x, y) = moved(enemy.x, enemy.y, dir)?;
+                    enemy.x = x;
+                    enemy.y = y;
+                }
+                EnemyCommand::Kill(index) => {
+                    self.enemies
+                        .get_mut(index)
+                        .ok_or(GameError::EnemyNotFound(index))?
+                        .dead = true
+                }
             },
             GameCommand::WorldState(state) => match state {
                 WorldStateCommand::SetGameOver => self.state = GameState::GameOver,
@@ -189,5 +266,17 @@ impl World for GameWorld {
             },
             GameCommand::ShouldStopGame => self.should_stop_game = true,
         }
+        Ok(())
+    }
+}
+
+// クイックセーブではWorldをそのまま複製して保存する
+impl Snapshot for GameWorld {
+    type State = GameWorld;
+    fn snapshot(&self) -> Self::State {
+        self.clone()
+    }
+    fn restore(&mut self, state: &Self::State) {
+        *self = state.clone();
     }
 }